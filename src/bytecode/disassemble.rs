@@ -0,0 +1,129 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+use crate::{
+    bytecode::{function::Function, instruction::Instruction},
+    program::INTERNER,
+    runtime::{debug_value::DebugValue, gc::Gc, value::Value},
+};
+
+/// Renders every function with its constants resolved and jump targets
+/// replaced by labels.
+pub struct Disassembly<'a> {
+    functions: &'a [Function],
+    constants: &'a [Value],
+}
+
+impl<'a> Disassembly<'a> {
+    pub fn new(functions: &'a [Function], constants: &'a [Value]) -> Self {
+        Self {
+            functions,
+            constants,
+        }
+    }
+
+    fn function_name(&self, index: usize) -> String {
+        let function = &self.functions[index];
+
+        match function.name {
+            Some(name) => INTERNER.lock().unwrap().resolve(name).to_owned(),
+            None if function.is_script => String::from("<script>"),
+            None => String::from("<anonymous>"),
+        }
+    }
+
+    fn constant(&self, index: u32) -> String {
+        let Some(&value) = self.constants.get(index as usize) else {
+            return format!("k{} = <missing>", index);
+        };
+
+        // Constants are never heap objects, so an empty collector can render them.
+        let gc = Gc::default();
+
+        if value.is_string() {
+            format!("k{} = {:?}", index, gc.get_str(value))
+        } else {
+            format!("k{} = {:?}", index, DebugValue::new(value, &gc))
+        }
+    }
+
+    fn write_function(&self, f: &mut fmt::Formatter<'_>, index: usize) -> fmt::Result {
+        let function = &self.functions[index];
+
+        writeln!(
+            f,
+            "function {} {} (arity {}, registers {})",
+            index,
+            self.function_name(index),
+            function.arity,
+            function.registers_count
+        )?;
+
+        let targets: BTreeSet<usize> = function
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(ip, instruction)| {
+                let offset = instruction.jump_offset()?;
+
+                Some((ip as i32 + offset) as usize)
+            })
+            .collect();
+
+        let labels: HashMap<usize, usize> = targets
+            .into_iter()
+            .enumerate()
+            .map(|(label, target)| (target, label))
+            .collect();
+
+        for (ip, instruction) in function.instructions.iter().enumerate() {
+            if let Some(label) = labels.get(&ip) {
+                writeln!(f, "L{}:", label)?;
+            }
+
+            let mut comments = Vec::new();
+
+            if let Some(constant) = instruction.constant() {
+                comments.push(self.constant(constant));
+            }
+
+            if let Some(offset) = instruction.jump_offset() {
+                let target = (ip as i32 + offset) as usize;
+                comments.push(format!("-> L{}", labels[&target]));
+            }
+
+            if let Instruction::CreateClosure { src, .. } = *instruction {
+                comments.push(self.function_name(src as usize));
+            }
+
+            if comments.is_empty() {
+                writeln!(f, "    {:04}  {}", ip, instruction)?;
+            } else {
+                let instruction = instruction.to_string();
+                writeln!(
+                    f,
+                    "    {:04}  {:<32}; {}",
+                    ip,
+                    instruction,
+                    comments.join(", ")
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> fmt::Display for Disassembly<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for index in 0..self.functions.len() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            self.write_function(f, index)?;
+        }
+
+        Ok(())
+    }
+}
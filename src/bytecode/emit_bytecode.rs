@@ -1,11 +1,18 @@
-use std::collections::HashMap;
+use foldhash::HashMap;
 
 use crate::{
     bytecode::{
-        function::Function, function_scope::FunctionScope, instruction::Instruction,
+        function::Function,
+        function_scope::{FunctionScope, Local, LoopContext},
+        instruction::Instruction,
         operand::Operand,
+        resolve::Resolution,
     },
+    diagnostics::error::Error,
+    program::INTERNER,
+    report_error,
     runtime::value::Value,
+    std::native_functions::NativeRegistry,
     syntax::{
         ast::{Ast, Expr, ExprId},
         ops::{AssignOp, BinaryOp, UnaryOp},
@@ -13,20 +20,51 @@ use crate::{
     util::string_interner::StringIndex,
 };
 
-#[derive(Default)]
-pub struct Compiler {
+pub struct Compiler<'a> {
     functions: Vec<Option<Function>>,
+    /// Number of functions already loaded, compiled functions are indexed after them.
+    function_offset: usize,
     constants: Vec<Value>,
+    /// Index of every constant, keyed by its bits so `0.0` and `-0.0` stay apart.
+    constant_indices: HashMap<Value, usize>,
+    natives: &'a NativeRegistry,
 }
 
-impl Compiler {
+impl<'a> Compiler<'a> {
+    pub fn new(natives: &'a NativeRegistry) -> Self {
+        Self::resume(natives, 0, Vec::new())
+    }
+
+    /// Creates a compiler for code loaded on top of `function_offset` existing
+    /// functions, extending their constant pool.
+    pub fn resume(
+        natives: &'a NativeRegistry,
+        function_offset: usize,
+        constants: Vec<Value>,
+    ) -> Self {
+        let mut constant_indices = HashMap::default();
+
+        for (index, &constant) in constants.iter().enumerate() {
+            constant_indices.entry(constant).or_insert(index);
+        }
+
+        Self {
+            functions: Vec::new(),
+            function_offset,
+            constants,
+            constant_indices,
+            natives,
+        }
+    }
+
     fn get_or_insert(&mut self, value: Value) -> usize {
-        if let Some(index) = self.constants.iter().copied().position(|c| c == value) {
+        if let Some(&index) = self.constant_indices.get(&value) {
             return index;
         }
 
         let index = self.constants.len();
         self.constants.push(value);
+        self.constant_indices.insert(value, index);
 
         index
     }
@@ -39,33 +77,64 @@ impl Compiler {
         self.get_or_insert(Value::number(value))
     }
 
+    pub fn push_boolean(&mut self, value: bool) -> usize {
+        self.get_or_insert(Value::boolean(value))
+    }
+
+    pub fn push_nil(&mut self) -> usize {
+        self.get_or_insert(Value::nil())
+    }
+
+    pub fn push_native(&mut self, index: usize) -> usize {
+        self.get_or_insert(Value::native(index))
+    }
+
     pub fn compile(
+        self,
+        ast: &Ast,
+        resolution: &Resolution,
+    ) -> Result<(Vec<Function>, Vec<Value>), Error> {
+        self.compile_script(ast, resolution, &mut FunctionScope::default())
+    }
+
+    /// Compiles the program into a script function, the first of the returned
+    /// functions. Top level declarations are made in `scope` itself, so they
+    /// remain visible to code compiled later with the same scope.
+    pub fn compile_script(
         mut self,
         ast: &Ast,
-        captures: HashMap<ExprId, Vec<StringIndex>>,
-    ) -> (Vec<Function>, Vec<Value>) {
+        resolution: &Resolution,
+        scope: &mut FunctionScope,
+    ) -> Result<(Vec<Function>, Vec<Value>), Error> {
         let entry = ast.entry();
 
         let index = self.functions.len();
         let function = None;
         self.functions.push(function);
 
-        let mut scope = FunctionScope::default();
-        let src = self.compile_expression(ast, &mut scope, &captures, entry);
+        let Expr::Block(ref expressions) = *ast.get(entry) else {
+            unreachable!("the program must be parsed as a block");
+        };
+
+        let src = self.compile_block(ast, scope, resolution, expressions)?;
 
         if !self.expression_returns(ast, entry) {
-            let src = materialize(&mut scope, src);
+            let src = materialize(scope, src)?;
 
             scope.emit_instruction(Instruction::Return {
                 src: src.unwrap_register(),
             });
         }
 
-        patch_function_arguments(&mut scope);
+        patch_function_arguments(scope);
 
         let function = Function {
-            instructions: scope.instructions,
-            registers_count: scope.next_register,
+            locals: scope.take_locals(),
+            instructions: std::mem::take(&mut scope.instructions),
+            spans: std::mem::take(&mut scope.spans),
+            name: None,
+            is_script: true,
+            registers_count: scope.registers_count(),
             arity: 0,
         };
 
@@ -77,71 +146,168 @@ impl Compiler {
             .map(|f| f.unwrap())
             .collect::<Vec<Function>>();
 
-        (functions, self.constants)
+        Ok((functions, self.constants))
     }
 
     fn compile_block(
         &mut self,
         ast: &Ast,
         scope: &mut FunctionScope,
-        captures: &HashMap<ExprId, Vec<StringIndex>>,
+        resolution: &Resolution,
         expressions: &[ExprId],
-    ) -> Operand {
+    ) -> Result<Operand, Error> {
         for expression in expressions.iter().copied() {
-            let expression = ast.get(expression);
+            let expression = ast.get(ast.declaration(expression));
 
             if let Expr::Function { name, .. } = &expression
                 && let Some(name) = name
             {
-                self.compile_expression(ast, scope, captures, *name);
+                let (local, declared) = compile_local(ast, scope, resolution, *name)?;
+
+                // Functions capture the cells of their siblings, which must
+                // exist before the first closure is created. Calls made
+                // before the declaration find nil instead of a value left in
+                // the register by another frame.
+                if declared {
+                    let nil = self.push_nil();
+                    emit_load_constant(scope, local.register, nil)?;
+
+                    if local.is_cell {
+                        store_local(scope, local, declared, local.register);
+                    }
+                }
+            }
+        }
+
+        let mut dest = self.unit(scope)?;
+
+        for (index, expression) in expressions.iter().copied().enumerate() {
+            let mark = scope.next_register;
+            let names = scope.names().len();
+
+            dest = self.compile_expression(ast, scope, resolution, expression)?;
+
+            // The value of the last expression is the value of the block, the
+            // temporaries of every other statement are released, except for
+            // the locals it declares.
+            if index + 1 < expressions.len() {
+                let top = scope.names()[names..]
+                    .iter()
+                    .map(|local| local.register + 1)
+                    .fold(mark, u16::max);
+
+                scope.free_registers(top);
             }
         }
 
-        expressions
-            .iter()
-            .copied()
-            .fold(self.unit(), |_, expression| {
-                self.compile_expression(ast, scope, captures, expression)
-            })
+        Ok(dest)
     }
 
     fn compile_expression(
         &mut self,
         ast: &Ast,
         scope: &mut FunctionScope,
-        captures: &HashMap<ExprId, Vec<StringIndex>>,
+        resolution: &Resolution,
         expression: ExprId,
-    ) -> Operand {
-        match *ast.get(expression) {
-            Expr::NativeFunction { .. } => {
-                todo!()
+    ) -> Result<Operand, Error> {
+        let parent_span = scope.span.clone();
+
+        if let Some(span) = ast.span(expression) {
+            scope.span = Some(span.clone());
+        }
+
+        let operand = match *ast.get(expression) {
+            Expr::NativeFunction {
+                name,
+                ref parameters,
+            } => {
+                let Expr::Identifier(identifier) = *ast.get(name) else {
+                    unreachable!("native function name must be parsed as identifier");
+                };
+
+                let span = ast.span(name).unwrap().clone();
+
+                let Some((index, native)) = self.natives.lookup(identifier) else {
+                    let slice = INTERNER.lock().unwrap().resolve(identifier).to_owned();
+                    return Err(report_error!(
+                        span,
+                        "`{}` is not a registered native function",
+                        slice
+                    ));
+                };
+
+                if native.arity as usize != parameters.len() {
+                    let slice = INTERNER.lock().unwrap().resolve(identifier).to_owned();
+                    return Err(report_error!(
+                        span,
+                        "native function `{}` takes {} parameters, but was declared with {}",
+                        slice,
+                        native.arity,
+                        parameters.len()
+                    ));
+                }
+
+                let (local, declared) = compile_local(ast, scope, resolution, name)?;
+                let dest = value_register(scope, local)?;
+                let src = self.push_native(index);
+
+                emit_load_constant(scope, dest, src)?;
+                store_local(scope, local, declared, dest);
+
+                Operand::Register(dest)
             }
             Expr::Function {
                 ref parameters,
                 block,
                 name,
             } => {
+                let Ok(arity) = u8::try_from(parameters.len()) else {
+                    return Err(Error::new(
+                        scope.span.clone(),
+                        format!("a function can't have more than {} parameters", u8::MAX),
+                    ));
+                };
+
                 let index = self.functions.len();
                 let function = None;
                 self.functions.push(function);
 
-                let dest = match name {
-                    Some(name) => self.compile_expression(ast, scope, captures, name),
-                    None => Operand::Register(scope.allocate_register()),
+                let local = match name {
+                    Some(name) => Some(compile_local(ast, scope, resolution, name)?),
+                    None => None,
+                };
+
+                let dest = match local {
+                    Some((local, _)) => value_register(scope, local)?,
+                    None => scope.allocate_register()?,
                 };
 
                 scope.emit_instruction(Instruction::CreateClosure {
-                    dest: dest.unwrap_register(),
-                    src: index as u32,
+                    dest,
+                    src: (self.function_offset + index) as u32,
                 });
 
-                for capture in captures.get(&expression).unwrap().iter().copied() {
-                    let src = scope.lookup_or_declare(capture);
+                let mut captured = Vec::new();
+
+                for capture in resolution
+                    .captures
+                    .get(&expression)
+                    .unwrap()
+                    .iter()
+                    .copied()
+                {
+                    let local = scope.lookup_or_declare(capture)?;
 
                     scope.emit_instruction(Instruction::CaptureValue {
-                        dest: dest.unwrap_register(),
-                        src,
+                        dest,
+                        src: local.register,
                     });
+
+                    captured.push(local);
+                }
+
+                if let Some((local, declared)) = local {
+                    store_local(scope, local, declared, dest);
                 }
 
                 let mut scope = FunctionScope::default();
@@ -149,98 +315,145 @@ impl Compiler {
                 scope.enter_scope();
 
                 for parameter in parameters.iter().copied() {
-                    self.compile_expression(ast, &mut scope, captures, parameter);
+                    let (local, declared) = compile_local(ast, &mut scope, resolution, parameter)?;
+
+                    store_local(&mut scope, local, declared, local.register);
                 }
 
-                for capture in captures.get(&expression).unwrap().iter().copied() {
-                    scope.lookup_or_declare(capture);
+                // Captured cells are shared, the closure refers to them like
+                // the function that declared them.
+                for local in captured {
+                    scope.insert_symbol(local.name, local.is_cell)?;
                 }
 
-                let src = self.compile_expression(ast, &mut scope, captures, block);
+                let src = self.compile_expression(ast, &mut scope, resolution, block)?;
 
                 if !self.expression_returns(ast, block) {
-                    let src = materialize(&mut scope, src);
+                    let src = materialize(&mut scope, src)?;
                     scope.emit_instruction(Instruction::Return {
                         src: src.unwrap_register(),
                     });
                 }
 
                 patch_function_arguments(&mut scope);
+                patch_tail_calls(&mut scope);
 
                 scope.exit_scope();
 
+                let name = name.map(|name| {
+                    let Expr::Identifier(name) = *ast.get(name) else {
+                        unreachable!("function name must be parsed as identifier");
+                    };
+
+                    name
+                });
+
                 let function = Function {
+                    locals: scope.take_locals(),
+                    registers_count: scope.registers_count(),
                     instructions: scope.instructions,
-                    registers_count: scope.next_register,
-                    arity: parameters.len() as u8,
+                    spans: scope.spans,
+                    name,
+                    is_script: false,
+                    arity,
                 };
 
                 self.functions[index] = Some(function);
 
-                dest
+                Operand::Register(dest)
             }
             Expr::DeclareAssign { left, right } => {
-                let src = self.compile_expression(ast, scope, captures, right);
-                let src = materialize(scope, src);
+                let mark = scope.next_register;
 
-                let dest = self.compile_expression(ast, scope, captures, left);
+                let src = self.compile_expression(ast, scope, resolution, right)?;
+                let src = materialize(scope, src)?.unwrap_register();
 
-                scope.emit_instruction(Instruction::Move {
-                    dest: dest.unwrap_register(),
-                    src: src.unwrap_register(),
-                });
+                // The temporaries are only read by the store below, so a new
+                // local can take the first of them. A new cell can't, the
+                // value stays in its register as the result.
+                if !resolution.cells.contains(&left) {
+                    scope.free_registers(mark);
+                }
+
+                let (local, declared) = compile_local(ast, scope, resolution, left)?;
 
-                dest
+                store_local(scope, local, declared, src);
+
+                if local.is_cell {
+                    Operand::Register(src)
+                } else {
+                    Operand::Register(local.register)
+                }
             }
             Expr::Assign {
                 operator,
                 left,
                 right,
-            } => {
-                let dest = self.compile_expression(ast, scope, captures, left);
+            } => match *ast.get(left) {
+                Expr::MemberAccess { object, property } => {
+                    let object = self.compile_expression(ast, scope, resolution, object)?;
+                    let object = materialize(scope, object)?.unwrap_register();
+                    let key = self.compile_expression(ast, scope, resolution, property)?;
+                    let key = materialize(scope, key)?.unwrap_register();
 
-                let src = match operator {
-                    AssignOp::Assign => self.compile_expression(ast, scope, captures, right),
-                    AssignOp::AddAssign => {
-                        self.compile_binary_op(ast, scope, captures, BinaryOp::Add, left, right)
-                    }
-                    AssignOp::SubtractAssign => self.compile_binary_op(
-                        ast,
-                        scope,
-                        captures,
-                        BinaryOp::Subtract,
-                        left,
-                        right,
-                    ),
-                    AssignOp::MultiplyAssign => self.compile_binary_op(
-                        ast,
-                        scope,
-                        captures,
-                        BinaryOp::Multiply,
-                        left,
-                        right,
-                    ),
-                    AssignOp::DivideAssign => {
-                        self.compile_binary_op(ast, scope, captures, BinaryOp::Divide, left, right)
-                    }
-                    AssignOp::ModuloAssign => {
-                        self.compile_binary_op(ast, scope, captures, BinaryOp::Modulo, left, right)
-                    }
-                };
-                let src = materialize(scope, src);
+                    let read = |dest| Instruction::GetField { dest, object, key };
+                    let value =
+                        self.compile_assigned_value(ast, scope, resolution, operator, read, right)?;
 
-                scope.emit_instruction(Instruction::Move {
-                    dest: dest.unwrap_register(),
-                    src: src.unwrap_register(),
-                });
+                    scope.emit_instruction(Instruction::SetField { object, key, value });
 
-                dest
-            }
+                    Operand::Register(value)
+                }
+                Expr::Index { object, index } => {
+                    let object = self.compile_expression(ast, scope, resolution, object)?;
+                    let object = materialize(scope, object)?.unwrap_register();
+                    let index = self.compile_expression(ast, scope, resolution, index)?;
+                    let index = materialize(scope, index)?.unwrap_register();
+
+                    let read = |dest| Instruction::GetIndex {
+                        dest,
+                        object,
+                        index,
+                    };
+                    let value =
+                        self.compile_assigned_value(ast, scope, resolution, operator, read, right)?;
+
+                    scope.emit_instruction(Instruction::SetIndex {
+                        object,
+                        index,
+                        value,
+                    });
+
+                    Operand::Register(value)
+                }
+                _ => {
+                    let (local, declared) = compile_local(ast, scope, resolution, left)?;
+
+                    let src = match operator.binary_operator() {
+                        Some(operator) => {
+                            let current = self.compile_expression(ast, scope, resolution, left)?;
+                            let src2 = self.compile_expression(ast, scope, resolution, right)?;
+
+                            self.emit_binary_op(scope, operator, current, src2)?
+                        }
+                        None => self.compile_expression(ast, scope, resolution, right)?,
+                    };
+                    let src = materialize(scope, src)?.unwrap_register();
+
+                    store_local(scope, local, declared, src);
+
+                    if local.is_cell {
+                        Operand::Register(src)
+                    } else {
+                        Operand::Register(local.register)
+                    }
+                }
+            },
             Expr::LogicalAnd { left, right } => {
-                let dest = scope.allocate_register();
+                let dest = scope.allocate_register()?;
 
-                let src = self.compile_expression(ast, scope, captures, left);
-                let src = materialize(scope, src);
+                let src = self.compile_expression(ast, scope, resolution, left)?;
+                let src = materialize(scope, src)?;
                 scope.emit_instruction(Instruction::Move {
                     dest,
                     src: src.unwrap_register(),
@@ -251,8 +464,8 @@ impl Compiler {
                     offset: 0,
                 });
 
-                let src = self.compile_expression(ast, scope, captures, right);
-                let src = materialize(scope, src);
+                let src = self.compile_expression(ast, scope, resolution, right)?;
+                let src = materialize(scope, src)?;
                 scope.emit_instruction(Instruction::Move {
                     dest,
                     src: src.unwrap_register(),
@@ -267,10 +480,10 @@ impl Compiler {
                 Operand::Register(dest)
             }
             Expr::LogicalOr { left, right } => {
-                let dest = scope.allocate_register();
+                let dest = scope.allocate_register()?;
 
-                let src = self.compile_expression(ast, scope, captures, left);
-                let src = materialize(scope, src);
+                let src = self.compile_expression(ast, scope, resolution, left)?;
+                let src = materialize(scope, src)?;
                 scope.emit_instruction(Instruction::Move {
                     dest,
                     src: src.unwrap_register(),
@@ -281,8 +494,8 @@ impl Compiler {
                     offset: 0,
                 });
 
-                let src = self.compile_expression(ast, scope, captures, right);
-                let src = materialize(scope, src);
+                let src = self.compile_expression(ast, scope, resolution, right)?;
+                let src = materialize(scope, src)?;
                 scope.emit_instruction(Instruction::Move {
                     dest,
                     src: src.unwrap_register(),
@@ -297,9 +510,9 @@ impl Compiler {
                 Operand::Register(dest)
             }
             Expr::LogicalNot(expression) => {
-                let src = self.compile_expression(ast, scope, captures, expression);
-                let src = materialize(scope, src);
-                let dest = scope.allocate_register();
+                let src = self.compile_expression(ast, scope, resolution, expression)?;
+                let src = materialize(scope, src)?;
+                let dest = scope.allocate_register()?;
                 scope.emit_instruction(Instruction::Not {
                     dest,
                     src: src.unwrap_register(),
@@ -310,11 +523,11 @@ impl Compiler {
                 operator,
                 left,
                 right,
-            } => self.compile_binary_op(ast, scope, captures, operator, left, right),
+            } => self.compile_binary_op(ast, scope, resolution, operator, left, right)?,
             Expr::Unary { operator, right } => {
-                let src = self.compile_expression(ast, scope, captures, right);
-                let src = materialize(scope, src);
-                let dest = scope.allocate_register();
+                let src = self.compile_expression(ast, scope, resolution, right)?;
+                let src = materialize(scope, src)?;
+                let dest = scope.allocate_register()?;
 
                 let instruction = match operator {
                     UnaryOp::Negate => Instruction::Negate {
@@ -330,33 +543,58 @@ impl Compiler {
                 callee,
                 ref arguments,
             } => {
-                let dest = scope.allocate_register();
-                let callee_src = self.compile_expression(ast, scope, captures, callee);
+                let Ok(arity) = u8::try_from(arguments.len()) else {
+                    return Err(Error::new(
+                        scope.span.clone(),
+                        format!("a call can't have more than {} arguments", u8::MAX),
+                    ));
+                };
+
+                let dest = scope.allocate_register()?;
+                let callee_src = self.compile_expression(ast, scope, resolution, callee)?;
+
+                // Arguments are passed in the registers above the frame, where
+                // the calls made by later arguments pass their own. So every
+                // argument is evaluated before the first one is passed.
+                let mut sources = Vec::with_capacity(arguments.len());
 
                 for (index, argument) in arguments.iter().enumerate() {
-                    let argument = self.compile_expression(ast, scope, captures, *argument);
-                    let argument = materialize(scope, argument);
+                    let mark = scope.next_register;
+                    let argument = self.compile_expression(ast, scope, resolution, *argument)?;
+                    let mut src = materialize(scope, argument)?.unwrap_register();
+
+                    // A variable is read now, later arguments may assign it.
+                    if src < mark && index + 1 < arguments.len() {
+                        let copy = scope.allocate_register()?;
+                        scope.emit_instruction(Instruction::Move { dest: copy, src });
+                        src = copy;
+                    }
+
+                    sources.push(src);
+                }
+
+                for (index, src) in sources.into_iter().enumerate() {
                     scope.emit_instruction(Instruction::MoveArg {
-                        dest: index as u8,
-                        src: argument.unwrap_register(),
+                        dest: index as u16,
+                        src,
                     });
                 }
 
                 scope.emit_instruction(Instruction::Call {
                     dest,
                     src: callee_src.unwrap_register(),
-                    arity: arguments.len() as u8,
+                    arity,
                 });
 
                 Operand::Register(dest)
             }
             Expr::MemberAccess { object, property } => {
-                let dest = scope.allocate_register();
+                let dest = scope.allocate_register()?;
 
-                let object = self.compile_expression(ast, scope, captures, object);
-                let object = materialize(scope, object);
-                let key = self.compile_expression(ast, scope, captures, property);
-                let key = materialize(scope, key);
+                let object = self.compile_expression(ast, scope, resolution, object)?;
+                let object = materialize(scope, object)?;
+                let key = self.compile_expression(ast, scope, resolution, property)?;
+                let key = materialize(scope, key)?;
 
                 scope.emit_instruction(Instruction::GetField {
                     dest,
@@ -366,30 +604,66 @@ impl Compiler {
 
                 Operand::Register(dest)
             }
+            Expr::Index { object, index } => {
+                let dest = scope.allocate_register()?;
+
+                let object = self.compile_expression(ast, scope, resolution, object)?;
+                let object = materialize(scope, object)?;
+                let index = self.compile_expression(ast, scope, resolution, index)?;
+                let index = materialize(scope, index)?;
+
+                scope.emit_instruction(Instruction::GetIndex {
+                    dest,
+                    object: object.unwrap_register(),
+                    index: index.unwrap_register(),
+                });
+
+                Operand::Register(dest)
+            }
             Expr::Block(ref expressions) => {
+                let mark = scope.next_register;
+
                 scope.enter_scope();
-                let dest = self.compile_block(ast, scope, captures, expressions);
+                let src = self.compile_block(ast, scope, resolution, expressions)?;
                 scope.exit_scope();
 
-                dest
+                // Locals of the block are released, the value it evaluates to
+                // is kept in the first released register.
+                scope.free_registers(mark);
+
+                match src {
+                    Operand::Register(src) if src >= mark => {
+                        let dest = scope.allocate_register()?;
+
+                        if dest != src {
+                            scope.emit_instruction(Instruction::Move { dest, src });
+                        }
+
+                        Operand::Register(dest)
+                    }
+                    _ => src,
+                }
             }
             Expr::If {
                 condition,
                 then_branch,
                 else_branch,
             } => {
-                let dest = scope.allocate_register();
+                let dest = scope.allocate_register()?;
 
-                let src = self.compile_expression(ast, scope, captures, condition);
-                let src = materialize(scope, src);
+                let src = self.compile_expression(ast, scope, resolution, condition)?;
+                let src = materialize(scope, src)?;
 
-                let jump_if_false = scope.emit_instruction(Instruction::JumpIfFalse {
-                    src: src.unwrap_register(),
-                    offset: 0,
-                });
+                let jump_if_false = scope.emit_instruction_at(
+                    Instruction::JumpIfFalse {
+                        src: src.unwrap_register(),
+                        offset: 0,
+                    },
+                    ast.span(condition),
+                );
 
-                let src = self.compile_expression(ast, scope, captures, then_branch);
-                let src = materialize(scope, src);
+                let src = self.compile_expression(ast, scope, resolution, then_branch)?;
+                let src = materialize(scope, src)?;
                 scope.emit_instruction(Instruction::Move {
                     dest,
                     src: src.unwrap_register(),
@@ -404,11 +678,11 @@ impl Compiler {
                 );
 
                 let src = if let Some(else_branch) = else_branch {
-                    self.compile_expression(ast, scope, captures, else_branch)
+                    self.compile_expression(ast, scope, resolution, else_branch)?
                 } else {
-                    self.unit()
+                    self.unit(scope)?
                 };
-                let src = materialize(scope, src);
+                let src = materialize(scope, src)?;
                 scope.emit_instruction(Instruction::Move {
                     dest,
                     src: src.unwrap_register(),
@@ -422,28 +696,138 @@ impl Compiler {
 
                 Operand::Register(dest)
             }
-            Expr::ForLoop { .. } => self.unit(),
-            Expr::WhileLoop { condition, block } => {
-                let src = self.compile_expression(ast, scope, captures, condition);
-                let src = materialize(scope, src);
+            Expr::ForLoop {
+                variable,
+                start,
+                end,
+                step,
+                descending,
+                block,
+            } => {
+                // The loop owns four consecutive registers: the hidden counter, limit
+                // and step, followed by the copy of the counter visible to the body.
+                let base = scope.allocate_register()?;
+                let limit = scope.allocate_register()?;
+                let increment = scope.allocate_register()?;
+                let counter = scope.allocate_register()?;
+
+                let src = self.compile_expression(ast, scope, resolution, start)?;
+                let src = materialize(scope, src)?;
+                scope.emit_instruction(Instruction::Move {
+                    dest: base,
+                    src: src.unwrap_register(),
+                });
 
-                let jump_if_false = scope.emit_instruction(Instruction::JumpIfFalse {
+                let src = self.compile_expression(ast, scope, resolution, end)?;
+                let src = materialize(scope, src)?;
+                scope.emit_instruction(Instruction::Move {
+                    dest: limit,
                     src: src.unwrap_register(),
-                    offset: 0,
                 });
 
+                match step {
+                    Some(step) => {
+                        // `downto` already counts down, so a negative step would
+                        // count up past the limit and never run the body.
+                        if descending && is_negative_literal(ast, step) {
+                            return Err(report_error!(
+                                ast.span(step).unwrap().clone(),
+                                "step of a `downto` loop must be positive, it already counts down"
+                            ));
+                        }
+
+                        let src = self.compile_expression(ast, scope, resolution, step)?;
+                        let src = materialize(scope, src)?;
+                        scope.emit_instruction(Instruction::Move {
+                            dest: increment,
+                            src: src.unwrap_register(),
+                        });
+                    }
+                    None => {
+                        let src = self.push_number(1.0);
+
+                        emit_load_constant(scope, increment, src)?;
+                    }
+                }
+
+                let for_prep = scope.emit_instruction_at(
+                    Instruction::ForPrep {
+                        descending,
+                        base,
+                        offset: 0,
+                    },
+                    ast.span(variable),
+                );
+
                 let loop_body = scope.instructions.len();
 
-                self.compile_expression(ast, scope, captures, block);
+                let Expr::Identifier(name) = *ast.get(variable) else {
+                    unreachable!("for loop variable must be parsed as identifier");
+                };
 
-                let src = self.compile_expression(ast, scope, captures, condition);
-                let src = materialize(scope, src);
+                scope.enter_scope();
 
-                let jump_if_true = scope.emit_instruction(Instruction::JumpIfTrue {
-                    src: src.unwrap_register(),
-                    offset: 0,
+                // A captured variable that changes gets a new cell every
+                // iteration, so each closure keeps the value of its own.
+                if resolution.cells.contains(&variable) {
+                    let local = scope.insert_symbol(name, true)?;
+                    store_local(scope, local, true, counter);
+                } else {
+                    scope.declare(name, counter, false);
+                }
+
+                scope.enter_loop();
+                self.compile_expression(ast, scope, resolution, block)?;
+                let context = scope.exit_loop();
+
+                scope.exit_scope();
+
+                let loop_step = scope.emit_instruction(Instruction::ForLoop {
+                    base,
+                    offset: loop_body as i32 - scope.instructions.len() as i32,
                 });
 
+                patch_jump(
+                    scope,
+                    for_prep,
+                    scope.instructions.len() as i32 - for_prep as i32,
+                );
+
+                patch_loop_exits(scope, context, loop_step);
+
+                self.unit(scope)?
+            }
+            Expr::WhileLoop { condition, block } => {
+                let src = self.compile_expression(ast, scope, resolution, condition)?;
+                let src = materialize(scope, src)?;
+
+                let jump_if_false = scope.emit_instruction_at(
+                    Instruction::JumpIfFalse {
+                        src: src.unwrap_register(),
+                        offset: 0,
+                    },
+                    ast.span(condition),
+                );
+
+                let loop_body = scope.instructions.len();
+
+                scope.enter_loop();
+                self.compile_expression(ast, scope, resolution, block)?;
+                let context = scope.exit_loop();
+
+                let loop_condition = scope.instructions.len();
+
+                let src = self.compile_expression(ast, scope, resolution, condition)?;
+                let src = materialize(scope, src)?;
+
+                let jump_if_true = scope.emit_instruction_at(
+                    Instruction::JumpIfTrue {
+                        src: src.unwrap_register(),
+                        offset: 0,
+                    },
+                    ast.span(condition),
+                );
+
                 patch_jump(scope, jump_if_true, loop_body as i32 - jump_if_true as i32);
                 patch_jump(
                     scope,
@@ -451,53 +835,121 @@ impl Compiler {
                     scope.instructions.len() as i32 - jump_if_false as i32,
                 );
 
-                self.unit()
+                patch_loop_exits(scope, context, loop_condition);
+
+                self.unit(scope)?
             }
             Expr::Return(expression) => {
                 let src = match expression {
-                    Some(expr) => self.compile_expression(ast, scope, captures, expr),
-                    None => self.unit(),
+                    Some(expr) => self.compile_expression(ast, scope, resolution, expr)?,
+                    None => self.unit(scope)?,
                 };
 
-                let src = materialize(scope, src);
+                let src = materialize(scope, src)?;
                 scope.emit_instruction(Instruction::Return {
                     src: src.unwrap_register(),
                 });
-                self.unit()
+                self.unit(scope)?
+            }
+            // Imported names are declared by the loader before compilation.
+            Expr::Import(_) => self.unit(scope)?,
+            Expr::Export(declaration) => {
+                self.compile_expression(ast, scope, resolution, declaration)?
+            }
+            Expr::Break => {
+                let span = ast.span(expression).unwrap().clone();
+                let jump = scope.emit_instruction(Instruction::Jump { offset: 0 });
+
+                let Some(context) = scope.current_loop() else {
+                    return Err(report_error!(
+                        span,
+                        "`break` can only be used inside a loop"
+                    ));
+                };
+
+                context.breaks.push(jump);
+
+                self.unit(scope)?
+            }
+            Expr::Continue => {
+                let span = ast.span(expression).unwrap().clone();
+                let jump = scope.emit_instruction(Instruction::Jump { offset: 0 });
+
+                let Some(context) = scope.current_loop() else {
+                    return Err(report_error!(
+                        span,
+                        "`continue` can only be used inside a loop"
+                    ));
+                };
+
+                context.continues.push(jump);
+
+                self.unit(scope)?
             }
-            Expr::Break => todo!(),
-            Expr::Continue => todo!(),
             Expr::Identifier(name) => {
-                let found = scope.lookup_or_declare(name);
+                let local = scope.lookup_or_declare(name)?;
+
+                if local.is_cell {
+                    let dest = scope.allocate_register()?;
+
+                    scope.emit_instruction(Instruction::GetCell {
+                        dest,
+                        cell: local.register,
+                    });
 
-                Operand::Register(found)
+                    Operand::Register(dest)
+                } else {
+                    Operand::Register(local.register)
+                }
             }
             Expr::StringLiteral(value) => {
                 let index = self.push_string(value);
 
-                Operand::Constant(index as u16)
+                constant_operand(scope, index)?
             }
             Expr::NumberLiteral(value) => {
                 let index = self.push_number(value);
 
-                Operand::Constant(index as u16)
+                constant_operand(scope, index)?
+            }
+            Expr::BooleanLiteral(value) => {
+                let index = self.push_boolean(value);
+
+                constant_operand(scope, index)?
+            }
+            Expr::NilLiteral => self.unit(scope)?,
+            Expr::VecLiteral { ref elements } => {
+                let dest = scope.allocate_register()?;
+                scope.emit_instruction(Instruction::CreateVec { dest });
+
+                for element in elements.iter().copied() {
+                    let src = self.compile_expression(ast, scope, resolution, element)?;
+                    let src = materialize(scope, src)?;
+
+                    scope.emit_instruction(Instruction::VecPush {
+                        vec: dest,
+                        src: src.unwrap_register(),
+                    });
+                }
+
+                Operand::Register(dest)
             }
             Expr::DictLiteral { ref fields } => {
-                let dest = scope.allocate_register();
+                let dest = scope.allocate_register()?;
                 scope.emit_instruction(Instruction::CreateDict { dest });
 
                 for (key, value) in fields.iter().copied() {
-                    let key_op = self.compile_expression(ast, scope, captures, key);
-                    let key_op = materialize(scope, key_op);
+                    let key_op = self.compile_expression(ast, scope, resolution, key)?;
+                    let key_op = materialize(scope, key_op)?;
 
                     let value_op = match value {
                         Some(v) => {
-                            let v = self.compile_expression(ast, scope, captures, v);
-                            materialize(scope, v)
+                            let v = self.compile_expression(ast, scope, resolution, v)?;
+                            materialize(scope, v)?
                         }
                         None => {
-                            let v = self.compile_expression(ast, scope, captures, key);
-                            materialize(scope, v)
+                            let v = self.compile_expression(ast, scope, resolution, key)?;
+                            materialize(scope, v)?
                         }
                     };
 
@@ -510,21 +962,73 @@ impl Compiler {
 
                 Operand::Register(dest)
             }
-        }
+        };
+
+        scope.span = parent_span;
+
+        Ok(operand)
     }
 
     fn compile_binary_op(
         &mut self,
         ast: &Ast,
         scope: &mut FunctionScope,
-        captures: &HashMap<ExprId, Vec<StringIndex>>,
+        resolution: &Resolution,
         operator: BinaryOp,
         left: ExprId,
         right: ExprId,
-    ) -> Operand {
-        let src1 = self.compile_expression(ast, scope, captures, left);
-        let src2 = self.compile_expression(ast, scope, captures, right);
-        let dest = scope.allocate_register();
+    ) -> Result<Operand, Error> {
+        let src1 = self.compile_expression(ast, scope, resolution, left)?;
+        let src2 = self.compile_expression(ast, scope, resolution, right)?;
+
+        self.emit_binary_op(scope, operator, src1, src2)
+    }
+
+    /// Compiles the value stored by a field or index assignment. Compound
+    /// operators read the current value with `read` first, so the target
+    /// object and key are only evaluated once.
+    fn compile_assigned_value(
+        &mut self,
+        ast: &Ast,
+        scope: &mut FunctionScope,
+        resolution: &Resolution,
+        operator: AssignOp,
+        read: impl Fn(u16) -> Instruction,
+        right: ExprId,
+    ) -> Result<u16, Error> {
+        let value = match operator.binary_operator() {
+            Some(operator) => {
+                let current = scope.allocate_register()?;
+                scope.emit_instruction(read(current));
+
+                let src2 = self.compile_expression(ast, scope, resolution, right)?;
+
+                self.emit_binary_op(scope, operator, Operand::Register(current), src2)?
+            }
+            None => self.compile_expression(ast, scope, resolution, right)?,
+        };
+
+        Ok(materialize(scope, value)?.unwrap_register())
+    }
+
+    fn emit_binary_op(
+        &mut self,
+        scope: &mut FunctionScope,
+        operator: BinaryOp,
+        mut src1: Operand,
+        mut src2: Operand,
+    ) -> Result<Operand, Error> {
+        if let (Operand::Constant(_), Operand::Constant(_)) = (src1, src2) {
+            src1 = materialize(scope, src1)?;
+        }
+
+        // Only equality accepts any constant, the other constant forms assume numbers.
+        if !matches!(operator, BinaryOp::Equal | BinaryOp::NotEqual) {
+            src1 = self.materialize_non_number(scope, src1)?;
+            src2 = self.materialize_non_number(scope, src2)?;
+        }
+
+        let dest = scope.allocate_register()?;
 
         let instruction = match (src1, src2) {
             (Operand::Register(src1), Operand::Register(src2)) => match operator {
@@ -632,12 +1136,13 @@ impl Compiler {
             },
 
             (Operand::Constant(_), Operand::Constant(_)) => {
-                unreachable!("No constant fold done yet!")
+                unreachable!("the left operand is materialized when both are constants")
             }
         };
 
         scope.emit_instruction(instruction);
-        Operand::Register(dest)
+
+        Ok(Operand::Register(dest))
     }
 
     /*     fn compile_loop(
@@ -649,11 +1154,11 @@ impl Compiler {
            increment: Option<&Expr>,
        ) -> Operand {
            if let Some(init) = init {
-               self.compile_expression(ast, scope, captures, init);
+               self.compile_expression(ast, scope, resolution, init);
            }
 
-           let src = self.compile_expression(ast, scope, captures, condition);
-           let src = materialize(scope, src);
+           let src = self.compile_expression(ast, scope, resolution, condition);
+           let src = materialize(scope, src)?;
 
            let jump_if_false = scope.emit_instruction(Instruction::JumpIfFalse {
                src: src.unwrap_register(),
@@ -662,14 +1167,14 @@ impl Compiler {
 
            let loop_body = scope.instructions.len();
 
-           self.compile_expression(ast, scope, captures, block);
+           self.compile_expression(ast, scope, resolution, block);
 
            if let Some(increment) = increment {
-               self.compile_expression(ast, scope, captures, increment);
+               self.compile_expression(ast, scope, resolution, increment);
            }
 
-           let src = self.compile_expression(ast, scope, captures, condition);
-           let src = materialize(scope, src);
+           let src = self.compile_expression(ast, scope, resolution, condition);
+           let src = materialize(scope, src)?;
 
            let jump_if_true = scope.emit_instruction(Instruction::JumpIfTrue {
                src: src.unwrap_register(),
@@ -683,7 +1188,7 @@ impl Compiler {
                scope.instructions.len() as i32 - jump_if_false as i32,
            );
 
-           self.unit()
+           self.unit(scope)?
        }
 
     */
@@ -716,34 +1221,242 @@ impl Compiler {
         }
     }
 
-    fn unit(&mut self) -> Operand {
-        Operand::Constant(self.push_number(0.0) as u16)
+    fn materialize_non_number(
+        &mut self,
+        scope: &mut FunctionScope,
+        src: Operand,
+    ) -> Result<Operand, Error> {
+        match src {
+            Operand::Constant(index) if !self.constants[index as usize].is_number() => {
+                materialize(scope, src)
+            }
+            _ => Ok(src),
+        }
+    }
+
+    fn unit(&mut self, scope: &mut FunctionScope) -> Result<Operand, Error> {
+        let index = self.push_nil();
+
+        constant_operand(scope, index)
     }
 }
-fn materialize(scope: &mut FunctionScope, src: Operand) -> Operand {
+
+/// Constants out of reach of the 16 bit constant operands are loaded into a
+/// register instead.
+fn constant_operand(scope: &mut FunctionScope, index: usize) -> Result<Operand, Error> {
+    if let Ok(index) = u16::try_from(index) {
+        return Ok(Operand::Constant(index));
+    }
+
+    let dest = scope.allocate_register()?;
+    emit_load_constant(scope, dest, index)?;
+
+    Ok(Operand::Register(dest))
+}
+
+fn emit_load_constant(scope: &mut FunctionScope, dest: u16, index: usize) -> Result<(), Error> {
+    let instruction = if let Ok(src) = u16::try_from(index) {
+        Instruction::LoadK { dest, src }
+    } else if let Ok(src) = u32::try_from(index) {
+        Instruction::LoadKWide { dest, src }
+    } else {
+        return Err(Error::new(
+            scope.span.clone(),
+            format!("program has more than {} constants", u32::MAX),
+        ));
+    };
+
+    scope.emit_instruction(instruction);
+
+    Ok(())
+}
+
+fn materialize(scope: &mut FunctionScope, src: Operand) -> Result<Operand, Error> {
     match src {
-        Operand::Register(_) => src,
+        Operand::Register(_) => Ok(src),
         Operand::Constant(src) => {
-            let dest = scope.allocate_register();
+            let dest = scope.allocate_register()?;
             scope.emit_instruction(Instruction::LoadK { dest, src });
-            Operand::Register(dest)
+
+            Ok(Operand::Register(dest))
         }
     }
 }
 
+/// Whether `expression` is a number literal below zero, such as `-2`.
+fn is_negative_literal(ast: &Ast, expression: ExprId) -> bool {
+    match *ast.get(expression) {
+        Expr::NumberLiteral(value) => value < 0.0,
+        Expr::Unary {
+            operator: UnaryOp::Negate,
+            right,
+        } => matches!(*ast.get(right), Expr::NumberLiteral(value) if value > 0.0),
+        _ => false,
+    }
+}
+
 fn patch_jump(scope: &mut FunctionScope, index: usize, new_offset: i32) {
     match &mut scope.instructions[index] {
         Instruction::Jump { offset }
         | Instruction::JumpIfTrue { offset, .. }
-        | Instruction::JumpIfFalse { offset, .. } => *offset = new_offset,
+        | Instruction::JumpIfFalse { offset, .. }
+        | Instruction::ForPrep { offset, .. } => *offset = new_offset,
         _ => panic!("tried to patch a non-jump instruction at index {index}"),
     }
 }
 
+fn patch_loop_exits(scope: &mut FunctionScope, context: LoopContext, continue_target: usize) {
+    let break_target = scope.instructions.len();
+
+    for jump in context.breaks {
+        patch_jump(scope, jump, break_target as i32 - jump as i32);
+    }
+
+    for jump in context.continues {
+        patch_jump(scope, jump, continue_target as i32 - jump as i32);
+    }
+}
+
+/// Finds the variable named by `identifier`, declaring it when it's not in
+/// scope yet. Also returns whether it was declared.
+fn compile_local(
+    ast: &Ast,
+    scope: &mut FunctionScope,
+    resolution: &Resolution,
+    identifier: ExprId,
+) -> Result<(Local, bool), Error> {
+    let Expr::Identifier(name) = *ast.get(identifier) else {
+        unreachable!("variable must be parsed as identifier");
+    };
+
+    match scope.lookup(name) {
+        Some(local) => Ok((local, false)),
+        None => {
+            let is_cell = resolution.cells.contains(&identifier);
+
+            Ok((scope.insert_symbol(name, is_cell)?, true))
+        }
+    }
+}
+
+/// Register to compute a value stored in `local`, a cell needs the value in
+/// a register of its own.
+fn value_register(scope: &mut FunctionScope, local: Local) -> Result<u16, Error> {
+    if local.is_cell {
+        scope.allocate_register()
+    } else {
+        Ok(local.register)
+    }
+}
+
+/// Stores the value of `src` in `local`. The declaration of a cell creates
+/// it, later assignments write through it.
+fn store_local(scope: &mut FunctionScope, local: Local, declared: bool, src: u16) {
+    let instruction = match (local.is_cell, declared) {
+        (false, _) if src == local.register => return,
+        (false, _) => Instruction::Move {
+            dest: local.register,
+            src,
+        },
+        (true, true) => Instruction::CreateCell {
+            dest: local.register,
+            src,
+        },
+        (true, false) => Instruction::SetCell {
+            cell: local.register,
+            src,
+        },
+    };
+
+    scope.emit_instruction(instruction);
+}
+
+/// Turns calls whose value is returned right away into tail calls, which run
+/// the callee in the frame of the caller. The value may pass through moves
+/// and jumps on its way to the return, which become unreachable.
+fn patch_tail_calls(scope: &mut FunctionScope) {
+    let instructions = &mut scope.instructions;
+
+    for index in 0..instructions.len() {
+        let Instruction::Call { dest, src, arity } = instructions[index] else {
+            continue;
+        };
+
+        let mut value = dest;
+        let mut next = index + 1;
+
+        // A jump may only lead back to the call in an infinite loop, which is
+        // cut off after visiting every instruction once.
+        for _ in 0..instructions.len() {
+            match instructions.get(next) {
+                Some(&Instruction::Move { dest, src }) if src == value => {
+                    value = dest;
+                    next += 1;
+                }
+                Some(&Instruction::Jump { offset }) => {
+                    next = (next as i32 + offset) as usize;
+                }
+                Some(&Instruction::Return { src: returned }) if returned == value => {
+                    instructions[index] = Instruction::TailCall { src, arity };
+                    break;
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
 fn patch_function_arguments(scope: &mut FunctionScope) {
+    let frame_size = scope.registers_count();
+
     for instruction in &mut scope.instructions {
         if let Instruction::MoveArg { dest, .. } = instruction {
-            *dest += scope.next_register;
+            *dest += frame_size;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{diagnostics::source_map::SourceMap, program::compile_source_code};
+
+    use super::*;
+
+    /// Registers taken by `fn long(a, b)` when its body repeats `statement`.
+    fn registers_count(statement: &str, times: usize) -> u16 {
+        let source = format!(
+            "fn long(a, b) {{ x := 0; {} return x; }}",
+            statement.repeat(times)
+        );
+
+        let (_, functions) = compile_source_code(
+            &source,
+            None,
+            &NativeRegistry::default(),
+            &mut SourceMap::default(),
+        )
+        .unwrap_or_else(|_| panic!("`{}` must compile", source));
+
+        functions[1].registers_count
+    }
+
+    #[test]
+    fn temporaries_are_reused_by_later_statements() {
+        let statement = "x = x + a * b + (a - b) * (a + b);";
+
+        assert_eq!(
+            registers_count(statement, 50),
+            registers_count(statement, 1)
+        );
+    }
+
+    #[test]
+    fn block_locals_are_released_at_its_end() {
+        let statement = "if x > 3 { y := x + 1; z := y * 2; x = z; }";
+
+        assert_eq!(
+            registers_count(statement, 50),
+            registers_count(statement, 1)
+        );
+    }
+}
@@ -1,11 +1,29 @@
 use super::instruction::Instruction;
+use crate::util::string_interner::StringIndex;
 use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
 
 #[derive(Debug, Default)]
 pub struct Function {
     pub instructions: Vec<Instruction>,
-    pub registers_count: u8,
+    /// Source span of each instruction, used to locate runtime errors.
+    pub spans: Vec<Option<Range<usize>>>,
+    pub name: Option<StringIndex>,
+    pub is_script: bool,
+    pub registers_count: u16,
     pub arity: u8,
+    /// Variables of the function and where they live, for debuggers.
+    pub locals: Vec<DebugLocal>,
+}
+
+/// A variable held in `register` while the instructions in `range` run.
+#[derive(Clone, Debug)]
+pub struct DebugLocal {
+    pub name: StringIndex,
+    pub register: u16,
+    /// The register holds a cell with the variable's value.
+    pub is_cell: bool,
+    pub range: Range<usize>,
 }
 
 impl Display for Function {
@@ -1,17 +1,69 @@
-use crate::{bytecode::instruction::Instruction, util::string_interner::StringIndex};
+use std::ops::Range;
+
+use crate::{
+    bytecode::{function::DebugLocal, instruction::Instruction},
+    diagnostics::error::Error,
+    util::string_interner::StringIndex,
+};
+
+/// Registers a function may use. The arguments of a call are written right
+/// above the caller's registers, so the last 255 register indices stay free
+/// for them.
+pub const MAX_REGISTERS: u16 = u16::MAX - u8::MAX as u16;
+
+/// A variable in scope and the register that holds it.
+#[derive(Clone, Copy, Debug)]
+pub struct Local {
+    pub name: StringIndex,
+    pub register: u16,
+    /// The register holds a cell that closures capturing the variable share.
+    pub is_cell: bool,
+}
+
+#[derive(Default)]
+pub struct LoopContext {
+    pub breaks: Vec<usize>,
+    pub continues: Vec<usize>,
+}
 
 #[derive(Default)]
 pub struct FunctionScope {
-    names: Vec<(StringIndex, u8)>,
+    names: Vec<Local>,
+    /// Debug info of every variable declared, `open_locals` holds the index
+    /// in it of each variable in `names`.
+    locals: Vec<DebugLocal>,
+    open_locals: Vec<usize>,
     scopes: Vec<usize>,
+    loops: Vec<LoopContext>,
     pub instructions: Vec<Instruction>,
-    pub next_register: u8,
+    pub spans: Vec<Option<Range<usize>>>,
+    /// Span of the innermost expression being compiled, attached to every
+    /// emitted instruction.
+    pub span: Option<Range<usize>>,
+    pub next_register: u16,
+    /// Most registers held at once, temporaries are released after use.
+    peak_registers: u16,
 }
 
 impl FunctionScope {
     pub fn emit_instruction(&mut self, instruction: Instruction) -> usize {
         let index = self.instructions.len();
         self.instructions.push(instruction);
+        self.spans.push(self.span.clone());
+
+        index
+    }
+
+    pub fn emit_instruction_at(
+        &mut self,
+        instruction: Instruction,
+        span: Option<&Range<usize>>,
+    ) -> usize {
+        let index = self.emit_instruction(instruction);
+
+        if let Some(span) = span {
+            self.spans[index] = Some(span.clone());
+        }
 
         index
     }
@@ -23,29 +75,127 @@ impl FunctionScope {
     pub fn exit_scope(&mut self) {
         let size = self.scopes.pop().unwrap();
         self.names.truncate(size);
+
+        for index in self.open_locals.drain(size..) {
+            self.locals[index].range.end = self.instructions.len();
+        }
     }
 
-    fn insert_symbol(&mut self, name: StringIndex) -> u8 {
-        let register = self.allocate_register();
+    pub fn enter_loop(&mut self) {
+        self.loops.push(LoopContext::default());
+    }
 
-        self.names.push((name, register));
+    pub fn exit_loop(&mut self) -> LoopContext {
+        self.loops
+            .pop()
+            .expect("exit_loop called without a matching enter_loop")
+    }
 
-        register
+    pub fn current_loop(&mut self) -> Option<&mut LoopContext> {
+        self.loops.last_mut()
     }
 
-    pub fn lookup_or_declare(&mut self, name: StringIndex) -> u8 {
-        for (found_name, register) in self.names.iter().copied().rev() {
-            if found_name == name {
-                return register;
-            }
+    pub fn insert_symbol(&mut self, name: StringIndex, is_cell: bool) -> Result<Local, Error> {
+        let register = self.allocate_register()?;
+
+        Ok(self.declare(name, register, is_cell))
+    }
+
+    pub fn declare(&mut self, name: StringIndex, register: u16, is_cell: bool) -> Local {
+        let local = Local {
+            name,
+            register,
+            is_cell,
+        };
+
+        self.names.push(local);
+        self.open_locals.push(self.locals.len());
+        self.locals.push(DebugLocal {
+            name,
+            register,
+            is_cell,
+            range: self.instructions.len()..self.instructions.len(),
+        });
+
+        local
+    }
+
+    /// Takes the debug info of the variables declared so far, the ones still
+    /// in scope live until the last instruction. They are recorded again from
+    /// the first instruction of the code compiled next with this scope.
+    pub fn take_locals(&mut self) -> Vec<DebugLocal> {
+        let end = self.instructions.len();
+
+        for &index in &self.open_locals {
+            self.locals[index].range.end = end;
         }
 
-        self.insert_symbol(name)
+        let locals = std::mem::take(&mut self.locals);
+
+        for (position, index) in self.open_locals.iter_mut().enumerate() {
+            let local = &self.names[position];
+
+            self.locals.push(DebugLocal {
+                name: local.name,
+                register: local.register,
+                is_cell: local.is_cell,
+                range: 0..0,
+            });
+
+            *index = position;
+        }
+
+        locals
+    }
+
+    /// Variables currently in scope.
+    pub fn names(&self) -> &[Local] {
+        &self.names
+    }
+
+    pub fn lookup(&self, name: StringIndex) -> Option<Local> {
+        self.names
+            .iter()
+            .rev()
+            .find(|local| local.name == name)
+            .copied()
     }
 
-    pub fn allocate_register(&mut self) -> u8 {
+    pub fn lookup_or_declare(&mut self, name: StringIndex) -> Result<Local, Error> {
+        match self.lookup(name) {
+            Some(local) => Ok(local),
+            None => self.insert_symbol(name, false),
+        }
+    }
+
+    pub fn allocate_register(&mut self) -> Result<u16, Error> {
+        if self.next_register == MAX_REGISTERS {
+            return Err(Error::new(
+                self.span.clone(),
+                format!(
+                    "function needs more than {} registers, split it into smaller functions",
+                    MAX_REGISTERS
+                ),
+            ));
+        }
+
         let register = self.next_register;
         self.next_register += 1;
-        register
+        self.peak_registers = self.peak_registers.max(self.next_register);
+
+        Ok(register)
+    }
+
+    /// Releases every register allocated after `mark`, they are reused by
+    /// the code compiled next.
+    pub fn free_registers(&mut self, mark: u16) {
+        debug_assert!(mark <= self.next_register);
+
+        self.next_register = mark;
+    }
+
+    /// Size of the frame, the number of registers live at the same time.
+    pub fn registers_count(&self) -> u16 {
+        self.peak_registers.max(self.next_register)
     }
 }
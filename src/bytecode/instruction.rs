@@ -3,64 +3,361 @@ use std::fmt;
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 pub enum Instruction {
-    Add { dest: u8, src1: u8, src2: u8 },
-    AddK { dest: u8, src1: u8, src2: u16 },
-    Subtract { dest: u8, src1: u8, src2: u8 },
-    SubtractRK { dest: u8, src1: u8, src2: u16 },
-    SubtractKR { dest: u8, src1: u16, src2: u8 },
-    Multiply { dest: u8, src1: u8, src2: u8 },
-    MultiplyK { dest: u8, src1: u8, src2: u16 },
-    Divide { dest: u8, src1: u8, src2: u8 },
-    DivideRK { dest: u8, src1: u8, src2: u16 },
-    DivideKR { dest: u8, src1: u16, src2: u8 },
-    Modulo { dest: u8, src1: u8, src2: u8 },
-    ModuloRK { dest: u8, src1: u8, src2: u16 },
-    ModuloKR { dest: u8, src1: u16, src2: u8 },
-    Equal { dest: u8, src1: u8, src2: u8 },
-    EqualK { dest: u8, src1: u8, src2: u16 },
-    NotEqual { dest: u8, src1: u8, src2: u8 },
-    NotEqualK { dest: u8, src1: u8, src2: u16 },
-    Less { dest: u8, src1: u8, src2: u8 },
-    LessK { dest: u8, src1: u8, src2: u16 },
-    LessEqual { dest: u8, src1: u8, src2: u8 },
-    LessEqualK { dest: u8, src1: u8, src2: u16 },
-    Greater { dest: u8, src1: u8, src2: u8 },
-    GreaterK { dest: u8, src1: u8, src2: u16 },
-    GreaterEqual { dest: u8, src1: u8, src2: u8 },
-    GreaterEqualK { dest: u8, src1: u8, src2: u16 },
-    Not { dest: u8, src: u8 },
-    Negate { dest: u8, src: u8 },
-    Move { dest: u8, src: u8 },
-    MoveArg { dest: u8, src: u8 },
-    LoadK { dest: u8, src: u16 },
-    CreateDict { dest: u8 },
-    SetField { object: u8, key: u8, value: u8 },
-    GetField { dest: u8, object: u8, key: u8 },
-    CreateClosure { dest: u8, src: u32 },
-    CaptureValue { dest: u8, src: u8 },
-    Call { dest: u8, src: u8, arity: u8 },
-    Return { src: u8 },
-    Jump { offset: i32 },
-    JumpIfFalse { src: u8, offset: i32 },
-    JumpIfTrue { src: u8, offset: i32 },
-    JumpIfLess { src1: u8, src2: u8, offset: i32 },
-    JumpIfLessK { src1: u8, src2: u16, offset: i32 },
-    JumpIfLessEqual { src1: u8, src2: u8, offset: i32 },
-    JumpIfLessEqualK { src1: u8, src2: u16, offset: i32 },
-    JumpIfGreater { src1: u8, src2: u8, offset: i32 },
-    JumpIfGreaterK { src1: u8, src2: u16, offset: i32 },
-    JumpIfGreaterEqual { src1: u8, src2: u8, offset: i32 },
-    JumpIfGreaterEqualK { src1: u8, src2: u16, offset: i32 },
-    JumpIfEqual { src1: u8, src2: u8, offset: i32 },
-    JumpIfEqualK { src1: u8, src2: u16, offset: i32 },
-    JumpIfNotEqual { src1: u8, src2: u8, offset: i32 },
-    JumpIfNotEqualK { src1: u8, src2: u16, offset: i32 },
+    Add {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    AddK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    Subtract {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    SubtractRK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    SubtractKR {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    Multiply {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    MultiplyK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    Divide {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    DivideRK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    DivideKR {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    Modulo {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    ModuloRK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    ModuloKR {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    Equal {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    EqualK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    NotEqual {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    NotEqualK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    Less {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    LessK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    LessEqual {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    LessEqualK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    Greater {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    GreaterK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    GreaterEqual {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    GreaterEqualK {
+        dest: u16,
+        src1: u16,
+        src2: u16,
+    },
+    Not {
+        dest: u16,
+        src: u16,
+    },
+    Negate {
+        dest: u16,
+        src: u16,
+    },
+    Move {
+        dest: u16,
+        src: u16,
+    },
+    MoveArg {
+        dest: u16,
+        src: u16,
+    },
+    LoadK {
+        dest: u16,
+        src: u16,
+    },
+    LoadKWide {
+        dest: u16,
+        src: u32,
+    },
+    CreateDict {
+        dest: u16,
+    },
+    SetField {
+        object: u16,
+        key: u16,
+        value: u16,
+    },
+    GetField {
+        dest: u16,
+        object: u16,
+        key: u16,
+    },
+    GetIndex {
+        dest: u16,
+        object: u16,
+        index: u16,
+    },
+    SetIndex {
+        object: u16,
+        index: u16,
+        value: u16,
+    },
+    CreateVec {
+        dest: u16,
+    },
+    VecPush {
+        vec: u16,
+        src: u16,
+    },
+    CreateClosure {
+        dest: u16,
+        src: u32,
+    },
+    CaptureValue {
+        dest: u16,
+        src: u16,
+    },
+    CreateCell {
+        dest: u16,
+        src: u16,
+    },
+    GetCell {
+        dest: u16,
+        cell: u16,
+    },
+    SetCell {
+        cell: u16,
+        src: u16,
+    },
+    Call {
+        dest: u16,
+        src: u16,
+        arity: u8,
+    },
+    TailCall {
+        src: u16,
+        arity: u8,
+    },
+    Return {
+        src: u16,
+    },
+    Jump {
+        offset: i32,
+    },
+    JumpIfFalse {
+        src: u16,
+        offset: i32,
+    },
+    JumpIfTrue {
+        src: u16,
+        offset: i32,
+    },
+    JumpIfLess {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfLessK {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfLessEqual {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfLessEqualK {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfGreater {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfGreaterK {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfGreaterEqual {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfGreaterEqualK {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfEqual {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfEqualK {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfNotEqual {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    JumpIfNotEqualK {
+        src1: u16,
+        src2: u16,
+        offset: i16,
+    },
+    ForPrep {
+        descending: bool,
+        base: u16,
+        offset: i32,
+    },
+    ForLoop {
+        base: u16,
+        offset: i32,
+    },
     Nop,
 }
+
+// Register operands are 16 bits wide, the fused compare and jump forms trade
+// their offset range to keep every instruction in 8 bytes.
+const _: () = assert!(size_of::<Instruction>() == 8);
+
 impl Instruction {
     pub fn discriminant(&self) -> usize {
         unsafe { *(self as *const Instruction as *const u8) as usize }
     }
+
+    /// Offset of the instruction a jump may continue at, relative to the jump.
+    pub fn jump_offset(&self) -> Option<i32> {
+        match *self {
+            Self::Jump { offset }
+            | Self::JumpIfFalse { offset, .. }
+            | Self::JumpIfTrue { offset, .. }
+            | Self::ForPrep { offset, .. }
+            | Self::ForLoop { offset, .. } => Some(offset),
+            Self::JumpIfLess { offset, .. }
+            | Self::JumpIfLessK { offset, .. }
+            | Self::JumpIfLessEqual { offset, .. }
+            | Self::JumpIfLessEqualK { offset, .. }
+            | Self::JumpIfGreater { offset, .. }
+            | Self::JumpIfGreaterK { offset, .. }
+            | Self::JumpIfGreaterEqual { offset, .. }
+            | Self::JumpIfGreaterEqualK { offset, .. }
+            | Self::JumpIfEqual { offset, .. }
+            | Self::JumpIfEqualK { offset, .. }
+            | Self::JumpIfNotEqual { offset, .. }
+            | Self::JumpIfNotEqualK { offset, .. } => Some(offset as i32),
+            _ => None,
+        }
+    }
+
+    /// Index of the constant read by the instruction.
+    pub fn constant(&self) -> Option<u32> {
+        match *self {
+            Self::AddK { src2, .. }
+            | Self::SubtractRK { src2, .. }
+            | Self::MultiplyK { src2, .. }
+            | Self::DivideRK { src2, .. }
+            | Self::ModuloRK { src2, .. }
+            | Self::EqualK { src2, .. }
+            | Self::NotEqualK { src2, .. }
+            | Self::LessK { src2, .. }
+            | Self::LessEqualK { src2, .. }
+            | Self::GreaterK { src2, .. }
+            | Self::GreaterEqualK { src2, .. }
+            | Self::JumpIfLessK { src2, .. }
+            | Self::JumpIfLessEqualK { src2, .. }
+            | Self::JumpIfGreaterK { src2, .. }
+            | Self::JumpIfGreaterEqualK { src2, .. }
+            | Self::JumpIfEqualK { src2, .. }
+            | Self::JumpIfNotEqualK { src2, .. } => Some(src2 as u32),
+            Self::SubtractKR { src1, .. }
+            | Self::DivideKR { src1, .. }
+            | Self::ModuloKR { src1, .. } => Some(src1 as u32),
+            Self::LoadK { src, .. } => Some(src as u32),
+            Self::LoadKWide { src, .. } => Some(src),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Instruction {
@@ -156,6 +453,9 @@ impl fmt::Display for Instruction {
             Self::LoadK { dest, src } => {
                 write!(f, "LOADK r{} k{}", dest, src)
             }
+            Self::LoadKWide { dest, src } => {
+                write!(f, "LOADK_WIDE r{} k{}", dest, src)
+            }
             Self::CreateDict { dest } => {
                 write!(f, "DICT r{}", dest)
             }
@@ -165,9 +465,32 @@ impl fmt::Display for Instruction {
             Self::GetField { dest, object, key } => {
                 write!(f, "GET r{} r{} r{}", dest, object, key)
             }
+            Self::GetIndex {
+                dest,
+                object,
+                index,
+            } => {
+                write!(f, "GET_INDEX r{} r{} r{}", dest, object, index)
+            }
+            Self::SetIndex {
+                object,
+                index,
+                value,
+            } => {
+                write!(f, "SET_INDEX r{} r{} r{}", object, index, value)
+            }
+            Self::CreateVec { dest } => {
+                write!(f, "VEC r{}", dest)
+            }
+            Self::VecPush { vec, src } => {
+                write!(f, "VEC_PUSH r{} r{}", vec, src)
+            }
             Self::Call { dest, src, arity } => {
                 write!(f, "CALL r{} r{} ARITY({})", dest, src, arity)
             }
+            Self::TailCall { src, arity } => {
+                write!(f, "TAILCALL r{} ARITY({})", src, arity)
+            }
             Self::Return { src } => {
                 write!(f, "RET r{}", src)
             }
@@ -216,12 +539,37 @@ impl fmt::Display for Instruction {
             Self::JumpIfNotEqualK { src1, src2, offset } => {
                 write!(f, "JMP_IF_NEQ r{} k{} {}", src1, src2, offset)
             }
+            Self::ForPrep {
+                descending,
+                base,
+                offset,
+            } => {
+                let name = if *descending {
+                    "FOR_PREP_DOWN"
+                } else {
+                    "FOR_PREP"
+                };
+
+                write!(f, "{} r{} {}", name, base, offset)
+            }
+            Self::ForLoop { base, offset } => {
+                write!(f, "FOR_LOOP r{} {}", base, offset)
+            }
             Self::CreateClosure { dest, src } => {
                 write!(f, "CREATE_CLOSURE r{} FUNCTIONS[{}]", dest, src)
             }
             Self::CaptureValue { dest, src } => {
                 write!(f, "CAPTURE_VALUE r{} r{}", dest, src)
             }
+            Self::CreateCell { dest, src } => {
+                write!(f, "CREATE_CELL r{} r{}", dest, src)
+            }
+            Self::GetCell { dest, cell } => {
+                write!(f, "GET_CELL r{} r{}", dest, cell)
+            }
+            Self::SetCell { cell, src } => {
+                write!(f, "SET_CELL r{} r{}", cell, src)
+            }
             Self::Nop => {
                 write!(f, "NOP")
             }
@@ -4,7 +4,9 @@ pub mod instruction;
 pub mod operand;
 pub use function::Function;
 
+pub mod disassemble;
 pub mod emit_bytecode;
 pub mod function_scope;
 pub mod optimize_bytecode;
 pub mod resolve;
+pub mod serialize_bytecode;
@@ -1,11 +1,11 @@
 #[derive(Clone, Copy)]
 pub enum Operand {
     Constant(u16),
-    Register(u8),
+    Register(u16),
 }
 
 impl Operand {
-    pub fn unwrap_register(self) -> u8 {
+    pub fn unwrap_register(self) -> u16 {
         if let Operand::Register(value) = self {
             value
         } else {
@@ -1,15 +1,292 @@
-use crate::bytecode::{function::Function, instruction::Instruction};
+use std::ops::Range;
+
+use crate::bytecode::{
+    function::{DebugLocal, Function},
+    instruction::Instruction,
+};
 
 pub fn optimize_bytecode(functions: &mut [Function]) {
     for function in functions {
-        let (reachable, leaders) = reachable_and_leaders(&function.instructions);
-        eliminate_dead_code(&mut function.instructions, &reachable);
-        remove_redundant_moves(&mut function.instructions, &leaders);
-        merge_conditional_jumps(&mut function.instructions);
-        remove_nop(&mut function.instructions);
+        let exit = RegisterSet::new(function.registers_count);
+
+        optimize_function(function, &exit);
+    }
+}
+
+/// Optimizes a script whose `globals` are read by code evaluated after it, so
+/// their registers are live when the script returns.
+pub fn optimize_script(function: &mut Function, globals: impl IntoIterator<Item = u16>) {
+    let mut exit = RegisterSet::new(function.registers_count);
+
+    for register in globals {
+        exit.insert(register);
+    }
+
+    optimize_function(function, &exit);
+}
+
+fn optimize_function(function: &mut Function, exit: &RegisterSet) {
+    let (reachable, leaders) = reachable_and_leaders(&function.instructions);
+    eliminate_dead_code(&mut function.instructions, &reachable);
+    let live = live_registers(&function.instructions, exit);
+    remove_redundant_moves(&mut function.instructions, &leaders, &live);
+    let live = live_registers(&function.instructions, exit);
+    merge_conditional_jumps(&mut function.instructions, &mut function.spans, &live);
+    remove_nop(
+        &mut function.instructions,
+        &mut function.spans,
+        &mut function.locals,
+    );
+}
+
+/// Bit set of the registers of one function, every set of a function has
+/// the same number of words.
+#[derive(Clone, PartialEq, Eq)]
+struct RegisterSet(Vec<u64>);
+
+impl RegisterSet {
+    /// Call arguments are moved to the registers right above the frame, so
+    /// the set also covers the maximum arity past `registers_count`.
+    fn new(registers_count: u16) -> Self {
+        Self(vec![
+            0;
+            (registers_count as usize + u8::MAX as usize) / 64 + 1
+        ])
+    }
+
+    fn clear(&mut self) {
+        self.0.fill(0);
+    }
+
+    fn insert(&mut self, register: u16) {
+        self.0[register as usize / 64] |= 1 << (register % 64);
+    }
+
+    fn remove(&mut self, register: u16) {
+        self.0[register as usize / 64] &= !(1 << (register % 64));
+    }
+
+    fn contains(&self, register: u16) -> bool {
+        self.0[register as usize / 64] & (1 << (register % 64)) != 0
+    }
+
+    fn union(&mut self, other: &RegisterSet) {
+        for (word, other) in self.0.iter_mut().zip(&other.0) {
+            *word |= other;
+        }
     }
 }
 
+fn jump_target(index: usize, offset: i32, len: usize) -> usize {
+    ((index as i32 + offset) as usize).clamp(0, len - 1)
+}
+
+pub(crate) fn successors(
+    instructions: &[Instruction],
+    index: usize,
+) -> (Option<usize>, Option<usize>) {
+    let len = instructions.len();
+    let next = (index + 1 < len).then_some(index + 1);
+
+    let target = instructions[index]
+        .jump_offset()
+        .map(|offset| jump_target(index, offset, len));
+
+    match instructions[index] {
+        Instruction::Jump { .. } => (None, target),
+        Instruction::Return { .. } | Instruction::TailCall { .. } => (None, None),
+        _ => (next, target),
+    }
+}
+
+pub(crate) fn destination(instruction: &mut Instruction) -> Option<&mut u16> {
+    match instruction {
+        Instruction::Add { dest, .. }
+        | Instruction::AddK { dest, .. }
+        | Instruction::Subtract { dest, .. }
+        | Instruction::SubtractRK { dest, .. }
+        | Instruction::SubtractKR { dest, .. }
+        | Instruction::Multiply { dest, .. }
+        | Instruction::MultiplyK { dest, .. }
+        | Instruction::Divide { dest, .. }
+        | Instruction::DivideRK { dest, .. }
+        | Instruction::DivideKR { dest, .. }
+        | Instruction::Modulo { dest, .. }
+        | Instruction::ModuloRK { dest, .. }
+        | Instruction::ModuloKR { dest, .. }
+        | Instruction::Equal { dest, .. }
+        | Instruction::EqualK { dest, .. }
+        | Instruction::NotEqual { dest, .. }
+        | Instruction::NotEqualK { dest, .. }
+        | Instruction::Less { dest, .. }
+        | Instruction::LessK { dest, .. }
+        | Instruction::LessEqual { dest, .. }
+        | Instruction::LessEqualK { dest, .. }
+        | Instruction::Greater { dest, .. }
+        | Instruction::GreaterK { dest, .. }
+        | Instruction::GreaterEqual { dest, .. }
+        | Instruction::GreaterEqualK { dest, .. }
+        | Instruction::Not { dest, .. }
+        | Instruction::Negate { dest, .. }
+        | Instruction::MoveArg { dest, .. }
+        | Instruction::Move { dest, .. }
+        | Instruction::LoadK { dest, .. }
+        | Instruction::LoadKWide { dest, .. }
+        | Instruction::CreateDict { dest }
+        | Instruction::CreateVec { dest }
+        | Instruction::GetField { dest, .. }
+        | Instruction::GetIndex { dest, .. }
+        | Instruction::CreateCell { dest, .. }
+        | Instruction::GetCell { dest, .. }
+        | Instruction::Call { dest, .. } => Some(dest),
+        _ => None,
+    }
+}
+
+pub(crate) fn for_each_read(instruction: &Instruction, mut read: impl FnMut(u16)) {
+    match *instruction {
+        Instruction::Add { src1, src2, .. }
+        | Instruction::Subtract { src1, src2, .. }
+        | Instruction::Multiply { src1, src2, .. }
+        | Instruction::Divide { src1, src2, .. }
+        | Instruction::Modulo { src1, src2, .. }
+        | Instruction::Equal { src1, src2, .. }
+        | Instruction::NotEqual { src1, src2, .. }
+        | Instruction::Less { src1, src2, .. }
+        | Instruction::LessEqual { src1, src2, .. }
+        | Instruction::Greater { src1, src2, .. }
+        | Instruction::GreaterEqual { src1, src2, .. }
+        | Instruction::JumpIfLess { src1, src2, .. }
+        | Instruction::JumpIfLessEqual { src1, src2, .. }
+        | Instruction::JumpIfGreater { src1, src2, .. }
+        | Instruction::JumpIfGreaterEqual { src1, src2, .. }
+        | Instruction::JumpIfEqual { src1, src2, .. }
+        | Instruction::JumpIfNotEqual { src1, src2, .. } => {
+            read(src1);
+            read(src2);
+        }
+        Instruction::AddK { src1, .. }
+        | Instruction::SubtractRK { src1, .. }
+        | Instruction::MultiplyK { src1, .. }
+        | Instruction::DivideRK { src1, .. }
+        | Instruction::ModuloRK { src1, .. }
+        | Instruction::EqualK { src1, .. }
+        | Instruction::NotEqualK { src1, .. }
+        | Instruction::LessK { src1, .. }
+        | Instruction::LessEqualK { src1, .. }
+        | Instruction::GreaterK { src1, .. }
+        | Instruction::GreaterEqualK { src1, .. }
+        | Instruction::JumpIfLessK { src1, .. }
+        | Instruction::JumpIfLessEqualK { src1, .. }
+        | Instruction::JumpIfGreaterK { src1, .. }
+        | Instruction::JumpIfGreaterEqualK { src1, .. }
+        | Instruction::JumpIfEqualK { src1, .. }
+        | Instruction::JumpIfNotEqualK { src1, .. } => read(src1),
+        Instruction::SubtractKR { src2, .. }
+        | Instruction::DivideKR { src2, .. }
+        | Instruction::ModuloKR { src2, .. } => read(src2),
+        Instruction::Not { src, .. }
+        | Instruction::Negate { src, .. }
+        | Instruction::Move { src, .. }
+        | Instruction::MoveArg { src, .. }
+        | Instruction::CreateCell { src, .. }
+        | Instruction::GetCell { cell: src, .. }
+        | Instruction::Call { src, .. }
+        | Instruction::TailCall { src, .. }
+        | Instruction::Return { src }
+        | Instruction::JumpIfFalse { src, .. }
+        | Instruction::JumpIfTrue { src, .. } => read(src),
+        Instruction::CaptureValue { dest, src } | Instruction::SetCell { cell: dest, src } => {
+            read(dest);
+            read(src);
+        }
+        Instruction::SetField { object, key, value } => {
+            read(object);
+            read(key);
+            read(value);
+        }
+        Instruction::GetField { object, key, .. } => {
+            read(object);
+            read(key);
+        }
+        Instruction::GetIndex { object, index, .. } => {
+            read(object);
+            read(index);
+        }
+        Instruction::SetIndex {
+            object,
+            index,
+            value,
+        } => {
+            read(object);
+            read(index);
+            read(value);
+        }
+        Instruction::VecPush { vec, src } => {
+            read(vec);
+            read(src);
+        }
+        Instruction::ForPrep { base, .. } | Instruction::ForLoop { base, .. } => {
+            read(base);
+            read(base + 1);
+            read(base + 2);
+        }
+        Instruction::LoadK { .. }
+        | Instruction::LoadKWide { .. }
+        | Instruction::CreateDict { .. }
+        | Instruction::CreateVec { .. }
+        | Instruction::CreateClosure { .. }
+        | Instruction::Jump { .. }
+        | Instruction::Nop => {}
+    }
+}
+
+/// Registers whose value may still be read after each instruction executes.
+/// Registers in `exit` are read after the function returns.
+fn live_registers(instructions: &[Instruction], exit: &RegisterSet) -> Vec<RegisterSet> {
+    let mut empty = exit.clone();
+    empty.clear();
+
+    let mut live_in = vec![empty.clone(); instructions.len()];
+    let mut live_out = vec![empty.clone(); instructions.len()];
+    let mut out = empty;
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+
+        for index in (0..instructions.len()).rev() {
+            out.clear();
+            let (next, target) = successors(instructions, index);
+
+            for successor in next.into_iter().chain(target) {
+                out.union(&live_in[successor]);
+            }
+
+            if let Instruction::Return { .. } = instructions[index] {
+                out.union(exit);
+            }
+
+            let mut instruction = instructions[index];
+            let mut input = out.clone();
+
+            if let Some(dest) = destination(&mut instruction) {
+                input.remove(*dest);
+            }
+
+            for_each_read(&instruction, |register| input.insert(register));
+
+            if input != live_in[index] || out != live_out[index] {
+                live_in[index] = input;
+                live_out[index].clone_from(&out);
+                changed = true;
+            }
+        }
+    }
+
+    live_out
+}
+
 fn eliminate_dead_code(instructions: &mut [Instruction], reachable: &[bool]) {
     for i in 0..instructions.len() {
         if !reachable[i] {
@@ -31,31 +308,35 @@ fn reachable_and_leaders(instructions: &[Instruction]) -> (Vec<bool>, Vec<bool>)
         }
         reachable[index] = true;
 
-        match instructions[index] {
-            Instruction::Jump { offset } => {
-                let target = ((index as i32 + offset) as usize).clamp(0, instructions.len() - 1);
-                leaders[target] = true;
-                stack.push(target);
-            }
-            Instruction::JumpIfFalse { offset, .. } | Instruction::JumpIfTrue { offset, .. } => {
-                let target = ((index as i32 + offset) as usize).clamp(0, instructions.len() - 1);
-                leaders[target] = true;
-                stack.push(target);
-                stack.push(index + 1);
-            }
-            Instruction::Return { .. } => {
-                if index + 1 < instructions.len() {
-                    leaders[index + 1] = true;
-                }
+        let (next, target) = successors(instructions, index);
+
+        if let Some(target) = target {
+            leaders[target] = true;
+            stack.push(target);
+        }
+
+        match (next, instructions[index]) {
+            (
+                Some(next),
+                Instruction::Return { .. }
+                | Instruction::TailCall { .. }
+                | Instruction::Jump { .. },
+            ) => {
+                leaders[next] = true;
             }
-            _ => stack.push(index + 1),
+            (Some(next), _) => stack.push(next),
+            (None, _) => {}
         }
     }
 
     (reachable, leaders)
 }
 
-fn remove_redundant_moves(instructions: &mut [Instruction], leaders: &[bool]) {
+fn remove_redundant_moves(
+    instructions: &mut [Instruction],
+    leaders: &[bool],
+    live: &[RegisterSet],
+) {
     let mut leader = 0;
 
     for index in 0..instructions.len() {
@@ -68,65 +349,74 @@ fn remove_redundant_moves(instructions: &mut [Instruction], leaders: &[bool]) {
             leader = index;
         };
 
+        // The source is only a disposable temporary if nothing reads it afterwards.
+        if live[index].contains(src) {
+            continue;
+        }
+
         let mut i = index;
 
         while i > leader {
             i -= 1;
 
-            match &mut instructions[i] {
-                Instruction::Add { dest, .. }
-                | Instruction::AddK { dest, .. }
-                | Instruction::Subtract { dest, .. }
-                | Instruction::SubtractRK { dest, .. }
-                | Instruction::SubtractKR { dest, .. }
-                | Instruction::Multiply { dest, .. }
-                | Instruction::MultiplyK { dest, .. }
-                | Instruction::Divide { dest, .. }
-                | Instruction::DivideRK { dest, .. }
-                | Instruction::DivideKR { dest, .. }
-                | Instruction::Modulo { dest, .. }
-                | Instruction::ModuloRK { dest, .. }
-                | Instruction::ModuloKR { dest, .. }
-                | Instruction::Equal { dest, .. }
-                | Instruction::EqualK { dest, .. }
-                | Instruction::NotEqual { dest, .. }
-                | Instruction::NotEqualK { dest, .. }
-                | Instruction::Less { dest, .. }
-                | Instruction::LessK { dest, .. }
-                | Instruction::LessEqual { dest, .. }
-                | Instruction::LessEqualK { dest, .. }
-                | Instruction::Greater { dest, .. }
-                | Instruction::GreaterK { dest, .. }
-                | Instruction::GreaterEqual { dest, .. }
-                | Instruction::GreaterEqualK { dest, .. }
-                | Instruction::Not { dest, .. }
-                | Instruction::Negate { dest, .. }
-                | Instruction::MoveArg { dest, .. }
-                | Instruction::Move { dest, .. }
-                | Instruction::LoadK { dest, .. }
-                | Instruction::CreateDict { dest }
-                | Instruction::GetField { dest, .. }
-                | Instruction::Call { dest, .. }
-                    if *dest == src =>
-                {
-                    *dest = move_dest;
-                    instructions[index] = Instruction::Nop;
-
-                    break;
-                }
-                Instruction::Nop => {}
-                _ => {
-                    break;
-                }
+            if let Instruction::Nop = instructions[i] {
+                continue;
+            }
+
+            if let Some(dest) = destination(&mut instructions[i])
+                && *dest == src
+            {
+                *dest = move_dest;
+                instructions[index] = Instruction::Nop;
+                break;
+            }
+
+            if !can_write_before(&instructions[i], src, move_dest) {
+                break;
             }
         }
     }
 }
 
-fn merge_conditional_jumps(instructions: &mut [Instruction]) {
+/// Whether a move from `src` to `dest` right after `instruction` could be
+/// done right before it instead, so that the value of `src` can be computed
+/// straight into `dest` further up.
+fn can_write_before(instruction: &Instruction, src: u16, dest: u16) -> bool {
+    let written = match *instruction {
+        // Calls also read and overwrite the arguments above the frame.
+        Instruction::Call { .. } | Instruction::TailCall { .. } | Instruction::Return { .. } => {
+            return false;
+        }
+        _ if instruction.jump_offset().is_some() => return false,
+        Instruction::CreateClosure { dest, .. } => Some(dest),
+        _ => destination(&mut instruction.clone()).map(|dest| *dest),
+    };
+
+    let mut reads = false;
+    for_each_read(instruction, |register| {
+        reads |= register == src || register == dest
+    });
+
+    !reads && written != Some(src) && written != Some(dest)
+}
+
+fn merge_conditional_jumps(
+    instructions: &mut [Instruction],
+    spans: &mut [Option<Range<usize>>],
+    live: &[RegisterSet],
+) {
     for index in 1..instructions.len() {
         match instructions[index] {
+            Instruction::JumpIfTrue { src, .. } | Instruction::JumpIfFalse { src, .. }
+                if live[index].contains(src) => {}
+
+            // The fused forms only reach targets within an i16 offset.
+            Instruction::JumpIfTrue { offset, .. } | Instruction::JumpIfFalse { offset, .. }
+                if i16::try_from(offset).is_err() => {}
+
             Instruction::JumpIfTrue { src, offset } => {
+                let offset = offset as i16;
+
                 let instruction = match instructions[index - 1] {
                     Instruction::Less { dest, src1, src2 } if dest == src => {
                         Some(Instruction::JumpIfLess { src1, src2, offset })
@@ -183,10 +473,13 @@ fn merge_conditional_jumps(instructions: &mut [Instruction]) {
                     instructions[index - 1] = Instruction::Nop;
 
                     instructions[index] = instruction;
+                    spans[index] = spans[index - 1].take();
                 }
             }
 
             Instruction::JumpIfFalse { src, offset } => {
+                let offset = offset as i16;
+
                 let instruction = match instructions[index - 1] {
                     Instruction::Less { dest, src1, src2 } if dest == src => {
                         Some(Instruction::JumpIfGreaterEqual { src1, src2, offset })
@@ -243,6 +536,7 @@ fn merge_conditional_jumps(instructions: &mut [Instruction]) {
                     instructions[index - 1] = Instruction::Nop;
 
                     instructions[index] = instruction;
+                    spans[index] = spans[index - 1].take();
                 }
             }
 
@@ -251,8 +545,13 @@ fn merge_conditional_jumps(instructions: &mut [Instruction]) {
     }
 }
 
-fn remove_nop(instructions: &mut Vec<Instruction>) {
-    let mut instructions_map = vec![0usize; instructions.len()];
+fn remove_nop(
+    instructions: &mut Vec<Instruction>,
+    spans: &mut Vec<Option<Range<usize>>>,
+    locals: &mut [DebugLocal],
+) {
+    // One past the last instruction maps too, where the ranges of locals end.
+    let mut instructions_map = vec![0usize; instructions.len() + 1];
 
     let mut index = 0;
 
@@ -265,6 +564,12 @@ fn remove_nop(instructions: &mut Vec<Instruction>) {
         }
     }
 
+    instructions_map[instructions.len()] = index;
+
+    for local in locals {
+        local.range = instructions_map[local.range.start]..instructions_map[local.range.end];
+    }
+
     let mut index = 0;
 
     for i in 0..instructions.len() {
@@ -272,7 +577,23 @@ fn remove_nop(instructions: &mut Vec<Instruction>) {
             Instruction::Jump { offset }
             | Instruction::JumpIfFalse { offset, .. }
             | Instruction::JumpIfTrue { offset, .. }
-            | Instruction::JumpIfLess { offset, .. }
+            | Instruction::ForPrep { offset, .. }
+            | Instruction::ForLoop { offset, .. } => {
+                let target = (i as i32 + *offset) as usize;
+
+                let target = instructions_map[target];
+
+                *offset = target as i32 - index as i32;
+
+                instructions[index] = instructions[i];
+                spans.swap(index, i);
+
+                index += 1;
+            }
+
+            // Removing instructions only brings targets closer, so the
+            // offset still fits.
+            Instruction::JumpIfLess { offset, .. }
             | Instruction::JumpIfLessK { offset, .. }
             | Instruction::JumpIfLessEqual { offset, .. }
             | Instruction::JumpIfLessEqualK { offset, .. }
@@ -284,13 +605,14 @@ fn remove_nop(instructions: &mut Vec<Instruction>) {
             | Instruction::JumpIfEqualK { offset, .. }
             | Instruction::JumpIfNotEqual { offset, .. }
             | Instruction::JumpIfNotEqualK { offset, .. } => {
-                let target = (i as i32 + *offset) as usize;
+                let target = (i as i32 + *offset as i32) as usize;
 
                 let target = instructions_map[target];
 
-                *offset = target as i32 - index as i32;
+                *offset = (target as i32 - index as i32) as i16;
 
                 instructions[index] = instructions[i];
+                spans.swap(index, i);
 
                 index += 1;
             }
@@ -299,6 +621,7 @@ fn remove_nop(instructions: &mut Vec<Instruction>) {
 
             _ => {
                 instructions[index] = instructions[i];
+                spans.swap(index, i);
 
                 index += 1;
             }
@@ -306,4 +629,5 @@ fn remove_nop(instructions: &mut Vec<Instruction>) {
     }
 
     instructions.truncate(index);
+    spans.truncate(index);
 }
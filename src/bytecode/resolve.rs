@@ -8,35 +8,62 @@ use crate::{
     program::INTERNER,
     report_error,
     syntax::ast::{Ast, Expr, ExprId},
-    util::string_interner::StringIndex,
+    util::string_interner::{StringIndex, StringInterner},
 };
 
+/// Index of a declared variable in the bindings of a [`Resolution`].
+type BindingId = usize;
+
+/// A declared variable, shared by every identifier that refers to it.
+struct Binding {
+    name: StringIndex,
+    /// Identifier that declared the variable, `None` for the globals of
+    /// earlier scripts.
+    declaration: Option<ExprId>,
+    /// Block the variable is visible in.
+    scope: ExprId,
+    /// Declared at the top level of the script, where it outlives the script.
+    global: bool,
+    /// Declared by a function or native function, which are seldom assigned
+    /// again.
+    function: bool,
+    /// Holds its value. Functions are declared at the start of their block,
+    /// but get their closure where they are written.
+    initialized: bool,
+    captured: bool,
+    assigned: bool,
+}
+
 #[derive(Default)]
 struct Environment {
     parent: Option<Box<Environment>>,
-    scopes: Vec<HashSet<StringIndex>>,
-    captures: Vec<StringIndex>,
+    scopes: Vec<HashMap<StringIndex, BindingId>>,
+    captures: Vec<(StringIndex, BindingId)>,
+    /// Innermost block being resolved, where declarations go.
+    block: Option<ExprId>,
 }
 
 impl Environment {
     pub fn new() -> Self {
         Self {
             parent: None,
-            scopes: vec![HashSet::new()],
+            scopes: vec![HashMap::new()],
             captures: Vec::new(),
+            block: None,
         }
     }
 
     pub fn with_parent(parent: Environment) -> Self {
         Self {
             parent: Some(Box::new(parent)),
-            scopes: vec![HashSet::new()],
+            scopes: vec![HashMap::new()],
             captures: Vec::new(),
+            block: None,
         }
     }
 
     pub fn push_scope(&mut self) {
-        self.scopes.push(HashSet::new());
+        self.scopes.push(HashMap::new());
     }
 
     pub fn pop_scope(&mut self) {
@@ -48,86 +75,291 @@ impl Environment {
         self.scopes.pop();
     }
 
-    pub fn insert(&mut self, name: StringIndex) {
+    pub fn insert(&mut self, name: StringIndex, binding: BindingId) {
         self.scopes
             .last_mut()
             .expect("scopes must never be empty")
-            .insert(name);
+            .insert(name, binding);
     }
 
-    pub fn lookup_local(&mut self, name: StringIndex) -> bool {
+    /// Finds a variable declared in this function or already captured by it.
+    pub fn lookup_in_function(&self, name: StringIndex) -> Option<BindingId> {
         for scope in self.scopes.iter().rev() {
-            if scope.get(&name).is_some() {
-                return true;
+            if let Some(binding) = scope.get(&name) {
+                return Some(*binding);
             }
         }
 
-        for found_name in self.captures.iter().copied() {
-            if found_name == name {
-                return true;
-            }
+        self.captures
+            .iter()
+            .find(|&&(found_name, _)| found_name == name)
+            .map(|&(_, binding)| binding)
+    }
+
+    pub fn lookup_local(&mut self, name: StringIndex) -> Option<BindingId> {
+        if let Some(binding) = self.lookup_in_function(name) {
+            return Some(binding);
         }
 
-        if let Some(parent) = &mut self.parent {
-            self.captures.push(name);
+        let binding = self.parent.as_mut()?.lookup_local(name)?;
+        self.captures.push((name, binding));
+
+        Some(binding)
+    }
+
+    /// The outermost scope of the script, below it are the globals of
+    /// earlier scripts.
+    fn is_top_level(&self) -> bool {
+        self.parent.is_none() && self.scopes.len() <= 2
+    }
+}
+
+#[derive(Default)]
+pub struct Resolution {
+    /// Names captured by every function, keyed by the function expression.
+    pub captures: HashMap<ExprId, Vec<StringIndex>>,
+    /// Declarations of captured variables that can change after the capture.
+    /// They are kept in cells, so that closures and the declaring function
+    /// share every assignment. Global variables can also be assigned by code
+    /// evaluated later, so they are cells whenever they are captured.
+    pub cells: HashSet<ExprId>,
+    /// Globals of earlier scripts captured by this one, which must move into cells.
+    pub global_cells: Vec<StringIndex>,
+    /// Globals declared by function declarations of this script.
+    pub global_functions: Vec<StringIndex>,
+    bindings: Vec<Binding>,
+    /// Variable of every identifier that declares or refers to one.
+    references: HashMap<ExprId, BindingId>,
+    errors: Vec<Error>,
+    /// Errors about undeclared names, whose message is written once the
+    /// interner holding the names is known.
+    undeclared: Vec<(usize, StringIndex)>,
+}
+
+/// A declared variable, as seen by editor tooling.
+#[derive(Clone, Copy, Debug)]
+pub struct Symbol {
+    pub id: usize,
+    pub name: StringIndex,
+    /// Identifier that declared it, `None` for the names declared before the
+    /// program.
+    pub declaration: Option<ExprId>,
+    /// Block the name is visible in.
+    pub scope: ExprId,
+    /// Declared by a function or native function.
+    pub function: bool,
+}
+
+impl Resolution {
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol> + '_ {
+        (0..self.bindings.len()).map(|id| self.symbol_by_id(id))
+    }
+
+    /// The variable `identifier` declares or refers to.
+    pub fn symbol(&self, identifier: ExprId) -> Option<Symbol> {
+        let &id = self.references.get(&identifier)?;
+
+        Some(self.symbol_by_id(id))
+    }
+
+    /// Every identifier that declares or refers to `symbol`.
+    pub fn references(&self, symbol: &Symbol) -> Vec<ExprId> {
+        self.references
+            .iter()
+            .filter(|&(_, &id)| id == symbol.id)
+            .map(|(&identifier, _)| identifier)
+            .collect()
+    }
+
+    fn symbol_by_id(&self, id: BindingId) -> Symbol {
+        let binding = &self.bindings[id];
+
+        Symbol {
+            id,
+            name: binding.name,
+            declaration: binding.declaration,
+            scope: binding.scope,
+            function: binding.function,
+        }
+    }
 
-            parent.lookup_local(name)
-        } else {
-            false
+    fn add_binding(
+        &mut self,
+        environment: &Environment,
+        name: StringIndex,
+        declaration: Option<ExprId>,
+        scope: ExprId,
+    ) -> BindingId {
+        if let Some(declaration) = declaration {
+            self.references.insert(declaration, self.bindings.len());
         }
+
+        self.bindings.push(Binding {
+            name,
+            declaration,
+            scope,
+            global: environment.is_top_level(),
+            function: false,
+            initialized: true,
+            captured: false,
+            assigned: false,
+        });
+
+        self.bindings.len() - 1
     }
 }
 
-pub fn resolve(ast: &Ast) -> Result<HashMap<ExprId, Vec<StringIndex>>, Error> {
+/// Resolves every identifier in the program, collecting all undeclared names
+/// instead of stopping at the first one.
+pub fn resolve(ast: &Ast) -> Result<Resolution, Vec<Error>> {
+    resolve_with_globals(ast, [])
+}
+
+/// Resolves a program that can also refer to `globals` declared by code
+/// evaluated before it, along with whether a function declaration declared them.
+pub fn resolve_with_globals(
+    ast: &Ast,
+    globals: impl IntoIterator<Item = (StringIndex, bool)>,
+) -> Result<Resolution, Vec<Error>> {
+    let (resolution, errors) = resolve_for_tools(ast, globals, &INTERNER.lock().unwrap());
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(resolution)
+}
+
+/// Resolves a program like [`resolve_with_globals`], but keeps the
+/// resolution of a program with undeclared names, for tools that work on
+/// code being edited. The names of `ast` are resolved with `interner`.
+pub fn resolve_for_tools(
+    ast: &Ast,
+    globals: impl IntoIterator<Item = (StringIndex, bool)>,
+    interner: &StringInterner,
+) -> (Resolution, Vec<Error>) {
     let mut environment = Environment::new();
-    let mut captures = HashMap::new();
+    let mut resolution = Resolution::default();
+
+    for (name, function) in globals {
+        let binding = resolution.add_binding(&environment, name, None, ast.entry());
+        resolution.bindings[binding].function = function;
+
+        environment.insert(name, binding);
+    }
+
+    resolve_expression(ast, ast.entry(), &mut environment, &mut resolution);
+
+    for binding in &resolution.bindings {
+        if binding.global && binding.function && binding.declaration.is_some() {
+            resolution.global_functions.push(binding.name);
+        }
+
+        let global_variable = binding.global && !binding.function;
+
+        if !(binding.captured && (binding.assigned || global_variable)) {
+            continue;
+        }
+
+        match binding.declaration {
+            Some(declaration) => {
+                resolution.cells.insert(declaration);
+            }
+            None => resolution.global_cells.push(binding.name),
+        }
+    }
+
+    let mut errors = std::mem::take(&mut resolution.errors);
 
-    resolve_expression(ast, ast.entry(), &mut environment, &mut captures)?;
+    for (index, name) in std::mem::take(&mut resolution.undeclared) {
+        errors[index].message = format!("`{}` is not declared", interner.resolve(name));
+    }
+
+    (resolution, errors)
+}
 
-    Ok(captures)
+/// Declares `name` at `identifier`, `function` tells whether a function
+/// declaration introduces it. Like in the compiler, declaring a name that is
+/// already visible in the same function assigns that variable.
+fn declare(
+    environment: &mut Environment,
+    resolution: &mut Resolution,
+    name: StringIndex,
+    identifier: ExprId,
+    function: bool,
+) {
+    match environment.lookup_in_function(name) {
+        Some(binding) => {
+            resolution.bindings[binding].assigned = true;
+            resolution.references.insert(identifier, binding);
+        }
+        None => {
+            let scope = environment
+                .block
+                .expect("declarations must be inside a block");
+            let binding = resolution.add_binding(environment, name, Some(identifier), scope);
+            resolution.bindings[binding].function = function;
+            resolution.bindings[binding].initialized = !function;
+
+            environment.insert(name, binding);
+        }
+    }
 }
 
 fn resolve_block(
     ast: &Ast,
     expressions: &[ExprId],
     environment: &mut Environment,
-    captures: &mut HashMap<ExprId, Vec<StringIndex>>,
-) -> Result<(), Error> {
+    resolution: &mut Resolution,
+) {
     for expression in expressions.iter().copied() {
         if let Expr::Function {
             name: Some(identifier),
             ..
-        } = *ast.get(expression)
+        } = *ast.get(ast.declaration(expression))
         {
             let Expr::Identifier(name) = *ast.get(identifier) else {
                 unreachable!("function name must be parsed as identifier");
             };
 
-            environment.insert(name);
+            declare(environment, resolution, name, identifier, true);
         }
     }
 
     for &expression in expressions {
-        resolve_expression(ast, expression, environment, captures)?;
+        resolve_expression(ast, expression, environment, resolution);
     }
-
-    Ok(())
 }
 
 fn resolve_expression(
     ast: &Ast,
     expression: ExprId,
     environment: &mut Environment,
-    captures: &mut HashMap<ExprId, Vec<StringIndex>>,
-) -> Result<(), Error> {
+    resolution: &mut Resolution,
+) {
     match *ast.get(expression) {
-        Expr::NativeFunction { .. } => {}
+        Expr::NativeFunction {
+            name: identifier, ..
+        } => {
+            let Expr::Identifier(name) = *ast.get(identifier) else {
+                unreachable!("native function name must be parsed as identifier");
+            };
+
+            declare(environment, resolution, name, identifier, true);
+        }
 
         Expr::Function {
             ref parameters,
             block,
-            ..
+            name,
         } => {
+            if let Some(identifier) = name
+                && let Expr::Identifier(name) = *ast.get(identifier)
+                && let Some(binding) = environment.lookup_in_function(name)
+            {
+                resolution.bindings[binding].initialized = true;
+            }
+
             let parent = std::mem::take(environment);
             let mut inner = Environment::with_parent(parent);
 
@@ -136,90 +368,187 @@ fn resolve_expression(
                     unreachable!("parameter must be parsed as identifier");
                 };
 
-                inner.insert(name);
+                let binding = resolution.add_binding(&inner, name, Some(identifier), block);
+                inner.insert(name, binding);
             }
 
-            resolve_expression(ast, block, &mut inner, captures)?;
+            resolve_expression(ast, block, &mut inner, resolution);
+
+            let mut captures = Vec::new();
+
+            for (name, binding) in inner.captures.iter().copied() {
+                let binding = &mut resolution.bindings[binding];
+                binding.captured = true;
+
+                // A function declared further down the block is captured
+                // before it gets its closure.
+                if !binding.initialized {
+                    binding.assigned = true;
+                }
 
-            captures.insert(expression, inner.captures);
+                captures.push(name);
+            }
+
+            resolution.captures.insert(expression, captures);
 
             *environment = *inner.parent.unwrap();
         }
 
         Expr::DeclareAssign { left, right } => {
-            resolve_expression(ast, right, environment, captures)?;
+            resolve_expression(ast, right, environment, resolution);
 
             let Expr::Identifier(name) = *ast.get(left) else {
                 unreachable!("declare_assign lhs must be parsed as identifier");
             };
 
-            environment.insert(name);
+            declare(environment, resolution, name, left, false);
+        }
+        Expr::Assign { left, right, .. } => {
+            resolve_expression(ast, left, environment, resolution);
+            resolve_expression(ast, right, environment, resolution);
+
+            if let Expr::Identifier(name) = *ast.get(left)
+                && let Some(binding) = environment.lookup_local(name)
+            {
+                resolution.bindings[binding].assigned = true;
+            }
         }
-        Expr::Assign { left, right, .. }
-        | Expr::LogicalAnd { left, right }
+        Expr::LogicalAnd { left, right }
         | Expr::LogicalOr { left, right }
         | Expr::Binary { left, right, .. } => {
-            resolve_expression(ast, left, environment, captures)?;
-            resolve_expression(ast, right, environment, captures)?;
+            resolve_expression(ast, left, environment, resolution);
+            resolve_expression(ast, right, environment, resolution);
         }
         Expr::LogicalNot(expr) => {
-            resolve_expression(ast, expr, environment, captures)?;
+            resolve_expression(ast, expr, environment, resolution);
         }
         Expr::Return(expr) => {
             if let Some(expr) = expr {
-                resolve_expression(ast, expr, environment, captures)?;
+                resolve_expression(ast, expr, environment, resolution);
             }
         }
         Expr::Unary { right, .. } => {
-            resolve_expression(ast, right, environment, captures)?;
+            resolve_expression(ast, right, environment, resolution);
         }
         Expr::FunctionCall {
             callee,
             ref arguments,
         } => {
-            resolve_expression(ast, callee, environment, captures)?;
+            resolve_expression(ast, callee, environment, resolution);
 
             for argument in arguments.iter().copied() {
-                resolve_expression(ast, argument, environment, captures)?;
+                resolve_expression(ast, argument, environment, resolution);
             }
         }
         Expr::MemberAccess { object, .. } => {
-            resolve_expression(ast, object, environment, captures)?;
+            resolve_expression(ast, object, environment, resolution);
+        }
+        Expr::Index { object, index } => {
+            resolve_expression(ast, object, environment, resolution);
+            resolve_expression(ast, index, environment, resolution);
         }
         Expr::Block(ref expressions) => {
+            let outer = environment.block.replace(expression);
+
             environment.push_scope();
-            resolve_block(ast, expressions, environment, captures)?;
+            resolve_block(ast, expressions, environment, resolution);
             environment.pop_scope();
+
+            environment.block = outer;
         }
         Expr::If {
             condition,
             then_branch,
             else_branch,
         } => {
-            resolve_expression(ast, condition, environment, captures)?;
-            resolve_expression(ast, then_branch, environment, captures)?;
+            resolve_expression(ast, condition, environment, resolution);
+            resolve_expression(ast, then_branch, environment, resolution);
 
             if let Some(else_branch) = else_branch {
-                resolve_expression(ast, else_branch, environment, captures)?;
+                resolve_expression(ast, else_branch, environment, resolution);
             }
         }
-        Expr::ForLoop { .. } => {
-            todo!("ForLoop")
+        Expr::ForLoop {
+            variable,
+            start,
+            end,
+            step,
+            block,
+            ..
+        } => {
+            resolve_expression(ast, start, environment, resolution);
+            resolve_expression(ast, end, environment, resolution);
+
+            if let Some(step) = step {
+                resolve_expression(ast, step, environment, resolution);
+            }
+
+            let Expr::Identifier(name) = *ast.get(variable) else {
+                unreachable!("for loop variable must be parsed as identifier");
+            };
+
+            environment.push_scope();
+            let binding = resolution.add_binding(environment, name, Some(variable), block);
+            environment.insert(name, binding);
+            resolve_expression(ast, block, environment, resolution);
+            environment.pop_scope();
         }
         Expr::WhileLoop { condition, block } => {
-            resolve_expression(ast, condition, environment, captures)?;
-            resolve_expression(ast, block, environment, captures)?;
+            resolve_expression(ast, condition, environment, resolution);
+            resolve_expression(ast, block, environment, resolution);
+        }
+        Expr::Import(_) => {
+            // The loader declares the imported names before resolution.
+            if !environment.is_top_level() {
+                let span = ast.span(expression).unwrap().clone();
+                resolution.errors.push(report_error!(
+                    span,
+                    "`import` is only allowed at the top level"
+                ));
+            }
+        }
+        Expr::Export(declaration) => {
+            if !environment.is_top_level() {
+                let span = ast.span(expression).unwrap().clone();
+                resolution.errors.push(report_error!(
+                    span,
+                    "`export` is only allowed at the top level"
+                ));
+            }
+
+            resolve_expression(ast, declaration, environment, resolution);
         }
         Expr::Break | Expr::Continue => {}
         Expr::Identifier(name) => {
-            if !environment.lookup_local(name) {
-                let slice = INTERNER.lock().unwrap().resolve(name);
+            if let Some(binding) = environment.lookup_local(name) {
+                resolution.references.insert(expression, binding);
+            } else {
                 let span = ast.span(expression).unwrap().clone();
-                return Err(report_error!(span, "`{}` is not declared", slice));
+                let index = resolution.errors.len();
+
+                resolution.undeclared.push((index, name));
+                resolution
+                    .errors
+                    .push(report_error!(span, "undeclared name"));
             };
         }
-        Expr::StringLiteral(_) | Expr::NumberLiteral(_) | Expr::DictLiteral { .. } => {}
-    };
+        Expr::DictLiteral { ref fields } => {
+            for (key, value) in fields.iter().copied() {
+                resolve_expression(ast, key, environment, resolution);
 
-    Ok(())
+                if let Some(value) = value {
+                    resolve_expression(ast, value, environment, resolution);
+                }
+            }
+        }
+        Expr::VecLiteral { ref elements } => {
+            for element in elements.iter().copied() {
+                resolve_expression(ast, element, environment, resolution);
+            }
+        }
+        Expr::StringLiteral(_)
+        | Expr::NumberLiteral(_)
+        | Expr::BooleanLiteral(_)
+        | Expr::NilLiteral => {}
+    };
 }
@@ -0,0 +1,1130 @@
+use foldhash::HashMap;
+
+use crate::{
+    bytecode::{
+        Function,
+        instruction::Instruction,
+        optimize_bytecode::{destination, for_each_read, successors},
+    },
+    diagnostics::error::Error,
+    program::INTERNER,
+    report_error,
+    runtime::value::Value,
+    std::native_functions::NativeRegistry,
+    util::string_interner::StringIndex,
+};
+
+/// First bytes of every compiled file.
+pub const MAGIC: &[u8; 4] = b"KRC\0";
+
+/// Bumped whenever the layout of the file or the instruction set changes.
+pub const FORMAT_VERSION: u16 = 5;
+
+const HEADER_SIZE: usize = MAGIC.len() + size_of::<u16>() + size_of::<u64>();
+
+const CONSTANT_NUMBER: u8 = 0;
+const CONSTANT_STRING: u8 = 1;
+const CONSTANT_BOOL: u8 = 2;
+const CONSTANT_NIL: u8 = 3;
+const CONSTANT_NATIVE: u8 = 4;
+
+/// Little endian encoding of every instruction field type.
+trait Operand: Sized {
+    fn write(self, writer: &mut Writer);
+    fn read(reader: &mut Reader) -> Result<Self, Error>;
+}
+
+macro_rules! impl_operand {
+    ($($ty:ty),*) => {
+        $(
+            impl Operand for $ty {
+                fn write(self, writer: &mut Writer) {
+                    writer.bytes.extend_from_slice(&self.to_le_bytes());
+                }
+
+                fn read(reader: &mut Reader) -> Result<Self, Error> {
+                    let bytes = reader.take(size_of::<$ty>())?;
+
+                    Ok(<$ty>::from_le_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+impl_operand!(u8, u16, u32, i16, i32, u64);
+
+impl Operand for bool {
+    fn write(self, writer: &mut Writer) {
+        writer.write(self as u8);
+    }
+
+    fn read(reader: &mut Reader) -> Result<Self, Error> {
+        match u8::read(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(report_error!("invalid bytecode, {} is not a boolean", byte)),
+        }
+    }
+}
+
+/// Instructions are written as their position in this list followed by their
+/// fields, so the encoding doesn't depend on the layout of [`Instruction`].
+macro_rules! instruction_codec {
+    ($($variant:ident { $($field:ident),* }),* $(,)?) => {
+        fn write_instruction(writer: &mut Writer, instruction: Instruction) {
+            let mut opcode: u8 = 0;
+
+            $(
+                if let Instruction::$variant { $($field),* } = instruction {
+                    writer.write(opcode);
+                    $(writer.write($field);)*
+                    return;
+                }
+
+                opcode += 1;
+            )*
+
+            unreachable!("{:?} is missing from the instruction codec", instruction);
+        }
+
+        fn read_instruction(reader: &mut Reader) -> Result<Instruction, Error> {
+            let opcode: u8 = reader.read()?;
+            let mut expected: u8 = 0;
+
+            $(
+                if opcode == expected {
+                    return Ok(Instruction::$variant { $($field: reader.read()?),* });
+                }
+
+                expected += 1;
+            )*
+
+            let _ = expected;
+
+            Err(report_error!("invalid bytecode, unknown opcode {}", opcode))
+        }
+    };
+}
+
+instruction_codec! {
+    Add { dest, src1, src2 },
+    AddK { dest, src1, src2 },
+    Subtract { dest, src1, src2 },
+    SubtractRK { dest, src1, src2 },
+    SubtractKR { dest, src1, src2 },
+    Multiply { dest, src1, src2 },
+    MultiplyK { dest, src1, src2 },
+    Divide { dest, src1, src2 },
+    DivideRK { dest, src1, src2 },
+    DivideKR { dest, src1, src2 },
+    Modulo { dest, src1, src2 },
+    ModuloRK { dest, src1, src2 },
+    ModuloKR { dest, src1, src2 },
+    Equal { dest, src1, src2 },
+    EqualK { dest, src1, src2 },
+    NotEqual { dest, src1, src2 },
+    NotEqualK { dest, src1, src2 },
+    Less { dest, src1, src2 },
+    LessK { dest, src1, src2 },
+    LessEqual { dest, src1, src2 },
+    LessEqualK { dest, src1, src2 },
+    Greater { dest, src1, src2 },
+    GreaterK { dest, src1, src2 },
+    GreaterEqual { dest, src1, src2 },
+    GreaterEqualK { dest, src1, src2 },
+    Not { dest, src },
+    Negate { dest, src },
+    Move { dest, src },
+    MoveArg { dest, src },
+    LoadK { dest, src },
+    LoadKWide { dest, src },
+    CreateDict { dest },
+    SetField { object, key, value },
+    GetField { dest, object, key },
+    GetIndex { dest, object, index },
+    SetIndex { object, index, value },
+    CreateVec { dest },
+    VecPush { vec, src },
+    CreateClosure { dest, src },
+    CaptureValue { dest, src },
+    CreateCell { dest, src },
+    GetCell { dest, cell },
+    SetCell { cell, src },
+    Call { dest, src, arity },
+    TailCall { src, arity },
+    Return { src },
+    Jump { offset },
+    JumpIfFalse { src, offset },
+    JumpIfTrue { src, offset },
+    JumpIfLess { src1, src2, offset },
+    JumpIfLessK { src1, src2, offset },
+    JumpIfLessEqual { src1, src2, offset },
+    JumpIfLessEqualK { src1, src2, offset },
+    JumpIfGreater { src1, src2, offset },
+    JumpIfGreaterK { src1, src2, offset },
+    JumpIfGreaterEqual { src1, src2, offset },
+    JumpIfGreaterEqualK { src1, src2, offset },
+    JumpIfEqual { src1, src2, offset },
+    JumpIfEqualK { src1, src2, offset },
+    JumpIfNotEqual { src1, src2, offset },
+    JumpIfNotEqualK { src1, src2, offset },
+    ForPrep { descending, base, offset },
+    ForLoop { base, offset },
+    Nop {},
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+    strings: Vec<StringIndex>,
+    string_table: HashMap<StringIndex, u32>,
+}
+
+impl Writer {
+    fn write(&mut self, operand: impl Operand) {
+        operand.write(self);
+    }
+
+    fn write_len(&mut self, len: usize) {
+        self.write(len as u32);
+    }
+
+    /// Interned strings are written as indices into the string table.
+    fn write_string(&mut self, index: StringIndex) {
+        let next = self.strings.len() as u32;
+        let entry = *self.string_table.entry(index).or_insert(next);
+
+        if entry == next {
+            self.strings.push(index);
+        }
+
+        self.write(entry);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    strings: Vec<StringIndex>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let end = self.position.saturating_add(len);
+
+        let Some(bytes) = self.bytes.get(self.position..end) else {
+            return Err(report_error!("invalid bytecode, the file is truncated"));
+        };
+
+        self.position = end;
+
+        Ok(bytes)
+    }
+
+    fn read<T: Operand>(&mut self) -> Result<T, Error> {
+        T::read(self)
+    }
+
+    fn read_len(&mut self) -> Result<usize, Error> {
+        Ok(self.read::<u32>()? as usize)
+    }
+
+    fn read_string(&mut self) -> Result<StringIndex, Error> {
+        let index = self.read_len()?;
+
+        match self.strings.get(index) {
+            Some(&string) => Ok(string),
+            None => Err(report_error!("invalid bytecode, unknown string {}", index)),
+        }
+    }
+}
+
+/// Encodes compiled functions and their constant pool. Natives are stored by
+/// name and looked up again when the file is loaded.
+pub fn serialize_bytecode(
+    functions: &[Function],
+    constants: &[Value],
+    natives: &NativeRegistry,
+) -> Vec<u8> {
+    let mut writer = Writer::default();
+
+    writer.write_len(constants.len());
+
+    for &constant in constants {
+        if constant.is_number() {
+            writer.write(CONSTANT_NUMBER);
+            writer.write(constant.as_number().to_bits());
+        } else if constant.is_string() {
+            writer.write(CONSTANT_STRING);
+            writer.write_string(StringIndex(constant.as_index() as u32));
+        } else if constant.is_bool() {
+            writer.write(CONSTANT_BOOL);
+            writer.write(constant.as_bool() as u8);
+        } else if constant.is_nil() {
+            writer.write(CONSTANT_NIL);
+        } else if constant.is_native() {
+            writer.write(CONSTANT_NATIVE);
+            writer.write_string(natives.functions()[constant.as_index()].name);
+        } else {
+            unreachable!("constants are numbers, strings, booleans, nil or natives");
+        }
+    }
+
+    writer.write_len(functions.len());
+
+    for function in functions {
+        match function.name {
+            Some(name) => {
+                writer.write(1u8);
+                writer.write_string(name);
+            }
+            None => writer.write(0u8),
+        }
+
+        writer.write(function.is_script as u8);
+        writer.write(function.arity);
+        writer.write(function.registers_count);
+        writer.write_len(function.instructions.len());
+
+        for &instruction in &function.instructions {
+            write_instruction(&mut writer, instruction);
+        }
+    }
+
+    let mut payload = Vec::new();
+    let interner = INTERNER.lock().unwrap();
+
+    payload.extend_from_slice(&(writer.strings.len() as u32).to_le_bytes());
+
+    for &index in &writer.strings {
+        let string = interner.resolve(index);
+
+        payload.extend_from_slice(&(string.len() as u32).to_le_bytes());
+        payload.extend_from_slice(string.as_bytes());
+    }
+
+    payload.extend_from_slice(&writer.bytes);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&checksum(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+
+    bytes
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Decodes a file written by [`serialize_bytecode`]. Source spans are not
+/// stored, so runtime errors of loaded bytecode only carry the stack trace.
+pub fn deserialize_bytecode(
+    bytes: &[u8],
+    natives: &NativeRegistry,
+) -> Result<(Vec<Function>, Vec<Value>), Error> {
+    if !is_bytecode(bytes) {
+        return Err(report_error!("not a compiled kaori file"));
+    }
+
+    if bytes.len() < HEADER_SIZE {
+        return Err(report_error!("invalid bytecode, the file is truncated"));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+
+    if version != FORMAT_VERSION {
+        return Err(report_error!(
+            "bytecode format version {} is not supported, expected version {}, recompile the source file",
+            version,
+            FORMAT_VERSION
+        ));
+    }
+
+    let expected = u64::from_le_bytes(bytes[6..HEADER_SIZE].try_into().unwrap());
+    let payload = &bytes[HEADER_SIZE..];
+
+    if checksum(payload) != expected {
+        return Err(report_error!(
+            "bytecode checksum mismatch, the file is corrupted"
+        ));
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        position: 0,
+        strings: Vec::new(),
+    };
+
+    let strings_count = reader.read_len()?;
+
+    for _ in 0..strings_count {
+        let len = reader.read_len()?;
+        let Ok(string) = std::str::from_utf8(reader.take(len)?) else {
+            return Err(report_error!(
+                "invalid bytecode, a string is not valid UTF-8"
+            ));
+        };
+
+        let index = INTERNER.lock().unwrap().get_or_intern(string);
+        reader.strings.push(index);
+    }
+
+    let constants_count = reader.read_len()?;
+    let mut constants = Vec::new();
+
+    for _ in 0..constants_count {
+        let constant = match reader.read::<u8>()? {
+            CONSTANT_NUMBER => {
+                let number = Value::number(f64::from_bits(reader.read()?));
+
+                // Some NaNs are how the other values are boxed, and would
+                // forge references to objects.
+                if !number.is_number() {
+                    return Err(report_error!(
+                        "invalid bytecode, constant {} is not a number",
+                        constants.len()
+                    ));
+                }
+
+                number
+            }
+            CONSTANT_STRING => Value::string(reader.read_string()?),
+            CONSTANT_BOOL => Value::boolean(reader.read::<u8>()? != 0),
+            CONSTANT_NIL => Value::nil(),
+            CONSTANT_NATIVE => {
+                let name = reader.read_string()?;
+
+                let Some((index, _)) = natives.lookup(name) else {
+                    let name = INTERNER.lock().unwrap().resolve(name).to_owned();
+
+                    return Err(report_error!(
+                        "native function `{}` used by the bytecode is not registered",
+                        name
+                    ));
+                };
+
+                Value::native(index)
+            }
+            tag => return Err(report_error!("invalid bytecode, unknown constant {}", tag)),
+        };
+
+        constants.push(constant);
+    }
+
+    let functions_count = reader.read_len()?;
+    let mut functions = Vec::new();
+
+    for _ in 0..functions_count {
+        let name = match reader.read::<u8>()? {
+            0 => None,
+            _ => Some(reader.read_string()?),
+        };
+
+        let is_script = reader.read::<u8>()? != 0;
+        let arity = reader.read()?;
+        let registers_count = reader.read()?;
+
+        let instructions_count = reader.read_len()?;
+        let mut instructions = Vec::new();
+
+        for _ in 0..instructions_count {
+            instructions.push(read_instruction(&mut reader)?);
+        }
+
+        functions.push(Function {
+            spans: vec![None; instructions.len()],
+            instructions,
+            name,
+            is_script,
+            registers_count,
+            arity,
+            locals: Vec::new(),
+        });
+    }
+
+    if reader.position != payload.len() {
+        return Err(report_error!(
+            "invalid bytecode, unexpected data at the end"
+        ));
+    }
+
+    validate(&functions, constants.len())?;
+
+    Ok((functions, constants))
+}
+
+/// The dispatch loop trusts the bytecode, so every constant, function, jump
+/// target, register and operand kind is checked before it can run.
+fn validate(functions: &[Function], constants_count: usize) -> Result<(), Error> {
+    if functions.is_empty() {
+        return Err(report_error!(
+            "invalid bytecode, there is no entry function"
+        ));
+    }
+
+    for function in functions {
+        let instructions = &function.instructions;
+
+        let ends_with_exit = matches!(
+            instructions.last(),
+            Some(
+                Instruction::Return { .. }
+                    | Instruction::TailCall { .. }
+                    | Instruction::Jump { .. }
+            )
+        );
+
+        if !ends_with_exit {
+            return Err(report_error!(
+                "invalid bytecode, a function doesn't end with a return"
+            ));
+        }
+
+        for (ip, instruction) in instructions.iter().enumerate() {
+            if let Some(constant) = instruction.constant()
+                && constant as usize >= constants_count
+            {
+                return Err(report_error!(
+                    "invalid bytecode, unknown constant {}",
+                    constant
+                ));
+            }
+
+            if let Instruction::CreateClosure { src, .. } = *instruction
+                && src as usize >= functions.len()
+            {
+                return Err(report_error!("invalid bytecode, unknown function {}", src));
+            }
+
+            if let Some(offset) = instruction.jump_offset() {
+                let target = ip as i64 + offset as i64;
+
+                if target < 0 || target >= instructions.len() as i64 {
+                    return Err(report_error!("invalid bytecode, jump out of the function"));
+                }
+            }
+
+            // A frame only reads its own registers, but it writes the
+            // arguments of the calls it makes right above them.
+            let frame_end = function.registers_count as usize;
+            let window_end = frame_end + u8::MAX as usize;
+
+            let mut read_end = 0;
+            for_each_read(instruction, |register| {
+                read_end = read_end.max(register as usize + 1)
+            });
+
+            let written = match *instruction {
+                Instruction::CreateClosure { dest, .. } => Some(dest as usize),
+                Instruction::ForPrep { base, .. } | Instruction::ForLoop { base, .. } => {
+                    // The loop variable is kept right after the three values read.
+                    read_end = base as usize + 4;
+                    None
+                }
+                _ => destination(&mut instruction.clone()).map(|dest| *dest as usize),
+            };
+
+            if read_end > frame_end {
+                return Err(report_error!(
+                    "invalid bytecode, register {} is read outside of the frame",
+                    read_end - 1
+                ));
+            }
+
+            if let Some(written) = written
+                && written >= window_end
+            {
+                return Err(report_error!(
+                    "invalid bytecode, register {} is out of the frame",
+                    written
+                ));
+            }
+        }
+    }
+
+    KindCheck::new(functions.len()).check(functions)
+}
+
+/// What a register is known to hold before an instruction runs.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// No path seen so far reaches the register with a value.
+    Pending,
+    /// Not written by the running frame on some path, so it may still hold a
+    /// value left by an earlier one, which the collector may have freed.
+    Unset,
+    Unknown,
+    Cell,
+    /// A closure of `function` created by the running frame, which can still
+    /// capture values while it can't have been called.
+    Closure {
+        function: u32,
+        captured: u16,
+    },
+}
+
+/// Checks that every register is written before it's read, and that every
+/// register `CaptureValue` reads as a closure and `GetCell` and `SetCell` read
+/// as a cell holds one on every path, since their handlers don't look.
+/// Captured values are followed into the registers a closure starts with, so
+/// the check runs over every function until nothing changes.
+struct KindCheck {
+    /// Kinds of the values captured by the closures of each function.
+    captured: Vec<Vec<Kind>>,
+    /// Fewest values a closure of each function had captured when it could
+    /// first be called, `None` while none can be.
+    callable: Vec<Option<u16>>,
+    /// Registers of the outermost frame written when the last script checked
+    /// returns, which the next script starts with. Scripts run in order.
+    script_exit: Option<Vec<Kind>>,
+    changed: bool,
+}
+
+impl KindCheck {
+    fn new(functions_count: usize) -> Self {
+        Self {
+            captured: vec![Vec::new(); functions_count],
+            callable: vec![None; functions_count],
+            script_exit: None,
+            changed: true,
+        }
+    }
+
+    fn check(mut self, functions: &[Function]) -> Result<(), Error> {
+        while self.changed {
+            self.changed = false;
+            self.script_exit = None;
+
+            for index in 0..functions.len() {
+                self.check_function(functions, index)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_function(&mut self, functions: &[Function], index: usize) -> Result<(), Error> {
+        let function = &functions[index];
+        let instructions = &function.instructions;
+
+        let mut states: Vec<Option<Vec<Kind>>> = vec![None; instructions.len()];
+        states[0] = Some(self.entry(function, index));
+
+        let mut exit: Option<Vec<Kind>> = None;
+        let mut pending = vec![0];
+
+        while let Some(ip) = pending.pop() {
+            let mut kinds = states[ip].clone().unwrap();
+
+            self.step(&instructions[ip], &mut kinds, function.registers_count)?;
+
+            match instructions[ip] {
+                Instruction::Return { .. } => match &mut exit {
+                    Some(exit) => {
+                        for (old, &new) in exit.iter_mut().zip(&kinds) {
+                            *old = self.join(*old, new);
+                        }
+                    }
+                    None => exit = Some(kinds.clone()),
+                },
+                // The callee runs in the frame and leaves its own values there.
+                Instruction::TailCall { .. } => exit = Some(vec![Kind::Unset; kinds.len()]),
+                _ => {}
+            }
+
+            let (next, target) = successors(instructions, ip);
+
+            for successor in next.into_iter().chain(target) {
+                let Some(state) = &mut states[successor] else {
+                    states[successor] = Some(kinds.clone());
+                    pending.push(successor);
+                    continue;
+                };
+
+                let mut changed = false;
+
+                for (old, &new) in state.iter_mut().zip(&kinds) {
+                    let joined = self.join(*old, new);
+
+                    if joined != *old {
+                        *old = joined;
+                        changed = true;
+                    }
+                }
+
+                if changed {
+                    pending.push(successor);
+                }
+            }
+        }
+
+        if function.is_script {
+            // Calls made by the script leave their values above its frame.
+            let frame_end = function.registers_count as usize;
+            let mut exit = exit.unwrap_or_default();
+
+            for (register, kind) in exit.iter_mut().enumerate() {
+                if register >= frame_end {
+                    *kind = Kind::Unset;
+                } else if let Kind::Closure { .. } = kind {
+                    *kind = Kind::Unknown;
+                }
+            }
+
+            self.script_exit = Some(exit);
+        }
+
+        Ok(())
+    }
+
+    /// Kinds of the registers when `function` starts. Captured values are
+    /// stored right after the parameters. The first script starts on fresh
+    /// registers, and every other one where the script before it left off.
+    fn entry(&self, function: &Function, index: usize) -> Vec<Kind> {
+        let len = function.registers_count as usize + u8::MAX as usize;
+
+        if function.is_script {
+            let Some(exit) = &self.script_exit else {
+                return vec![Kind::Unknown; len];
+            };
+
+            let mut kinds = vec![Kind::Unset; len];
+            let kept = len.min(exit.len());
+            kinds[..kept].copy_from_slice(&exit[..kept]);
+
+            return kinds;
+        }
+
+        let mut kinds = vec![Kind::Unset; len];
+        kinds[..function.arity as usize].fill(Kind::Unknown);
+
+        for (position, &kind) in self.captured[index].iter().enumerate() {
+            let kind = match self.callable[index] {
+                Some(captured) if position >= captured as usize => Kind::Unset,
+                _ => kind,
+            };
+
+            if let Some(register) = kinds.get_mut(function.arity as usize + position) {
+                *register = kind;
+            }
+        }
+
+        kinds
+    }
+
+    fn step(
+        &mut self,
+        instruction: &Instruction,
+        kinds: &mut [Kind],
+        registers_count: u16,
+    ) -> Result<(), Error> {
+        let frame_end = registers_count as usize;
+
+        let mut unset = None;
+
+        for_each_read(instruction, |register| {
+            if kinds[register as usize] == Kind::Unset {
+                unset = Some(register as usize);
+            }
+        });
+
+        // A call also reads its arguments, right above the frame.
+        if let Instruction::Call { arity, .. } | Instruction::TailCall { arity, .. } = *instruction
+        {
+            let mut arguments = frame_end..frame_end + arity as usize;
+
+            unset = unset.or(arguments.find(|&register| kinds[register] == Kind::Unset));
+        }
+
+        if let Some(register) = unset {
+            return Err(report_error!(
+                "invalid bytecode, register {} is read before it's written",
+                register
+            ));
+        }
+
+        match *instruction {
+            Instruction::CaptureValue { dest, src } => {
+                let Kind::Closure { function, captured } = kinds[dest as usize] else {
+                    if kinds[dest as usize] == Kind::Pending {
+                        return Ok(());
+                    }
+
+                    return Err(report_error!(
+                        "invalid bytecode, register {} isn't a new closure to capture into",
+                        dest
+                    ));
+                };
+
+                // A closure capturing itself can only be called through the
+                // captured value once it can be called some other way.
+                let value = if kinds[src as usize] == kinds[dest as usize] {
+                    Kind::Unknown
+                } else {
+                    self.capture(kinds[src as usize])
+                };
+                let slots = &mut self.captured[function as usize];
+
+                if slots.len() <= captured as usize {
+                    slots.resize(captured as usize + 1, Kind::Pending);
+                }
+
+                let slot = slots[captured as usize];
+                let joined = self.join(slot, value);
+
+                if joined != slot {
+                    self.captured[function as usize][captured as usize] = joined;
+                    self.changed = true;
+                }
+
+                let Some(captured) = captured.checked_add(1) else {
+                    return Err(report_error!("invalid bytecode, too many captured values"));
+                };
+
+                kinds[dest as usize] = Kind::Closure { function, captured };
+
+                return Ok(());
+            }
+            Instruction::GetCell { cell, .. } | Instruction::SetCell { cell, .. } => {
+                if !matches!(kinds[cell as usize], Kind::Cell | Kind::Pending) {
+                    return Err(report_error!(
+                        "invalid bytecode, register {} isn't known to hold a cell",
+                        cell
+                    ));
+                }
+            }
+            _ => {}
+        }
+
+        // A closure read by anything else may be called from then on.
+        for_each_read(instruction, |register| {
+            self.escape(kinds[register as usize])
+        });
+
+        // Registers of the outermost frame outlive it, and the host can call
+        // the closures left in them.
+        if let Instruction::Return { .. } | Instruction::TailCall { .. } = *instruction {
+            for &kind in kinds.iter() {
+                self.escape(kind);
+            }
+        }
+
+        let (dest, kind) = match *instruction {
+            Instruction::CreateClosure { dest, src } if (dest as usize) < frame_end => (
+                dest,
+                Kind::Closure {
+                    function: src,
+                    captured: 0,
+                },
+            ),
+            Instruction::CreateClosure { dest, src } => {
+                // Arguments are read by the call without being listed.
+                self.escape(Kind::Closure {
+                    function: src,
+                    captured: 0,
+                });
+
+                (dest, Kind::Unknown)
+            }
+            Instruction::CreateCell { dest, .. } => (dest, Kind::Cell),
+            Instruction::Move { dest, src } | Instruction::MoveArg { dest, src }
+                if kinds[src as usize] == Kind::Cell =>
+            {
+                (dest, Kind::Cell)
+            }
+            Instruction::ForPrep { base, .. } => (base + 3, Kind::Unknown),
+            Instruction::ForLoop { base, .. } => {
+                kinds[base as usize] = Kind::Unknown;
+
+                (base + 3, Kind::Unknown)
+            }
+            _ => match destination(&mut instruction.clone()) {
+                Some(&mut dest) => (dest, Kind::Unknown),
+                None => return Ok(()),
+            },
+        };
+
+        // The callee's frame starts right above the caller's registers, and
+        // the values it leaves there are no longer kept alive.
+        if let Instruction::Call { .. } = *instruction {
+            kinds[frame_end..].fill(Kind::Unset);
+        }
+
+        kinds[dest as usize] = kind;
+
+        Ok(())
+    }
+
+    /// The kind a closure starts with for a captured value of `kind`.
+    fn capture(&mut self, kind: Kind) -> Kind {
+        self.escape(kind);
+
+        match kind {
+            Kind::Closure { .. } => Kind::Unknown,
+            _ => kind,
+        }
+    }
+
+    /// Records that a closure of kind `kind` may be called from now on.
+    fn escape(&mut self, kind: Kind) {
+        let Kind::Closure { function, captured } = kind else {
+            return;
+        };
+
+        let callable = &mut self.callable[function as usize];
+
+        if callable.is_none_or(|callable| captured < callable) {
+            *callable = Some(captured);
+            self.changed = true;
+        }
+    }
+
+    fn join(&mut self, a: Kind, b: Kind) -> Kind {
+        match (a, b) {
+            (Kind::Pending, kind) | (kind, Kind::Pending) => kind,
+            _ if a == b => a,
+            (Kind::Unset, kind) | (kind, Kind::Unset) => {
+                self.escape(kind);
+
+                Kind::Unset
+            }
+            _ => {
+                // Whatever closure the register holds is no longer tracked.
+                self.escape(a);
+                self.escape(b);
+
+                Kind::Unknown
+            }
+        }
+    }
+}
+
+/// 64 bit FNV-1a.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diagnostics::source_map::SourceMap, program::compile_source_code};
+
+    const PROGRAM: &str = "
+        native fn print(value);
+        fn add(a, b) { return a + b; }
+        x := add(20, 22);
+        print(x);
+        nil;
+    ";
+
+    fn compile(source: &str) -> (Vec<Function>, Vec<Value>) {
+        let natives = NativeRegistry::default();
+        let mut sources = SourceMap::default();
+
+        let (constants, functions) = compile_source_code(source, None, &natives, &mut sources)
+            .unwrap_or_else(|_| panic!("`{}` must compile", source));
+
+        (functions, constants)
+    }
+
+    fn load(functions: &[Function], constants: &[Value]) -> Result<(), String> {
+        let natives = NativeRegistry::default();
+        let bytes = serialize_bytecode(functions, constants, &natives);
+
+        deserialize_bytecode(&bytes, &natives)
+            .map(|_| ())
+            .map_err(|error| error.message)
+    }
+
+    fn assert_rejected(functions: &[Function], constants: &[Value], message: &str) {
+        match load(functions, constants) {
+            Ok(()) => panic!("the bytecode must be rejected with `{}`", message),
+            Err(error) => assert!(error.contains(message), "unexpected error `{}`", error),
+        }
+    }
+
+    /// Rewrites the checksum of `bytes`, so the change they carry reaches the verifier.
+    fn seal(bytes: &mut [u8]) {
+        let payload_checksum = checksum(&bytes[HEADER_SIZE..]).to_le_bytes();
+        bytes[HEADER_SIZE - payload_checksum.len()..HEADER_SIZE].copy_from_slice(&payload_checksum);
+    }
+
+    fn load_bytes(bytes: &[u8]) -> String {
+        match deserialize_bytecode(bytes, &NativeRegistry::default()) {
+            Ok(_) => panic!("the bytecode must be rejected"),
+            Err(error) => error.message,
+        }
+    }
+
+    #[test]
+    fn compiled_program_loads() {
+        let (functions, constants) = compile(PROGRAM);
+
+        assert_eq!(load(&functions, &constants), Ok(()));
+    }
+
+    #[test]
+    fn call_above_the_frame_is_rejected() {
+        let (mut functions, constants) = compile(PROGRAM);
+        let script = &mut functions[0];
+        let above = script.registers_count + 200;
+
+        let call = script
+            .instructions
+            .iter_mut()
+            .find_map(|instruction| match instruction {
+                Instruction::Call { src, .. } => Some(src),
+                _ => None,
+            })
+            .unwrap();
+
+        *call = above;
+
+        assert_rejected(&functions, &constants, "is read outside of the frame");
+    }
+
+    #[test]
+    fn unwritten_register_is_rejected() {
+        let (mut functions, constants) = compile(PROGRAM);
+        let add = &mut functions[1];
+
+        add.instructions = vec![Instruction::Return {
+            src: add.arity as u16,
+        }];
+
+        assert_rejected(&functions, &constants, "is read before it's written");
+    }
+
+    #[test]
+    fn argument_clobbered_by_a_call_is_rejected() {
+        let (mut functions, constants) = compile(PROGRAM);
+        let add = &mut functions[1];
+        let frame_end = add.registers_count;
+
+        // The second argument is passed before a call that reuses its register.
+        add.instructions = vec![
+            Instruction::MoveArg {
+                dest: frame_end,
+                src: 0,
+            },
+            Instruction::Call {
+                dest: 0,
+                src: 1,
+                arity: 0,
+            },
+            Instruction::Call {
+                dest: 0,
+                src: 1,
+                arity: 1,
+            },
+            Instruction::Return { src: 0 },
+        ];
+
+        assert_rejected(&functions, &constants, "is read before it's written");
+    }
+
+    #[test]
+    fn boxed_value_as_number_is_rejected() {
+        let natives = NativeRegistry::default();
+        let (functions, constants) = compile("native fn print(value); print(1.5); nil;");
+        let mut bytes = serialize_bytecode(&functions, &constants, &natives);
+
+        let number = 1.5f64.to_bits().to_le_bytes();
+        // The vec at index 123456.
+        let forged = Value::number(f64::from_bits(0x7FFE_0000_0001_E240));
+        assert!(forged.is_vec());
+
+        let at = bytes
+            .windows(number.len())
+            .position(|window| window == number)
+            .unwrap();
+
+        bytes[at..at + number.len()].copy_from_slice(&forged.as_number().to_bits().to_le_bytes());
+
+        seal(&mut bytes);
+
+        let error = deserialize_bytecode(&bytes, &natives).unwrap_err();
+        assert!(
+            error.message.contains("is not a number"),
+            "{}",
+            error.message
+        );
+    }
+
+    #[test]
+    fn nan_constant_loads() {
+        let (functions, mut constants) = compile("native fn print(value); print(1.5); nil;");
+
+        for constant in constants.iter_mut().filter(|constant| constant.is_number()) {
+            *constant = Value::number(-f64::NAN);
+        }
+
+        assert_eq!(load(&functions, &constants), Ok(()));
+    }
+
+    #[test]
+    fn corrupted_file_is_rejected() {
+        let (functions, constants) = compile(PROGRAM);
+        let mut bytes = serialize_bytecode(&functions, &constants, &NativeRegistry::default());
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+
+        assert!(load_bytes(&bytes).contains("checksum mismatch"));
+    }
+
+    #[test]
+    fn truncated_file_is_rejected() {
+        let (functions, constants) = compile(PROGRAM);
+        let mut bytes = serialize_bytecode(&functions, &constants, &NativeRegistry::default());
+
+        bytes.truncate(bytes.len() - 3);
+        seal(&mut bytes);
+
+        assert!(load_bytes(&bytes).contains("the file is truncated"));
+    }
+
+    #[test]
+    fn other_format_version_is_rejected() {
+        let (functions, constants) = compile(PROGRAM);
+        let mut bytes = serialize_bytecode(&functions, &constants, &NativeRegistry::default());
+
+        bytes[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        assert!(load_bytes(&bytes).contains("is not supported"));
+    }
+
+    #[test]
+    fn jump_out_of_the_function_is_rejected() {
+        let (mut functions, constants) = compile(PROGRAM);
+        let add = &mut functions[1];
+
+        add.instructions.insert(0, Instruction::Jump { offset: -5 });
+
+        assert_rejected(&functions, &constants, "jump out of the function");
+    }
+
+    #[test]
+    fn closure_of_an_unknown_function_is_rejected() {
+        let (mut functions, constants) = compile(PROGRAM);
+        let count = functions.len() as u32;
+
+        let closure = functions[0]
+            .instructions
+            .iter_mut()
+            .find_map(|instruction| match instruction {
+                Instruction::CreateClosure { src, .. } => Some(src),
+                _ => None,
+            })
+            .unwrap();
+
+        *closure = count;
+
+        assert_rejected(&functions, &constants, "unknown function");
+    }
+}
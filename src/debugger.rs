@@ -0,0 +1,399 @@
+use std::{
+    cell::Cell,
+    fs,
+    io::{self, BufRead, Write},
+    path::Path,
+    process::ExitCode,
+    rc::Rc,
+};
+
+use crate::{
+    diagnostics::{
+        error::Error,
+        source_map::{SourceFile, SourceMap},
+    },
+    program::{INTERNER, compile_for_debugging, run_functions},
+    report_error,
+    runtime::{
+        debug_hook::{DebugHook, Frame},
+        debug_value::DebugValue,
+        vm::VmState,
+    },
+    std::native_functions::NativeRegistry,
+};
+
+const HELP: &str = "\
+Commands:
+  c, continue          run until a breakpoint
+  s, step              run to the next line, entering calls
+  n, next              run to the next line of this call or its callers
+  o, out               run until the current call returns
+  b, break [FILE:]LINE add a breakpoint, list them without a line
+  d, delete [FILE:]LINE remove a breakpoint
+  bt, backtrace        show the active calls
+  f, frame N           select the call for print and locals
+  p, print NAME        show a variable of the selected call
+  l, locals            show every variable of the selected call
+  q, quit              stop the program
+An empty line repeats the last command.";
+
+/// Runs the file at `path` under an interactive debugger reading commands
+/// from stdin. It stops at the first line of the file, and whenever a
+/// breakpoint or a step is reached. Fails when the program doesn't compile
+/// or stops with an error, but not when the user quits it.
+pub fn run_debugger(path: &Path, max_depth: usize) -> ExitCode {
+    let Ok(source) = fs::read_to_string(path) else {
+        eprintln!("Error: Could not read the file by the given path.");
+        return ExitCode::FAILURE;
+    };
+
+    let natives = NativeRegistry::default();
+    let mut sources = SourceMap::default();
+
+    let (constants, functions) =
+        match compile_for_debugging(&source, Some(path), &natives, &mut sources) {
+            Ok(compiled) => compiled,
+            Err(errors) => {
+                Error::report_all(&errors, &sources);
+                return ExitCode::FAILURE;
+            }
+        };
+
+    // The file being debugged is added before the modules it imports.
+    let main = sources.files()[0].start;
+    let sources = Rc::new(sources);
+    let quit = Rc::new(Cell::new(false));
+
+    let mut state = VmState::new(&natives);
+    state.set_max_depth(max_depth);
+    state.attach_debugger(Box::new(Debugger::new(sources.clone(), main, quit.clone())));
+
+    match run_functions(&mut state, functions, constants) {
+        Ok(()) => println!("Program finished."),
+        Err(_) if quit.get() => {}
+        Err(error) => {
+            error.report(&sources);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Where execution stops next, besides breakpoints.
+#[derive(Clone, Copy)]
+enum Mode {
+    /// At the first line of the file that starts there.
+    Start(usize),
+    Continue,
+    /// At the next line.
+    StepIn,
+    /// At the next line of a call at most this deep.
+    StepOver(usize),
+    /// At the next line of a call shallower than this.
+    StepOut(usize),
+}
+
+/// A line of a file, which starts at `file` in the [`SourceMap`].
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Line {
+    file: usize,
+    line: usize,
+}
+
+struct Debugger {
+    sources: Rc<SourceMap>,
+    breakpoints: Vec<Line>,
+    mode: Mode,
+    /// Function and line each active call was last on, to tell when one
+    /// reaches a new line.
+    lines: Vec<(usize, Line)>,
+    /// Call that print and locals look into, counted from the innermost.
+    selected: usize,
+    last_command: String,
+    /// Set when the user stops the program, which isn't an error to report.
+    quit: Rc<Cell<bool>>,
+}
+
+impl DebugHook for Debugger {
+    fn before_instruction(&mut self, state: &VmState) -> Result<(), Error> {
+        let frames = state.frames();
+        let depth = frames.len() - 1;
+        let frame = &frames[depth];
+
+        let Some(line) = self.line(state, frame) else {
+            return Ok(());
+        };
+
+        self.lines.truncate(depth + 1);
+
+        let new_line = self.lines.get(depth) != Some(&(frame.function, line));
+
+        if !new_line {
+            return Ok(());
+        }
+
+        if self.lines.len() == depth + 1 {
+            self.lines[depth] = (frame.function, line);
+        } else {
+            self.lines.push((frame.function, line));
+        }
+
+        let stop = match self.mode {
+            Mode::Start(file) => line.file == file,
+            Mode::Continue => false,
+            Mode::StepIn => true,
+            Mode::StepOver(over) => depth <= over,
+            Mode::StepOut(out) => depth < out,
+        };
+
+        if stop || self.breakpoints.contains(&line) {
+            self.prompt(state, line)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Debugger {
+    fn new(sources: Rc<SourceMap>, main: usize, quit: Rc<Cell<bool>>) -> Self {
+        Self {
+            sources,
+            breakpoints: Vec::new(),
+            mode: Mode::Start(main),
+            lines: Vec::new(),
+            selected: 0,
+            last_command: String::new(),
+            quit,
+        }
+    }
+
+    /// Reads commands until one resumes the program.
+    fn prompt(&mut self, state: &VmState, line: Line) -> Result<(), Error> {
+        self.selected = 0;
+        self.show_line(line);
+
+        let stdin = io::stdin();
+
+        loop {
+            print!("(debug) ");
+            io::stdout().flush().unwrap();
+
+            let mut input = String::new();
+
+            match stdin.lock().read_line(&mut input) {
+                Ok(0) => return self.stop(),
+                Ok(_) => {}
+                Err(error) => {
+                    eprintln!("Error: Could not read the input: {}", error);
+                    return self.stop();
+                }
+            }
+
+            let input = input.trim();
+
+            let input = if input.is_empty() {
+                self.last_command.clone()
+            } else {
+                self.last_command = input.to_owned();
+                input.to_owned()
+            };
+
+            let (command, argument) = match input.split_once(char::is_whitespace) {
+                Some((command, argument)) => (command, argument.trim()),
+                None => (input.as_str(), ""),
+            };
+
+            let depth = state.frames().len() - 1;
+
+            match command {
+                "c" | "continue" => {
+                    self.mode = Mode::Continue;
+                    return Ok(());
+                }
+                "s" | "step" => {
+                    self.mode = Mode::StepIn;
+                    return Ok(());
+                }
+                "n" | "next" => {
+                    self.mode = Mode::StepOver(depth);
+                    return Ok(());
+                }
+                "o" | "out" => {
+                    self.mode = Mode::StepOut(depth);
+                    return Ok(());
+                }
+                "b" | "break" if argument.is_empty() => self.list_breakpoints(),
+                "b" | "break" => match self.parse_line(argument, line) {
+                    Ok(breakpoint) if !self.breakpoints.contains(&breakpoint) => {
+                        self.breakpoints.push(breakpoint);
+                        println!("Breakpoint at {}.", self.describe(breakpoint));
+                    }
+                    Ok(_) => println!("There is already a breakpoint there."),
+                    Err(message) => println!("{}", message),
+                },
+                "d" | "delete" => match self.parse_line(argument, line) {
+                    Ok(breakpoint) if self.breakpoints.contains(&breakpoint) => {
+                        self.breakpoints.retain(|&found| found != breakpoint);
+                        println!("Removed the breakpoint at {}.", self.describe(breakpoint));
+                    }
+                    Ok(_) => println!("There is no breakpoint there."),
+                    Err(message) => println!("{}", message),
+                },
+                "bt" | "backtrace" => self.backtrace(state),
+                "f" | "frame" => match argument.parse::<usize>() {
+                    Ok(index) if index <= depth => {
+                        self.selected = index;
+                        self.backtrace_entry(state, index);
+                    }
+                    _ => println!("Expected a call number from 0 to {}.", depth),
+                },
+                "p" | "print" if argument.is_empty() => println!("Expected a variable name."),
+                "p" | "print" => {
+                    let frame = self.selected_frame(state);
+
+                    let local = frame
+                        .locals(state)
+                        .into_iter()
+                        .find(|&(name, _)| INTERNER.lock().unwrap().resolve(name) == argument);
+
+                    match local {
+                        Some((_, value)) => println!("{:?}", DebugValue::new(value, state.gc())),
+                        None => println!("No variable `{}` in scope.", argument),
+                    }
+                }
+                "l" | "locals" => {
+                    let frame = self.selected_frame(state);
+
+                    for (name, value) in frame.locals(state) {
+                        let name = INTERNER.lock().unwrap().resolve(name).to_owned();
+
+                        println!("{} = {:?}", name, DebugValue::new(value, state.gc()));
+                    }
+                }
+                "q" | "quit" => return self.stop(),
+                "h" | "help" => println!("{}", HELP),
+                _ => println!("Unknown command `{}`, type `help` for a list.", command),
+            }
+        }
+    }
+
+    /// Stops the program by unwinding it with an error.
+    fn stop(&self) -> Result<(), Error> {
+        self.quit.set(true);
+
+        Err(report_error!("stopped by the debugger"))
+    }
+
+    fn selected_frame<'a>(&self, state: &'a VmState) -> &'a Frame {
+        let frames = state.frames();
+
+        &frames[frames.len() - 1 - self.selected]
+    }
+
+    fn backtrace(&self, state: &VmState) {
+        for index in 0..state.frames().len() {
+            self.backtrace_entry(state, index);
+        }
+    }
+
+    /// Prints the call `index` calls from the innermost.
+    fn backtrace_entry(&self, state: &VmState, index: usize) {
+        let frames = state.frames();
+        let frame = &frames[frames.len() - 1 - index];
+        let function = &state.functions()[frame.function];
+
+        let name = match function.name {
+            Some(name) => INTERNER.lock().unwrap().resolve(name).to_owned(),
+            None if function.is_script => String::from("<script>"),
+            None => String::from("<anonymous>"),
+        };
+
+        let marker = if index == self.selected { '*' } else { ' ' };
+
+        match self.line(state, frame) {
+            Some(line) => println!("{}#{} {} at {}", marker, index, name, self.describe(line)),
+            None => println!("{}#{} {}", marker, index, name),
+        }
+    }
+
+    fn list_breakpoints(&self) {
+        if self.breakpoints.is_empty() {
+            println!("No breakpoints.");
+        }
+
+        for &breakpoint in &self.breakpoints {
+            println!("{}", self.describe(breakpoint));
+        }
+    }
+
+    /// Line of the instruction `frame` is about to run, if it has a span.
+    fn line(&self, state: &VmState, frame: &Frame) -> Option<Line> {
+        let span = state.functions()[frame.function].spans[frame.pc].as_ref()?;
+        let file = self.sources.file(span.start)?;
+
+        Some(Line {
+            file: file.start,
+            line: file.location(span.start).0,
+        })
+    }
+
+    /// Parses `LINE` in the file of `current`, or `FILE:LINE`.
+    fn parse_line(&self, argument: &str, current: Line) -> Result<Line, String> {
+        let (file, line) = match argument.rsplit_once(':') {
+            Some((name, line)) => {
+                let file = self
+                    .sources
+                    .files()
+                    .iter()
+                    .find(|file| matches_name(file, name))
+                    .ok_or_else(|| format!("No loaded file is named `{}`.", name))?;
+
+                (file, line)
+            }
+            None => (self.file(current), argument),
+        };
+
+        let lines = file.source.lines().count();
+
+        match line.parse::<usize>() {
+            Ok(line) if (1..=lines).contains(&line) => Ok(Line {
+                file: file.start,
+                line,
+            }),
+            _ => Err(format!(
+                "Expected a line from 1 to {} of {}.",
+                lines, file.name
+            )),
+        }
+    }
+
+    fn file(&self, line: Line) -> &SourceFile {
+        self.sources
+            .file(line.file)
+            .expect("lines must point into a loaded file")
+    }
+
+    fn describe(&self, line: Line) -> String {
+        format!("{}:{}", self.file(line).name, line.line)
+    }
+
+    fn show_line(&self, line: Line) {
+        let text = self
+            .file(line)
+            .source
+            .lines()
+            .nth(line.line - 1)
+            .unwrap_or_default();
+
+        println!("{}", self.describe(line));
+        println!("{:>5} | {}", line.line, text);
+    }
+}
+
+/// Whether `name` is the name of `file` or the end of its path.
+fn matches_name(file: &SourceFile, name: &str) -> bool {
+    let path = Path::new(&file.name);
+
+    path == Path::new(name) || path.ends_with(name)
+}
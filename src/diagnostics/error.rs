@@ -1,6 +1,8 @@
-use std::ops::Range;
+use std::{fmt, ops::Range};
 
-use ariadne::{Color, Label, Report, ReportKind, Source};
+use ariadne::{Color, Label, Report, ReportKind};
+
+use crate::diagnostics::source_map::{SourceFile, SourceMap};
 
 #[macro_export]
 macro_rules! report_error {
@@ -12,30 +14,196 @@ macro_rules! report_error {
     };
 }
 
+/// Longest cycle of calls folded when a trace is printed, enough for mutual
+/// recursion between a few functions.
+const MAX_FOLDED_CYCLE: usize = 4;
+
+/// Identifies a file in the reports, files with the same name are told apart
+/// by where they start.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct FileId {
+    start: usize,
+    name: String,
+}
+
+impl FileId {
+    fn new(file: &SourceFile) -> Self {
+        Self {
+            start: file.start,
+            name: file.name.clone(),
+        }
+    }
+}
+
+impl fmt::Display for FileId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// One active call at the moment a runtime error was raised.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceFrame {
+    pub function: String,
+    pub span: Option<Range<usize>>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Error {
     pub span: Option<Range<usize>>,
     pub message: String,
+    /// Call stack of a runtime error, innermost call first.
+    pub trace: Vec<TraceFrame>,
 }
 
 impl Error {
     pub fn new(span: Option<Range<usize>>, message: String) -> Self {
-        Self { span, message }
+        Self {
+            span,
+            message,
+            trace: Vec::new(),
+        }
+    }
+
+    /// Splits the trace into cycles of frames that repeat back to back, with
+    /// their number of repetitions, so deep recursion prints as a few lines.
+    pub fn folded_trace(&self) -> Vec<(&[TraceFrame], usize)> {
+        let trace = &self.trace;
+        let mut folded = Vec::new();
+        let mut start = 0;
+
+        while start < trace.len() {
+            let mut best = (1, 1);
+
+            for len in 1..=MAX_FOLDED_CYCLE.min(trace.len() - start) {
+                let cycle = &trace[start..start + len];
+                let mut repeats = 1;
+
+                while trace[start + repeats * len..]
+                    .get(..len)
+                    .is_some_and(|next| next == cycle)
+                {
+                    repeats += 1;
+                }
+
+                if repeats > 1 && repeats * len > best.0 * best.1 {
+                    best = (len, repeats);
+                }
+            }
+
+            let (len, repeats) = best;
+            folded.push((&trace[start..start + len], repeats));
+            start += len * repeats;
+        }
+
+        folded
     }
 
-    pub fn report(&self, source: &str) {
-        let file_id = "source";
-        let span = self.span.clone().unwrap_or(0..0);
+    /// Line printed after a cycle of `len` frames that repeated `repeats` times.
+    pub fn repeated_note(len: usize, repeats: usize) -> String {
+        match len {
+            1 => format!("    ... repeated {} more times", repeats - 1),
+            _ => format!(
+                "    ... previous {} calls repeated {} more times",
+                len,
+                repeats - 1
+            ),
+        }
+    }
 
-        let report = Report::build(ReportKind::Error, (file_id, span.clone())).with_label(
-            Label::new((file_id, span.clone()))
-                .with_message(&self.message)
-                .with_color(Color::Red),
+    pub fn report(&self, sources: &SourceMap) {
+        Self::report_all(std::slice::from_ref(self), sources);
+    }
+
+    /// Prints every error to stderr in source order, sharing one source cache
+    /// between the reports. Errors without a span are printed last.
+    pub fn report_all(errors: &[Error], sources: &SourceMap) {
+        let mut cache = ariadne::sources(
+            sources
+                .files()
+                .iter()
+                .map(|file| (FileId::new(file), file.source.clone())),
         );
 
-        report
-            .finish()
-            .print((file_id, Source::from(source)))
-            .unwrap();
+        let mut errors: Vec<&Error> = errors.iter().collect();
+        errors.sort_by_key(|error| error.span.as_ref().map_or((1, 0), |span| (0, span.start)));
+
+        for error in errors {
+            let file = error
+                .span
+                .as_ref()
+                .and_then(|span| sources.file(span.start));
+
+            let (Some(span), Some(file)) = (&error.span, file) else {
+                error.report_without_source();
+                continue;
+            };
+
+            let file_id = FileId::new(file);
+            let span = file.local_span(span);
+
+            let mut report = Report::build(ReportKind::Error, (file_id.clone(), span.clone()))
+                .with_label(
+                    Label::new((file_id, span))
+                        .with_message(&error.message)
+                        .with_color(Color::Red),
+                );
+
+            if !error.trace.is_empty() {
+                let mut note = String::from("stack trace:");
+
+                for (cycle, repeats) in error.folded_trace() {
+                    for frame in cycle {
+                        note.push_str(&format!("\n    at {}", frame.function));
+
+                        let location = frame.span.as_ref().and_then(|span| {
+                            let frame_file = sources.file(span.start)?;
+                            let (line, column) = frame_file.location(span.start);
+
+                            Some((frame_file, line, column))
+                        });
+
+                        // Frames in the file of the error leave out its name.
+                        match location {
+                            Some((frame_file, line, column)) if frame_file.start == file.start => {
+                                note.push_str(&format!(" ({}:{})", line, column));
+                            }
+                            Some((frame_file, line, column)) => {
+                                note.push_str(&format!(
+                                    " ({}:{}:{})",
+                                    frame_file.name, line, column
+                                ));
+                            }
+                            None => {}
+                        }
+                    }
+
+                    if repeats > 1 {
+                        note.push('\n');
+                        note.push_str(&Self::repeated_note(cycle.len(), repeats));
+                    }
+                }
+
+                report = report.with_note(note);
+            }
+
+            report.finish().eprint(&mut cache).unwrap();
+        }
+    }
+
+    /// Prints the message and the functions on the stack, for errors that
+    /// have no source to point into.
+    pub fn report_without_source(&self) {
+        eprintln!("Error: {}", self.message);
+
+        for (cycle, repeats) in self.folded_trace() {
+            for frame in cycle {
+                eprintln!("    at {}", frame.function);
+            }
+
+            if repeats > 1 {
+                eprintln!("{}", Self::repeated_note(cycle.len(), repeats));
+            }
+        }
     }
 }
@@ -0,0 +1,76 @@
+use std::ops::Range;
+
+/// A source file and where it starts in its [`SourceMap`].
+pub struct SourceFile {
+    pub name: String,
+    pub source: String,
+    pub start: usize,
+}
+
+impl SourceFile {
+    /// Line and column of `offset`, both counted from 1.
+    pub fn location(&self, offset: usize) -> (usize, usize) {
+        let offset = (offset - self.start).min(self.source.len());
+        let before = &self.source[..offset];
+
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        let column = before[line_start..].chars().count() + 1;
+
+        (line, column)
+    }
+
+    /// `span` relative to the start of the file.
+    pub fn local_span(&self, span: &Range<usize>) -> Range<usize> {
+        span.start - self.start..span.end - self.start
+    }
+}
+
+/// Every source file loaded by a program. Files are laid out one after the
+/// other, spans are offsets in that layout, so a span alone tells which file
+/// it points into.
+#[derive(Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    /// A map with `source` as its only file, which starts at offset 0.
+    pub fn single(name: impl Into<String>, source: impl Into<String>) -> Self {
+        let mut sources = Self::default();
+        sources.add(name, source);
+
+        sources
+    }
+
+    /// Adds a file and returns the offset it starts at, the offset to parse
+    /// it with.
+    pub fn add(&mut self, name: impl Into<String>, source: impl Into<String>) -> usize {
+        // One past the end of the previous file, where the spans at its end
+        // point, is left out so that they are not taken for this file.
+        let start = self
+            .files
+            .last()
+            .map_or(0, |file| file.start + file.source.len() + 1);
+
+        self.files.push(SourceFile {
+            name: name.into(),
+            source: source.into(),
+            start,
+        });
+
+        start
+    }
+
+    /// The file `offset` points into.
+    pub fn file(&self, offset: usize) -> Option<&SourceFile> {
+        let index = self.files.partition_point(|file| file.start <= offset);
+        let file = self.files.get(index.checked_sub(1)?)?;
+
+        (offset <= file.start + file.source.len()).then_some(file)
+    }
+
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+}
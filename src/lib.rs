@@ -8,7 +8,11 @@ pub mod bytecode;
 pub mod diagnostics;
 pub mod syntax;
 
+pub mod debugger;
+pub mod lsp;
+pub mod module_loader;
 pub mod program;
+pub mod repl;
 pub mod runtime;
 
 pub mod std;
@@ -0,0 +1,550 @@
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use logos::Logos;
+
+use crate::{
+    bytecode::resolve::{Resolution, Symbol, resolve_for_tools},
+    diagnostics::error::Error,
+    lsp::position::{Position, offset, position},
+    report_error,
+    syntax::{
+        ast::{Ast, Expr, ExprId},
+        parser::Parser,
+        token::Token,
+    },
+    util::string_interner::{StringIndex, StringInterner},
+};
+
+const KEYWORDS: [&str; 20] = [
+    "fn", "native", "import", "export", "if", "else", "while", "for", "to", "downto", "by",
+    "return", "break", "continue", "and", "or", "not", "true", "false", "nil",
+];
+
+/// Where a name is declared, `path` is `None` in the document itself.
+pub struct Location {
+    pub path: Option<PathBuf>,
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionKind {
+    Function,
+    Variable,
+    Field,
+    Keyword,
+}
+
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+    pub detail: Option<String>,
+}
+
+pub struct Diagnostic {
+    pub start: Position,
+    pub end: Position,
+    pub message: String,
+}
+
+/// Parameters and documentation of a function, shown on hover.
+#[derive(Clone)]
+struct Signature {
+    native: bool,
+    name: String,
+    parameters: Vec<String>,
+    doc: Option<String>,
+}
+
+impl Signature {
+    fn label(&self) -> String {
+        let native = if self.native { "native " } else { "" };
+
+        format!("{}fn {}({})", native, self.name, self.parameters.join(", "))
+    }
+
+    fn markdown(&self) -> String {
+        let mut markdown = format!("```kaori\n{}\n```", self.label());
+
+        if let Some(doc) = &self.doc {
+            markdown.push_str("\n\n");
+            markdown.push_str(doc);
+        }
+
+        markdown
+    }
+}
+
+/// A name exported by a module the document imports.
+struct Export {
+    path: PathBuf,
+    start: Position,
+    end: Position,
+    signature: Option<Signature>,
+}
+
+/// An open source file and what the parser and resolver found in it. It's
+/// analyzed again on every change, errors included, so that the parts that
+/// still parse keep working while it's being edited.
+pub struct Document {
+    text: String,
+    /// Names and strings of the document and its imports, freed with it
+    /// rather than kept by the global interner across every edit.
+    interner: StringInterner,
+    ast: Ast,
+    resolution: Resolution,
+    errors: Vec<Error>,
+    /// Names brought in by imports.
+    imports: HashMap<StringIndex, Export>,
+    /// Signatures of the functions, keyed by the identifier naming them.
+    signatures: HashMap<ExprId, Signature>,
+    /// Values given to the variables declared with `:=`, keyed by the identifier.
+    initializers: HashMap<ExprId, ExprId>,
+}
+
+impl Document {
+    /// Analyzes `text`, read from `path`, which imports are found relative to.
+    pub fn new(text: String, path: Option<&Path>) -> Self {
+        let mut interner = StringInterner::default();
+
+        let tokens = Token::lexer(&text).spanned();
+        let (ast, mut errors) = Parser::new(tokens)
+            .keep_comments()
+            .interner(&mut interner)
+            .parse();
+
+        let imports = load_imports(&ast, path, &mut interner, &mut errors);
+
+        let globals: Vec<(StringIndex, bool)> = imports
+            .iter()
+            .map(|(&name, export)| (name, export.signature.is_some()))
+            .collect();
+
+        let (resolution, resolve_errors) = resolve_for_tools(&ast, globals, &interner);
+        errors.extend(resolve_errors);
+
+        let signatures = signatures(&ast, &interner);
+
+        let initializers = ast
+            .expressions()
+            .filter_map(|id| match *ast.get(id) {
+                Expr::DeclareAssign { left, right } => Some((left, right)),
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            text,
+            interner,
+            ast,
+            resolution,
+            errors,
+            imports,
+            signatures,
+            initializers,
+        }
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.errors
+            .iter()
+            .map(|error| {
+                let span = error.span.clone().unwrap_or(0..0);
+
+                Diagnostic {
+                    start: position(&self.text, span.start),
+                    end: position(&self.text, span.end),
+                    message: error.message.clone(),
+                }
+            })
+            .collect()
+    }
+
+    pub fn definition(&self, at: Position) -> Option<Location> {
+        let symbol = self.symbol_at(at)?;
+
+        match symbol.declaration {
+            Some(declaration) => Some(self.location(declaration)),
+            None => {
+                let export = self.imports.get(&symbol.name)?;
+
+                Some(Location {
+                    path: Some(export.path.clone()),
+                    start: export.start,
+                    end: export.end,
+                })
+            }
+        }
+    }
+
+    /// Every identifier in the document that refers to the name at `at`.
+    pub fn references(&self, at: Position, include_declaration: bool) -> Vec<Location> {
+        let Some(symbol) = self.symbol_at(at) else {
+            return Vec::new();
+        };
+
+        let mut references: Vec<ExprId> = self
+            .resolution
+            .references(&symbol)
+            .into_iter()
+            .filter(|&identifier| include_declaration || Some(identifier) != symbol.declaration)
+            .collect();
+
+        references.sort_by_key(|&identifier| self.span(identifier).start);
+
+        references
+            .into_iter()
+            .map(|identifier| self.location(identifier))
+            .collect()
+    }
+
+    /// Markdown describing the name at `at`, the parameters of a function.
+    pub fn hover(&self, at: Position) -> Option<String> {
+        let symbol = self.symbol_at(at)?;
+
+        let signature = match symbol.declaration {
+            Some(declaration) => self.signatures.get(&declaration),
+            None => self
+                .imports
+                .get(&symbol.name)
+                .and_then(|export| export.signature.as_ref()),
+        };
+
+        Some(match signature {
+            Some(signature) => signature.markdown(),
+            None => format!("```kaori\n{}\n```", self.resolve(symbol.name)),
+        })
+    }
+
+    /// Names in scope at `at`, or the dict fields when it follows a `.`.
+    pub fn completion(&self, at: Position) -> Vec<Completion> {
+        let offset = offset(&self.text, at);
+        let before = &self.text[..offset];
+
+        let prefix_start = before
+            .char_indices()
+            .rev()
+            .find(|&(_, char)| !is_identifier_char(char))
+            .map_or(0, |(index, char)| index + char.len_utf8());
+
+        let prefix = &before[prefix_start..];
+
+        let mut completions = match before[..prefix_start].strip_suffix('.') {
+            Some(object) => self.field_completions(object, offset),
+            None => self.name_completions(offset),
+        };
+
+        completions.retain(|completion| completion.label.starts_with(prefix));
+        completions.sort_by(|a, b| a.label.cmp(&b.label));
+
+        completions
+    }
+
+    fn name_completions(&self, offset: usize) -> Vec<Completion> {
+        let mut completions: Vec<Completion> = self
+            .visible_symbols(offset)
+            .into_iter()
+            .map(|symbol| {
+                let signature = match symbol.declaration {
+                    Some(declaration) => self.signatures.get(&declaration),
+                    None => self
+                        .imports
+                        .get(&symbol.name)
+                        .and_then(|export| export.signature.as_ref()),
+                };
+
+                let kind = if symbol.function || signature.is_some() {
+                    CompletionKind::Function
+                } else {
+                    CompletionKind::Variable
+                };
+
+                Completion {
+                    label: self.resolve(symbol.name),
+                    kind,
+                    detail: signature.map(Signature::label),
+                }
+            })
+            .collect();
+
+        completions.extend(KEYWORDS.iter().map(|keyword| Completion {
+            label: keyword.to_string(),
+            kind: CompletionKind::Keyword,
+            detail: None,
+        }));
+
+        completions
+    }
+
+    /// Fields of the dict literal the variable before the `.` was declared
+    /// with, or of every dict literal in the document when it's not known.
+    fn field_completions(&self, object: &str, offset: usize) -> Vec<Completion> {
+        let name_start = object
+            .char_indices()
+            .rev()
+            .find(|&(_, char)| !is_identifier_char(char))
+            .map_or(0, |(index, char)| index + char.len_utf8());
+
+        let name = &object[name_start..];
+
+        let dict = self
+            .visible_symbols(offset)
+            .into_iter()
+            .find(|symbol| self.resolve(symbol.name) == name)
+            .and_then(|symbol| self.initializers.get(&symbol.declaration?))
+            .filter(|&&value| matches!(self.ast.get(value), Expr::DictLiteral { .. }));
+
+        let dicts: Vec<ExprId> = match dict {
+            Some(&dict) => vec![dict],
+            None => self
+                .ast
+                .expressions()
+                .filter(|&id| matches!(self.ast.get(id), Expr::DictLiteral { .. }))
+                .collect(),
+        };
+
+        let mut fields: Vec<String> = Vec::new();
+
+        for dict in dicts {
+            let Expr::DictLiteral { fields: ref keys } = *self.ast.get(dict) else {
+                unreachable!("only dict literals are collected");
+            };
+
+            for &(key, _) in keys.iter() {
+                let Expr::StringLiteral(key) = *self.ast.get(key) else {
+                    continue;
+                };
+
+                let key = self.resolve(key);
+
+                if key.chars().all(is_identifier_char) && !fields.contains(&key) {
+                    fields.push(key);
+                }
+            }
+        }
+
+        fields
+            .into_iter()
+            .map(|field| Completion {
+                label: field,
+                kind: CompletionKind::Field,
+                detail: None,
+            })
+            .collect()
+    }
+
+    /// The innermost variable of every name visible at `offset`. Functions
+    /// are visible in their whole block, other variables after their
+    /// declaration.
+    fn visible_symbols(&self, offset: usize) -> Vec<Symbol> {
+        let entry = self.ast.entry();
+
+        let mut symbols: Vec<(usize, Symbol)> = self
+            .resolution
+            .symbols()
+            .filter_map(|symbol| {
+                let scope = self.span(symbol.scope);
+
+                let in_scope =
+                    symbol.scope == entry || (scope.start < offset && offset < scope.end);
+
+                let declared = symbol.function
+                    || symbol
+                        .declaration
+                        .is_none_or(|declaration| self.span(declaration).end <= offset);
+
+                (in_scope && declared).then_some((scope.len(), symbol))
+            })
+            .collect();
+
+        symbols.sort_by_key(|&(size, _)| size);
+
+        let mut visible: Vec<Symbol> = Vec::new();
+
+        for (_, symbol) in symbols {
+            if !visible.iter().any(|found| found.name == symbol.name) {
+                visible.push(symbol);
+            }
+        }
+
+        visible
+    }
+
+    fn symbol_at(&self, at: Position) -> Option<Symbol> {
+        let offset = offset(&self.text, at);
+
+        let identifier = self.ast.expressions().find(|&id| {
+            matches!(self.ast.get(id), Expr::Identifier(..))
+                && self
+                    .ast
+                    .span(id)
+                    .is_some_and(|span| span.start <= offset && offset <= span.end)
+        })?;
+
+        self.resolution.symbol(identifier)
+    }
+
+    fn span(&self, id: ExprId) -> Range<usize> {
+        self.ast.span(id).cloned().unwrap_or(0..0)
+    }
+
+    fn resolve(&self, name: StringIndex) -> String {
+        self.interner.resolve(name).to_owned()
+    }
+
+    fn location(&self, id: ExprId) -> Location {
+        let span = self.span(id);
+
+        Location {
+            path: None,
+            start: position(&self.text, span.start),
+            end: position(&self.text, span.end),
+        }
+    }
+}
+
+fn is_identifier_char(char: char) -> bool {
+    char.is_ascii_alphanumeric() || char == '_'
+}
+
+/// Reads the modules imported at the top level of `ast`, along with the
+/// names they export. Modules that can't be read are reported in `errors`.
+fn load_imports(
+    ast: &Ast,
+    path: Option<&Path>,
+    interner: &mut StringInterner,
+    errors: &mut Vec<Error>,
+) -> HashMap<StringIndex, Export> {
+    let Expr::Block(ref statements) = *ast.get(ast.entry()) else {
+        unreachable!("the program must be parsed as a block");
+    };
+
+    let directory = path.and_then(Path::parent).unwrap_or(Path::new(""));
+    let mut imports = HashMap::new();
+
+    for &statement in statements.iter() {
+        let Expr::Import(module) = *ast.get(statement) else {
+            continue;
+        };
+
+        let file = directory.join(interner.resolve(module));
+
+        let Ok(source) = fs::read_to_string(&file) else {
+            let span = ast.span(statement).cloned().unwrap_or(0..0);
+            errors.push(report_error!(
+                span,
+                "cannot find module `{}`",
+                file.display()
+            ));
+            continue;
+        };
+
+        let tokens = Token::lexer(&source).spanned();
+        let (module, _) = Parser::new(tokens)
+            .keep_comments()
+            .interner(interner)
+            .parse();
+
+        let signatures = signatures(&module, interner);
+
+        let Expr::Block(ref exports) = *module.get(module.entry()) else {
+            unreachable!("the program must be parsed as a block");
+        };
+
+        for &export in exports.iter() {
+            let Expr::Export(declaration) = *module.get(export) else {
+                continue;
+            };
+
+            let identifier = match *module.get(declaration) {
+                Expr::Function {
+                    name: Some(name), ..
+                } => name,
+                Expr::NativeFunction { name, .. } | Expr::DeclareAssign { left: name, .. } => name,
+                _ => continue,
+            };
+
+            let Expr::Identifier(name) = *module.get(identifier) else {
+                continue;
+            };
+
+            let span = module.span(identifier).cloned().unwrap_or(0..0);
+
+            imports.insert(
+                name,
+                Export {
+                    path: file.clone(),
+                    start: position(&source, span.start),
+                    end: position(&source, span.end),
+                    signature: signatures.get(&identifier).cloned(),
+                },
+            );
+        }
+    }
+
+    imports
+}
+
+/// Signatures of the functions declared in `ast`, by name or by assigning a
+/// function to a new variable, keyed by the identifier naming them.
+fn signatures(ast: &Ast, interner: &StringInterner) -> HashMap<ExprId, Signature> {
+    let mut signatures = HashMap::new();
+
+    let parameter_names = |parameters: &[ExprId]| -> Vec<String> {
+        parameters
+            .iter()
+            .filter_map(|&parameter| match *ast.get(parameter) {
+                Expr::Identifier(name) => Some(interner.resolve(name).to_owned()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    for block in ast.expressions() {
+        let Expr::Block(ref statements) = *ast.get(block) else {
+            continue;
+        };
+
+        for &statement in statements.iter() {
+            let declaration = ast.declaration(statement);
+
+            let (identifier, parameters, native) = match *ast.get(declaration) {
+                Expr::Function {
+                    name: Some(name),
+                    ref parameters,
+                    ..
+                } => (name, parameters, false),
+                Expr::NativeFunction {
+                    name,
+                    ref parameters,
+                } => (name, parameters, true),
+                Expr::DeclareAssign { left, right } => match *ast.get(right) {
+                    Expr::Function { ref parameters, .. } => (left, parameters, false),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+
+            let Expr::Identifier(name) = *ast.get(identifier) else {
+                continue;
+            };
+
+            signatures.insert(
+                identifier,
+                Signature {
+                    native,
+                    name: interner.resolve(name).to_owned(),
+                    parameters: parameter_names(parameters),
+                    doc: ast.doc_comment(statement),
+                },
+            );
+        }
+    }
+
+    signatures
+}
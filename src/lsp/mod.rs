@@ -0,0 +1,5 @@
+pub mod document;
+pub mod position;
+pub mod server;
+
+pub use server::run_server;
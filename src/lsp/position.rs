@@ -0,0 +1,49 @@
+/// A position as editors count it: lines from 0, and characters from 0 in
+/// UTF-16 code units.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
+/// Converts a byte offset in `text` into a position.
+pub fn position(text: &str, offset: usize) -> Position {
+    let mut offset = offset.min(text.len());
+
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+
+    Position {
+        line: before.matches('\n').count() as u32,
+        character: before[line_start..].encode_utf16().count() as u32,
+    }
+}
+
+/// Converts a position into a byte offset in `text`. Positions past the end
+/// of a line are clamped to it.
+pub fn offset(text: &str, position: Position) -> usize {
+    let mut line_start = 0;
+
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(index) => line_start += index + 1,
+            None => return text.len(),
+        }
+    }
+
+    let mut character = 0;
+
+    for (index, char) in text[line_start..].char_indices() {
+        if char == '\n' || character >= position.character {
+            return line_start + index;
+        }
+
+        character += char.len_utf16() as u32;
+    }
+
+    text.len()
+}
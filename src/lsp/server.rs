@@ -0,0 +1,358 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+};
+
+use serde_json::{Value, json};
+
+use crate::lsp::{
+    document::{CompletionKind, Document, Location},
+    position::Position,
+};
+
+/// Error codes defined by JSON-RPC and the language server protocol.
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INVALID_REQUEST: i64 = -32600;
+
+/// Full document sync, every change sends the whole text.
+const SYNC_FULL: u8 = 1;
+
+/// Answers the requests of an editor on standard input and output until it
+/// asks the server to exit. Returns whether it asked for a shutdown first,
+/// which is how the protocol tells a clean exit.
+pub fn run_server() -> bool {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout().lock();
+
+    let mut server = Server::default();
+
+    while let Some(message) = read_message(&mut input) {
+        for reply in server.handle(message) {
+            if write_message(&mut output, &reply).is_err() {
+                return false;
+            }
+        }
+
+        if server.exited {
+            break;
+        }
+    }
+
+    server.shut_down
+}
+
+#[derive(Default)]
+struct Server {
+    documents: HashMap<String, Document>,
+    shut_down: bool,
+    exited: bool,
+}
+
+impl Server {
+    /// Handles a request or notification, returning the messages to send
+    /// back, a response and the diagnostics of the documents that changed.
+    fn handle(&mut self, message: Value) -> Vec<Value> {
+        let id = message.get("id").cloned();
+        let params = message.get("params").cloned().unwrap_or(Value::Null);
+
+        let Some(method) = message.get("method").and_then(Value::as_str) else {
+            // Responses to requests the server never sends are ignored.
+            return match id {
+                Some(id) if message.get("result").is_none() => {
+                    vec![error_response(id, INVALID_REQUEST, "missing method")]
+                }
+                _ => Vec::new(),
+            };
+        };
+
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": SYNC_FULL,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": ["."] },
+                },
+                "serverInfo": { "name": "kaori", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => {
+                self.shut_down = true;
+                Ok(Value::Null)
+            }
+            "exit" => {
+                self.exited = true;
+                return Vec::new();
+            }
+            "textDocument/didOpen" => {
+                let document = &params["textDocument"];
+
+                return match (document["uri"].as_str(), document["text"].as_str()) {
+                    (Some(uri), Some(text)) => self.update(uri, text.to_owned()),
+                    _ => Vec::new(),
+                };
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str();
+
+                // With full sync the last change holds the whole text.
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+
+                return match (uri, text) {
+                    (Some(uri), Some(text)) => self.update(uri, text.to_owned()),
+                    _ => Vec::new(),
+                };
+            }
+            "textDocument/didClose" => {
+                let Some(uri) = params["textDocument"]["uri"].as_str() else {
+                    return Vec::new();
+                };
+
+                self.documents.remove(uri);
+
+                // Closed documents keep no diagnostics in the editor.
+                return vec![publish_diagnostics(uri, Vec::new())];
+            }
+            "textDocument/definition" => self.with_document(&params, |document, uri, at| {
+                document
+                    .definition(at)
+                    .map_or(Value::Null, |location| location_json(uri, &location))
+            }),
+            "textDocument/references" => self.with_document(&params, |document, uri, at| {
+                let include_declaration = params["context"]["includeDeclaration"]
+                    .as_bool()
+                    .unwrap_or(true);
+
+                document
+                    .references(at, include_declaration)
+                    .iter()
+                    .map(|location| location_json(uri, location))
+                    .collect()
+            }),
+            "textDocument/hover" => self.with_document(&params, |document, _, at| {
+                document.hover(at).map_or(
+                    Value::Null,
+                    |markdown| json!({ "contents": { "kind": "markdown", "value": markdown } }),
+                )
+            }),
+            "textDocument/completion" => self.with_document(&params, |document, _, at| {
+                document
+                    .completion(at)
+                    .into_iter()
+                    .map(|completion| {
+                        json!({
+                            "label": completion.label,
+                            "kind": completion_kind(completion.kind),
+                            "detail": completion.detail,
+                        })
+                    })
+                    .collect()
+            }),
+            _ if id.is_none() => return Vec::new(),
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method `{}`", method))),
+        };
+
+        // Notifications are never answered, even when they fail.
+        let Some(id) = id else {
+            return Vec::new();
+        };
+
+        match result {
+            Ok(result) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })],
+            Err((code, message)) => vec![error_response(id, code, &message)],
+        }
+    }
+
+    /// Analyzes the new text of the document at `uri` and returns its
+    /// diagnostics.
+    fn update(&mut self, uri: &str, text: String) -> Vec<Value> {
+        let path = uri_to_path(uri);
+        let document = Document::new(text, path.as_deref());
+
+        let diagnostics = document
+            .diagnostics()
+            .into_iter()
+            .map(|diagnostic| {
+                json!({
+                    "range": range_json(diagnostic.start, diagnostic.end),
+                    "severity": 1,
+                    "source": "kaori",
+                    "message": diagnostic.message,
+                })
+            })
+            .collect();
+
+        self.documents.insert(uri.to_owned(), document);
+
+        vec![publish_diagnostics(uri, diagnostics)]
+    }
+
+    /// Runs `f` on the open document and the position a request is about.
+    fn with_document(
+        &self,
+        params: &Value,
+        f: impl FnOnce(&Document, &str, Position) -> Value,
+    ) -> Result<Value, (i64, String)> {
+        let uri = params["textDocument"]["uri"].as_str();
+        let position = &params["position"];
+
+        let (Some(uri), Some(line), Some(character)) = (
+            uri,
+            position["line"].as_u64(),
+            position["character"].as_u64(),
+        ) else {
+            return Err((INVALID_PARAMS, "expected a document and a position".into()));
+        };
+
+        let Some(document) = self.documents.get(uri) else {
+            return Ok(Value::Null);
+        };
+
+        let at = Position {
+            line: line as u32,
+            character: character as u32,
+        };
+
+        Ok(f(document, uri, at))
+    }
+}
+
+/// Reads the next message, framed by a `Content-Length` header. Returns
+/// `None` once the input ends or can't be read.
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    loop {
+        let mut length = None;
+
+        loop {
+            let mut line = String::new();
+
+            if input.read_line(&mut line).ok()? == 0 {
+                return None;
+            }
+
+            let line = line.trim_end();
+
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':')
+                && name.eq_ignore_ascii_case("content-length")
+            {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+
+        // Headers without a length can't be skipped past, so start over.
+        let Some(length) = length else {
+            continue;
+        };
+
+        let mut body = vec![0; length];
+        input.read_exact(&mut body).ok()?;
+
+        // Malformed messages are dropped, the editor resends the state.
+        if let Ok(message) = serde_json::from_slice(&body) {
+            return Some(message);
+        }
+    }
+}
+
+fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+fn range_json(start: Position, end: Position) -> Value {
+    json!({
+        "start": { "line": start.line, "character": start.character },
+        "end": { "line": end.line, "character": end.character },
+    })
+}
+
+/// `uri` is the document the location was found from.
+fn location_json(uri: &str, location: &Location) -> Value {
+    let uri = match &location.path {
+        Some(path) => path_to_uri(path),
+        None => uri.to_owned(),
+    };
+
+    json!({ "uri": uri, "range": range_json(location.start, location.end) })
+}
+
+fn completion_kind(kind: CompletionKind) -> u8 {
+    match kind {
+        CompletionKind::Function => 3,
+        CompletionKind::Variable => 6,
+        CompletionKind::Field => 5,
+        CompletionKind::Keyword => 14,
+    }
+}
+
+/// The file a `file://` URI names, `None` for other schemes.
+fn uri_to_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match escaped {
+            Some(escaped) if byte == b'%' => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+fn path_to_uri(path: &Path) -> String {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut uri = String::from("file://");
+
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                uri.push(byte as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    uri
+}
@@ -1,35 +1,327 @@
 #[allow(unused_imports)]
 use std::{env::args, process::ExitCode};
 #[allow(unused_imports)]
-use std::{fs, time::Instant};
+use std::{fs, thread, time::Instant};
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command, value_parser};
 
-use kaori::program::run_program;
-use std::path::PathBuf;
+use kaori::{
+    bytecode::serialize_bytecode::is_bytecode,
+    debugger::run_debugger,
+    diagnostics::{error::Error, source_map::SourceMap},
+    lsp::run_server,
+    program::{Emit, Vm, compile_to_bytecode, emit_source_code, run_bytecode},
+    repl::run_repl,
+    runtime::vm::DEFAULT_MAX_DEPTH,
+    std::native_functions::NativeRegistry,
+    syntax::format_source::format_source,
+};
+use std::{
+    io::{self, Read},
+    path::PathBuf,
+};
 
-fn main() {
+/// Native stack reserved for every nested Kaori call. A call takes a few
+/// hundred bytes in release builds and a lot more in debug builds.
+const STACK_PER_CALL: usize = 4 * 1024;
+
+/// Native stack for everything besides nested calls.
+const BASE_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+fn main() -> ExitCode {
     let matches = Command::new("kaori")
-        .arg(Arg::new("file").required(true))
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("fmt")
+                .about("Format source files in place, or standard input to standard output")
+                .arg(Arg::new("files").num_args(0..))
+                .arg(
+                    Arg::new("check")
+                        .long("check")
+                        .action(ArgAction::SetTrue)
+                        .help("Only report the files that would change, failing if any would"),
+                ),
+        )
+        .subcommand(
+            Command::new("compile")
+                .about("Compile a source file to bytecode that can be run later")
+                .arg(Arg::new("file").required(true))
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Where to write the bytecode, defaults to the file with a .krc extension"),
+                ),
+        )
+        .subcommand(
+            Command::new("debug")
+                .about("Run a source file under an interactive debugger")
+                .arg(Arg::new("file").required(true)),
+        )
+        .subcommand(
+            Command::new("lsp").about("Run a language server on standard input and output"),
+        )
+        .arg(Arg::new("file"))
+        .arg(
+            Arg::new("emit")
+                .long("emit")
+                .value_name("STAGE")
+                .help("Print a compiler stage instead of running the program")
+                .value_parser(["tokens", "ast", "bytecode", "bytecode-unoptimized"]),
+        )
+        .arg(
+            Arg::new("max-depth")
+                .long("max-depth")
+                .value_name("CALLS")
+                .help(format!(
+                    "Nested calls allowed before a stack overflow is reported, defaults to {}",
+                    DEFAULT_MAX_DEPTH
+                ))
+                .value_parser(value_parser!(usize)),
+        )
         .get_matches();
 
-    let file: PathBuf = matches.get_one::<String>("file").unwrap().into();
+    if let Some(("compile", matches)) = matches.subcommand() {
+        let file: PathBuf = matches.get_one::<String>("file").unwrap().into();
+
+        let output: PathBuf = match matches.get_one::<String>("output") {
+            Some(output) => output.into(),
+            None => file.with_extension("krc"),
+        };
+
+        return compile_file(&file, &output);
+    }
 
-    match fs::read_to_string(&file) {
-        Ok(source) => {
-            if let Err(error) = run_program(&source) {
-                error.report(&source);
+    if let Some(("fmt", matches)) = matches.subcommand() {
+        let files: Vec<PathBuf> = matches
+            .get_many::<String>("files")
+            .unwrap_or_default()
+            .map(PathBuf::from)
+            .collect();
+
+        return format_files(&files, matches.get_flag("check"));
+    }
+
+    if let Some(("lsp", _)) = matches.subcommand() {
+        return if run_server() {
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+    }
+
+    let emit = matches
+        .get_one::<String>("emit")
+        .map(|stage| match stage.as_str() {
+            "tokens" => Emit::Tokens,
+            "ast" => Emit::Ast,
+            "bytecode" => Emit::Bytecode,
+            _ => Emit::BytecodeUnoptimized,
+        });
+
+    let max_depth = matches
+        .get_one::<usize>("max-depth")
+        .copied()
+        .unwrap_or(DEFAULT_MAX_DEPTH);
+    let file = matches.get_one::<String>("file").map(PathBuf::from);
+
+    let debug = match matches.subcommand() {
+        Some(("debug", matches)) => matches.get_one::<String>("file").map(PathBuf::from),
+        _ => None,
+    };
+
+    // Kaori calls nest on the native stack, which is sized for the deepest
+    // recursion allowed so that it overflows as a Kaori error first.
+    let stack_size = max_depth
+        .checked_mul(STACK_PER_CALL)
+        .and_then(|size| size.checked_add(BASE_STACK_SIZE));
+
+    let runner = stack_size.and_then(|stack_size| {
+        thread::Builder::new()
+            .stack_size(stack_size)
+            .spawn(move || match (debug, file) {
+                (Some(file), _) => run_debugger(&file, max_depth),
+                (None, Some(file)) => run_file(&file, emit, max_depth),
+                (None, None) => {
+                    run_repl(max_depth);
+                    ExitCode::SUCCESS
+                }
+            })
+            .ok()
+    });
+
+    match runner {
+        Some(runner) => runner.join().unwrap(),
+        None => {
+            eprintln!(
+                "Error: Could not reserve a stack for {} nested calls.",
+                max_depth
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_file(file: &PathBuf, emit: Option<Emit>, max_depth: usize) -> ExitCode {
+    let Ok(bytes) = fs::read(file) else {
+        eprintln!("Error: Could not read the file by the given path.");
+        return ExitCode::FAILURE;
+    };
+
+    if is_bytecode(&bytes) {
+        if emit.is_some() {
+            eprintln!("Error: --emit needs a source file, not compiled bytecode.");
+            return ExitCode::FAILURE;
+        }
+
+        if let Err(error) = run_bytecode(&bytes, &NativeRegistry::default(), max_depth) {
+            error.report_without_source();
+            return ExitCode::FAILURE;
+        }
+
+        return ExitCode::SUCCESS;
+    }
+
+    let Ok(source) = String::from_utf8(bytes) else {
+        eprintln!("Error: The file is not valid UTF-8.");
+        return ExitCode::FAILURE;
+    };
+
+    match emit {
+        Some(emit) => {
+            let mut sources = SourceMap::default();
+            let natives = NativeRegistry::default();
+
+            match emit_source_code(&source, Some(file), &natives, emit, &mut sources) {
+                Ok(output) => print!("{}", output),
+                Err(errors) => {
+                    Error::report_all(&errors, &sources);
+                    return ExitCode::FAILURE;
+                }
             }
         }
-        Err(_) => eprintln!("Error: Could not read the file by the given path."),
+        None => {
+            let mut vm = Vm::new();
+            vm.set_max_depth(max_depth);
+
+            if let Err(errors) = vm.eval_file(file) {
+                Error::report_all(&errors, vm.sources());
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Formats `files` in place, or standard input to standard output when there
+/// are none. With `check`, nothing is written and the files that would
+/// change are listed instead.
+fn format_files(files: &[PathBuf], check: bool) -> ExitCode {
+    if files.is_empty() {
+        let mut source = String::new();
+
+        if io::stdin().read_to_string(&mut source).is_err() {
+            eprintln!("Error: Could not read the standard input.");
+            return ExitCode::FAILURE;
+        }
+
+        return match format_source(&source) {
+            Ok(formatted) if check => {
+                if formatted == source {
+                    ExitCode::SUCCESS
+                } else {
+                    eprintln!("<stdin> is not formatted");
+                    ExitCode::FAILURE
+                }
+            }
+            Ok(formatted) => {
+                print!("{}", formatted);
+                ExitCode::SUCCESS
+            }
+            Err(errors) => {
+                Error::report_all(&errors, &SourceMap::single("<stdin>", source));
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    let mut success = true;
+
+    for file in files {
+        let Ok(source) = fs::read_to_string(file) else {
+            eprintln!("Error: Could not read {}.", file.display());
+            success = false;
+            continue;
+        };
+
+        let formatted = match format_source(&source) {
+            Ok(formatted) => formatted,
+            Err(errors) => {
+                Error::report_all(
+                    &errors,
+                    &SourceMap::single(file.display().to_string(), source),
+                );
+                success = false;
+                continue;
+            }
+        };
+
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            eprintln!("{} is not formatted", file.display());
+            success = false;
+        } else if fs::write(file, formatted).is_err() {
+            eprintln!("Error: Could not write {}.", file.display());
+            success = false;
+        }
+    }
+
+    if success {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+fn compile_file(file: &PathBuf, output: &PathBuf) -> ExitCode {
+    let Ok(source) = fs::read_to_string(file) else {
+        eprintln!("Error: Could not read the file by the given path.");
+        return ExitCode::FAILURE;
     };
+
+    let mut sources = SourceMap::default();
+
+    match compile_to_bytecode(
+        &source,
+        Some(file),
+        &NativeRegistry::default(),
+        &mut sources,
+    ) {
+        Ok(bytes) => {
+            if fs::write(output, bytes).is_err() {
+                eprintln!("Error: Could not write {}.", output.display());
+                return ExitCode::FAILURE;
+            }
+
+            ExitCode::SUCCESS
+        }
+        Err(errors) => {
+            Error::report_all(&errors, &sources);
+            ExitCode::FAILURE
+        }
+    }
 }
 
-/* fn main() {
+/* fn main() -> ExitCode {
     let source = fs::read_to_string("main.kr").expect("could not read main.kr");
 
-    if let Err(error) = run_program(&source) {
-        error.report(&source);
+    if let Err(errors) = run_program(&source) {
+        Error::report_all(&errors, &SourceMap::single("main.kr", source));
     }
 }
  */
@@ -0,0 +1,445 @@
+use std::{
+    collections::HashMap,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use logos::Logos;
+
+use crate::{
+    bytecode::{
+        Function,
+        emit_bytecode::Compiler,
+        function_scope::{FunctionScope, Local},
+        instruction::Instruction,
+        optimize_bytecode::{optimize_bytecode, optimize_script},
+        resolve::resolve_with_globals,
+    },
+    diagnostics::{error::Error, source_map::SourceMap},
+    program::INTERNER,
+    report_error,
+    runtime::value::Value,
+    std::native_functions::NativeRegistry,
+    syntax::{
+        ast::{Ast, Expr},
+        parser::Parser,
+        token::Token,
+    },
+    util::string_interner::StringIndex,
+};
+
+/// A top level variable of a compiled script. Scripts run in the outermost
+/// frame, so it keeps its register for the scripts compiled after it.
+#[derive(Clone, Copy, Debug)]
+struct Global {
+    local: Local,
+    /// Declared by a function declaration.
+    function: bool,
+}
+
+/// A name in scope at the top level of a script, and the register of its global.
+pub type Visible = (StringIndex, u16);
+
+/// Source code to compile, `path` is the file it was read from.
+pub struct SourceInput<'a> {
+    pub name: String,
+    pub source: &'a str,
+    pub path: Option<&'a Path>,
+}
+
+/// A script and the first register it may use besides the globals before it.
+#[derive(Clone, Copy, Debug)]
+pub struct Script {
+    pub function: usize,
+    pub first_free: u16,
+}
+
+/// Output of [`ModuleLoader::compile`].
+pub struct Compiled {
+    pub functions: Vec<Function>,
+    pub constants: Vec<Value>,
+    /// Scripts to run in order, every module before the scripts that import it.
+    pub scripts: Vec<Script>,
+    /// Names in scope at the end of the compiled script, for the scripts after it.
+    pub visible: Vec<Visible>,
+    /// Modules compiled for the first time.
+    pub modules: Vec<PathBuf>,
+}
+
+/// Compiles scripts along with the modules they import. A module is compiled
+/// once, later imports of the same file share its globals.
+#[derive(Default)]
+pub struct ModuleLoader {
+    globals: Vec<Global>,
+    /// Names exported by every module compiled so far, by canonical path.
+    modules: HashMap<PathBuf, Vec<Visible>>,
+    /// Leaves the bytecode as emitted, to print it before optimization.
+    unoptimized: bool,
+}
+
+impl ModuleLoader {
+    /// A loader that skips the optimizer.
+    pub fn unoptimized() -> Self {
+        Self {
+            unoptimized: true,
+            ..Self::default()
+        }
+    }
+
+    /// Compiles `input` and the modules it imports, which are found relative
+    /// to its path. `visible` are the globals of earlier scripts `input` can
+    /// refer to, compiled functions are indexed after `function_offset`.
+    pub fn compile(
+        &mut self,
+        natives: &NativeRegistry,
+        sources: &mut SourceMap,
+        input: SourceInput,
+        visible: &[Visible],
+        function_offset: usize,
+        constants: Vec<Value>,
+    ) -> Result<Compiled, Vec<Error>> {
+        let globals = self.globals.clone();
+
+        let mut session = Session {
+            loader: self,
+            natives,
+            sources,
+            function_offset,
+            functions: Vec::new(),
+            constants,
+            scripts: Vec::new(),
+            loading: Vec::new(),
+            modules: Vec::new(),
+        };
+
+        if let Some(path) = input.path.and_then(|path| path.canonicalize().ok()) {
+            session.loading.push(path);
+        }
+
+        let result = session.compile_script(input, visible.to_vec());
+        let modules = session.modules;
+
+        match result {
+            Ok((names, _)) => Ok(Compiled {
+                functions: session.functions,
+                constants: session.constants,
+                scripts: session.scripts,
+                visible: names
+                    .iter()
+                    .map(|local| (local.name, local.register))
+                    .collect(),
+                modules,
+            }),
+            Err(errors) => {
+                // Nothing compiled by a failed call runs, later calls must not see it.
+                self.globals = globals;
+                self.forget(&modules);
+
+                Err(errors)
+            }
+        }
+    }
+
+    /// Drops modules from the cache, so that the next import compiles them
+    /// again. Used for modules that failed to run.
+    pub fn forget(&mut self, modules: &[PathBuf]) {
+        for module in modules {
+            self.modules.remove(module);
+        }
+    }
+
+    /// The register of a global and whether it holds a cell.
+    pub fn global(&self, register: u16) -> Option<Local> {
+        self.find(register).map(|global| global.local)
+    }
+
+    /// Declares a global set by the host.
+    pub fn declare(&mut self, name: StringIndex, register: u16) {
+        let local = Local {
+            name,
+            register,
+            is_cell: false,
+        };
+
+        self.update(local, false);
+    }
+
+    /// First register above every global, where the next script starts
+    /// declaring its own.
+    pub fn first_free_register(&self) -> u16 {
+        self.globals
+            .iter()
+            .map(|global| global.local.register + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn find(&self, register: u16) -> Option<&Global> {
+        self.globals
+            .iter()
+            .find(|global| global.local.register == register)
+    }
+
+    fn update(&mut self, local: Local, function: bool) {
+        match self
+            .globals
+            .iter_mut()
+            .find(|global| global.local.register == local.register)
+        {
+            Some(global) => global.local = local,
+            None => self.globals.push(Global { local, function }),
+        }
+    }
+}
+
+/// State of one call to [`ModuleLoader::compile`].
+struct Session<'a> {
+    loader: &'a mut ModuleLoader,
+    natives: &'a NativeRegistry,
+    sources: &'a mut SourceMap,
+    function_offset: usize,
+    functions: Vec<Function>,
+    constants: Vec<Value>,
+    scripts: Vec<Script>,
+    /// Canonical paths of the files being compiled, each imported by the one
+    /// before it.
+    loading: Vec<PathBuf>,
+    modules: Vec<PathBuf>,
+}
+
+impl Session<'_> {
+    /// Compiles a script after the modules it imports. Returns the names in
+    /// scope at its end and the ones it exports.
+    fn compile_script(
+        &mut self,
+        input: SourceInput,
+        mut visible: Vec<Visible>,
+    ) -> Result<(Vec<Local>, Vec<StringIndex>), Vec<Error>> {
+        let offset = self.sources.add(input.name, input.source);
+
+        let tokens = Token::lexer(input.source).spanned();
+        let (ast, mut errors) = Parser::with_offset(tokens, offset).parse();
+
+        let directory = input.path.and_then(Path::parent).unwrap_or(Path::new(""));
+        let mut imported: Vec<(Visible, StringIndex)> = Vec::new();
+
+        for (path, span) in imports(&ast) {
+            let exports = match self.import(directory, path, span.clone()) {
+                Ok(exports) => exports,
+                Err(import_errors) => {
+                    errors.extend(import_errors);
+                    continue;
+                }
+            };
+
+            for (name, register) in exports {
+                let conflict = imported.iter().find(|&&((found, found_register), _)| {
+                    found == name && found_register != register
+                });
+
+                if let Some(&(_, other)) = conflict {
+                    let interner = INTERNER.lock().unwrap();
+
+                    errors.push(report_error!(
+                        span.clone(),
+                        "`{}` is also exported by `{}`, which is already imported",
+                        interner.resolve(name),
+                        interner.resolve(other)
+                    ));
+                    continue;
+                }
+
+                visible.retain(|&(found, _)| found != name);
+                visible.push((name, register));
+                imported.push(((name, register), path));
+            }
+        }
+
+        // Resolution still runs on a tree with syntax errors so that undeclared
+        // names are reported in the same run.
+        let globals: Vec<(StringIndex, bool)> = visible
+            .iter()
+            .map(|&(name, register)| {
+                let function = self.loader.find(register).is_some_and(|g| g.function);
+
+                (name, function)
+            })
+            .collect();
+
+        let resolution = match resolve_with_globals(&ast, globals) {
+            Ok(resolution) if errors.is_empty() => resolution,
+            Ok(_) => return Err(errors),
+            Err(resolve_errors) => {
+                errors.extend(resolve_errors);
+                return Err(errors);
+            }
+        };
+
+        let first_free = self.loader.first_free_register();
+
+        let mut scope = FunctionScope::default();
+        scope.next_register = first_free;
+
+        for (name, register) in visible.iter().copied() {
+            let was_cell = self
+                .loader
+                .global(register)
+                .is_some_and(|global| global.is_cell);
+            let is_cell = was_cell || resolution.global_cells.contains(&name);
+
+            scope.declare(name, register, is_cell);
+
+            // Closures of this script share the global with the host and
+            // later scripts, so its value moves into a cell first.
+            if is_cell && !was_cell {
+                scope.emit_instruction(Instruction::CreateCell {
+                    dest: register,
+                    src: register,
+                });
+            }
+        }
+
+        let function_offset = self.function_offset + self.functions.len();
+        let constants = std::mem::take(&mut self.constants);
+
+        let (mut functions, constants) = Compiler::resume(self.natives, function_offset, constants)
+            .compile_script(&ast, &resolution, &mut scope)
+            .map_err(|error| vec![error])?;
+
+        let names = scope.names().to_vec();
+
+        if !self.loader.unoptimized {
+            let (script, closures) = functions.split_first_mut().unwrap();
+            optimize_script(script, names.iter().map(|local| local.register));
+            optimize_bytecode(closures);
+        }
+
+        self.scripts.push(Script {
+            function: function_offset,
+            first_free,
+        });
+        self.functions.extend(functions);
+        self.constants = constants;
+
+        for local in names.iter().copied() {
+            let function = resolution.global_functions.contains(&local.name);
+            self.loader.update(local, function);
+        }
+
+        Ok((names, exports(&ast)))
+    }
+
+    /// Compiles the module at `path` unless an earlier import did, and
+    /// returns the globals it exports.
+    fn import(
+        &mut self,
+        directory: &Path,
+        path: StringIndex,
+        span: Range<usize>,
+    ) -> Result<Vec<Visible>, Vec<Error>> {
+        let relative = INTERNER.lock().unwrap().resolve(path).to_owned();
+        let file = directory.join(relative);
+
+        let Ok(canonical) = file.canonicalize() else {
+            return Err(vec![report_error!(
+                span,
+                "cannot find module `{}`",
+                file.display()
+            )]);
+        };
+
+        if let Some(start) = self.loading.iter().position(|found| *found == canonical) {
+            let cycle = self.loading[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|path| path.display().to_string())
+                .collect::<Vec<String>>()
+                .join(" -> ");
+
+            return Err(vec![report_error!(span, "import cycle: {}", cycle)]);
+        }
+
+        if let Some(exports) = self.loader.modules.get(&canonical) {
+            return Ok(exports.clone());
+        }
+
+        let Ok(source) = fs::read_to_string(&canonical) else {
+            return Err(vec![report_error!(
+                span,
+                "cannot read module `{}`",
+                file.display()
+            )]);
+        };
+
+        let input = SourceInput {
+            name: file.display().to_string(),
+            source: &source,
+            path: Some(&canonical),
+        };
+
+        self.loading.push(canonical.clone());
+        let result = self.compile_script(input, Vec::new());
+        self.loading.pop();
+
+        let (names, exported) = result?;
+
+        let exports: Vec<Visible> = exported
+            .into_iter()
+            .filter_map(|name| {
+                let local = names.iter().rev().find(|local| local.name == name)?;
+
+                Some((name, local.register))
+            })
+            .collect();
+
+        self.loader
+            .modules
+            .insert(canonical.clone(), exports.clone());
+        self.modules.push(canonical);
+
+        Ok(exports)
+    }
+}
+
+/// Paths imported at the top level of `ast`, along with the spans of the imports.
+fn imports(ast: &Ast) -> Vec<(StringIndex, Range<usize>)> {
+    let Expr::Block(ref expressions) = *ast.get(ast.entry()) else {
+        unreachable!("the program must be parsed as a block");
+    };
+
+    expressions
+        .iter()
+        .filter_map(|&expression| match *ast.get(expression) {
+            Expr::Import(path) => Some((path, ast.span(expression)?.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Names declared by the exports at the top level of `ast`.
+fn exports(ast: &Ast) -> Vec<StringIndex> {
+    let Expr::Block(ref expressions) = *ast.get(ast.entry()) else {
+        unreachable!("the program must be parsed as a block");
+    };
+
+    expressions
+        .iter()
+        .filter_map(|&expression| {
+            let Expr::Export(declaration) = *ast.get(expression) else {
+                return None;
+            };
+
+            let name = match *ast.get(declaration) {
+                Expr::Function { name, .. } => name?,
+                Expr::NativeFunction { name, .. } | Expr::DeclareAssign { left: name, .. } => name,
+                _ => return None,
+            };
+
+            match *ast.get(name) {
+                Expr::Identifier(name) => Some(name),
+                _ => None,
+            }
+        })
+        .collect()
+}
@@ -1,45 +1,804 @@
-use std::sync::{LazyLock, Mutex, OnceLock};
+use std::{
+    fs,
+    path::Path,
+    rc::Rc,
+    sync::{LazyLock, Mutex},
+};
 
 use logos::Logos;
 
 use crate::{
     bytecode::{
-        Function, emit_bytecode::Compiler, optimize_bytecode::optimize_bytecode, resolve::resolve,
-    },
-    diagnostics::error::Error,
-    runtime::{
-        value::Value,
-        vm::{VmState, run_vm},
+        Function,
+        disassemble::Disassembly,
+        function_scope::{Local, MAX_REGISTERS},
+        serialize_bytecode::{deserialize_bytecode, serialize_bytecode},
     },
-    syntax::{parser::Parser, token::Token},
+    diagnostics::{error::Error, source_map::SourceMap},
+    module_loader::{ModuleLoader, Script, SourceInput, Visible},
+    report_error,
+    runtime::{debug_value::DebugValue, value::Value, vm::VmState},
+    std::native_functions::NativeRegistry,
+    syntax::{debug_ast::DebugAst, parser::Parser, token::Token},
     util::string_interner::StringInterner,
 };
 
+/// Names and strings of every program, which runtime strings point into.
+/// Strings are never removed from it, so they stay valid until the process
+/// exits.
 pub static INTERNER: LazyLock<Mutex<StringInterner>> =
     LazyLock::new(|| Mutex::new(StringInterner::default()));
 
-pub fn compile_source_code(source: &str) -> Result<(Vec<Value>, Vec<Function>), Error> {
-    let tokens = Token::lexer(source).spanned();
-    let parser = Parser::new(tokens);
-    let ast = parser.parse()?;
-    let captures = resolve(&ast)?;
+/// Compiles `source` and the modules it imports into functions that share
+/// one constant pool. Imports are found relative to `path`, the file the
+/// source was read from, and every file is added to `sources`.
+pub fn compile_source_code(
+    source: &str,
+    path: Option<&Path>,
+    natives: &NativeRegistry,
+    sources: &mut SourceMap,
+) -> Result<(Vec<Value>, Vec<Function>), Vec<Error>> {
+    compile_with(&mut ModuleLoader::default(), source, path, natives, sources)
+}
+
+fn compile_with(
+    loader: &mut ModuleLoader,
+    source: &str,
+    path: Option<&Path>,
+    natives: &NativeRegistry,
+    sources: &mut SourceMap,
+) -> Result<(Vec<Value>, Vec<Function>), Vec<Error>> {
+    let input = SourceInput {
+        name: source_name(path),
+        source,
+        path,
+    };
+
+    let compiled = loader.compile(natives, sources, input, &[], 0, Vec::new())?;
+
+    Ok((compiled.constants, compiled.functions))
+}
+
+/// Name of a source in diagnostics.
+fn source_name(path: Option<&Path>) -> String {
+    match path {
+        Some(path) => path.display().to_string(),
+        None => String::from("source"),
+    }
+}
+
+/// A stage of the compiler whose output can be printed with [`emit_source_code`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emit {
+    Tokens,
+    Ast,
+    Bytecode,
+    BytecodeUnoptimized,
+}
+
+/// Prints a stage of the compiler for `source`, read from `path`. The
+/// bytecode stages include the modules it imports.
+pub fn emit_source_code(
+    source: &str,
+    path: Option<&Path>,
+    natives: &NativeRegistry,
+    emit: Emit,
+    sources: &mut SourceMap,
+) -> Result<String, Vec<Error>> {
+    let output = match emit {
+        Emit::Tokens => {
+            let mut output = String::new();
+            let mut lexer = Token::lexer(source);
+
+            while let Some(token) = lexer.next() {
+                let span = lexer.span();
+
+                let token = match token {
+                    Ok(token) => format!("{:?}", token),
+                    Err(_) => String::from("<invalid>"),
+                };
+
+                output.push_str(&format!(
+                    "{:>5}..{:<5} {:<24} {:?}\n",
+                    span.start,
+                    span.end,
+                    token,
+                    lexer.slice()
+                ));
+            }
+
+            output
+        }
+        Emit::Ast => {
+            let offset = sources.add(source_name(path), source);
+            let tokens = Token::lexer(source).spanned();
+            let (ast, errors) = Parser::with_offset(tokens, offset).keep_comments().parse();
+
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+
+            DebugAst::new(&ast).to_string()
+        }
+        Emit::Bytecode => {
+            let (constants, functions) = compile_source_code(source, path, natives, sources)?;
+
+            Disassembly::new(&functions, &constants).to_string()
+        }
+        Emit::BytecodeUnoptimized => {
+            let mut loader = ModuleLoader::unoptimized();
+            let (constants, functions) = compile_with(&mut loader, source, path, natives, sources)?;
 
-    let (mut functions, constants) = Compiler::default().compile(&ast, captures);
+            Disassembly::new(&functions, &constants).to_string()
+        }
+    };
 
-    optimize_bytecode(&mut functions);
+    Ok(output)
+}
+
+/// Compiles `source` and the modules it imports into the contents of a
+/// `.krc` file.
+pub fn compile_to_bytecode(
+    source: &str,
+    path: Option<&Path>,
+    natives: &NativeRegistry,
+    sources: &mut SourceMap,
+) -> Result<Vec<u8>, Vec<Error>> {
+    let (constants, functions) = compile_source_code(source, path, natives, sources)?;
+
+    Ok(serialize_bytecode(&functions, &constants, natives))
+}
+
+/// Loads and runs the contents of a `.krc` file written by [`compile_to_bytecode`],
+/// allowing at most `max_depth` nested calls. The scripts of the imported
+/// modules run first, in the order they were compiled.
+pub fn run_bytecode(bytes: &[u8], natives: &NativeRegistry, max_depth: usize) -> Result<(), Error> {
+    let (functions, constants) = deserialize_bytecode(bytes, natives)?;
 
-    /*     for (index, function) in bytecode.iter().enumerate() {
-        println!("FUNCTION {}", index);
-        println!("{}", function);
-    } */
+    let mut state = VmState::new(natives);
+    state.set_max_depth(max_depth);
 
-    Ok((constants, functions))
+    run_functions(&mut state, functions, constants)
 }
 
-pub fn run_program(source: &str) -> Result<(), Error> {
-    let (constants, functions) = compile_source_code(source)?;
+/// Compiles `source` like [`compile_source_code`], but without optimizations
+/// so that every variable stays in its own register for a debugger to read.
+pub fn compile_for_debugging(
+    source: &str,
+    path: Option<&Path>,
+    natives: &NativeRegistry,
+    sources: &mut SourceMap,
+) -> Result<(Vec<Value>, Vec<Function>), Vec<Error>> {
+    compile_with(
+        &mut ModuleLoader::unoptimized(),
+        source,
+        path,
+        natives,
+        sources,
+    )
+}
+
+/// Loads the functions of a whole program into a new `state` and runs its
+/// scripts in the order they were compiled, the imported modules first.
+pub fn run_functions(
+    state: &mut VmState,
+    functions: Vec<Function>,
+    constants: Vec<Value>,
+) -> Result<(), Error> {
+    let scripts: Vec<usize> = functions
+        .iter()
+        .enumerate()
+        .filter(|(_, function)| function.is_script)
+        .map(|(index, _)| index)
+        .collect();
+
+    state.load(functions, constants);
 
-    run_vm(functions, constants)?;
+    for script in scripts {
+        state.run(script)?;
+    }
 
     Ok(())
 }
+
+pub fn run_program(source: &str) -> Result<(), Vec<Error>> {
+    run_program_with_natives(source, &NativeRegistry::default())
+}
+
+pub fn run_program_with_natives(source: &str, natives: &NativeRegistry) -> Result<(), Vec<Error>> {
+    Vm::with_natives(natives.clone()).eval(source)?;
+
+    Ok(())
+}
+
+/// A Kaori value copied out of the VM, so the host can use it without
+/// access to the garbage collector.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Vec(Vec<HostValue>),
+    Dict(Vec<(HostValue, HostValue)>),
+    Function(FunctionHandle),
+}
+
+/// A closure or native function that can be called through [`Vm::call`]. The
+/// function stays alive until the handle and all its clones are dropped.
+#[derive(Clone, Debug)]
+pub struct FunctionHandle {
+    value: Value,
+    /// Keeps the function pinned in the `Vm` that returned it.
+    _pin: Rc<()>,
+}
+
+impl PartialEq for FunctionHandle {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+/// An embeddable virtual machine. Every call to [`Vm::eval`] runs as a new
+/// script, and top level declarations remain visible to the scripts after it.
+pub struct Vm {
+    state: VmState,
+    natives: NativeRegistry,
+    loader: ModuleLoader,
+    sources: SourceMap,
+    /// Globals in scope for the next script.
+    visible: Vec<Visible>,
+    /// Registers taken by the outermost frame, host calls are placed above them.
+    frame_size: usize,
+    /// Name in diagnostics of the sources evaluated without a path.
+    source_name: String,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::with_natives(NativeRegistry::default())
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_natives(natives: NativeRegistry) -> Self {
+        Self {
+            state: VmState::new(&natives),
+            natives,
+            loader: ModuleLoader::default(),
+            sources: SourceMap::default(),
+            visible: Vec::new(),
+            frame_size: 0,
+            source_name: String::from("<eval>"),
+        }
+    }
+
+    /// Limits the number of nested calls, deeper recursion fails with a
+    /// stack overflow error. Every call also uses native stack, so a limit
+    /// above [`DEFAULT_MAX_DEPTH`](crate::runtime::vm::DEFAULT_MAX_DEPTH) may need a thread with a larger
+    /// stack, recursion that runs out of it fails with the same error.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.state.set_max_depth(max_depth);
+    }
+
+    /// Names the sources given to [`Vm::eval`] and [`Vm::eval_debug`] in
+    /// diagnostics, `<eval>` by default.
+    pub fn set_source_name(&mut self, name: impl Into<String>) {
+        self.source_name = name.into();
+    }
+
+    pub fn eval(&mut self, source: &str) -> Result<HostValue, Vec<Error>> {
+        let value = self.run_source(source, None)?;
+
+        Ok(self.export_value(value, &mut Vec::new()))
+    }
+
+    /// Evaluates the file at `path`, its imports are found relative to it.
+    pub fn eval_file(&mut self, path: &Path) -> Result<HostValue, Vec<Error>> {
+        let source = fs::read_to_string(path)
+            .map_err(|error| vec![report_error!("cannot read `{}`: {}", path.display(), error)])?;
+
+        let value = self.run_source(&source, Some(path))?;
+
+        Ok(self.export_value(value, &mut Vec::new()))
+    }
+
+    /// Evaluates `source` and formats its value with [`DebugValue`], `None`
+    /// when the value is nil.
+    pub fn eval_debug(&mut self, source: &str) -> Result<Option<String>, Vec<Error>> {
+        let value = self.run_source(source, None)?;
+
+        if value.is_nil() {
+            return Ok(None);
+        }
+
+        // Closures are shown by name rather than by their address, which
+        // changes from run to run.
+        if value.is_closure() || value.is_native() {
+            let name = self
+                .state
+                .function_name(value)
+                .map(|name| INTERNER.lock().unwrap().resolve(name).to_owned());
+
+            return Ok(Some(match name {
+                Some(name) => format!("<fn {}>", name),
+                None => String::from("<fn>"),
+            }));
+        }
+
+        Ok(Some(format!(
+            "{:?}",
+            DebugValue::new(value, self.state.gc())
+        )))
+    }
+
+    /// Every source evaluated so far, to report errors with.
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+
+    fn run_source(&mut self, source: &str, path: Option<&Path>) -> Result<Value, Vec<Error>> {
+        let input = SourceInput {
+            name: match path {
+                Some(_) => source_name(path),
+                None => self.source_name.clone(),
+            },
+            source,
+            path,
+        };
+
+        let compiled = self.loader.compile(
+            &self.natives,
+            &mut self.sources,
+            input,
+            &self.visible,
+            self.state.functions().len(),
+            self.state.constants().to_vec(),
+        )?;
+
+        let scripts: Vec<(Script, usize)> = compiled
+            .scripts
+            .iter()
+            .map(|&script| {
+                let function = &compiled.functions[script.function - self.state.functions().len()];
+
+                (script, function.registers_count as usize)
+            })
+            .collect();
+
+        self.state.load(compiled.functions, compiled.constants);
+        self.visible = compiled.visible;
+
+        let mut value = Value::nil();
+
+        for (script, registers_count) in scripts {
+            let first_free = script.first_free as usize;
+
+            // Registers above the existing globals may hold values of earlier
+            // scripts, which must not leak into globals that fail to initialize.
+            self.state.reserve_registers(registers_count);
+            self.state.registers()[first_free..registers_count.max(first_free)].fill(Value::nil());
+
+            self.frame_size = self.frame_size.max(registers_count);
+
+            value = self.state.run(script.function).map_err(|error| {
+                // A module that failed halfway is compiled and run again by
+                // the next import instead of sharing its broken globals.
+                self.loader.forget(&compiled.modules);
+
+                vec![error]
+            })?;
+        }
+
+        Ok(value)
+    }
+
+    pub fn global(&mut self, name: &str) -> Option<HostValue> {
+        let global = self.global_local(name)?;
+        let mut value = self.state.registers()[global.register as usize];
+
+        if global.is_cell {
+            value = self.state.gc().get_cell(value);
+        }
+
+        Some(self.export_value(value, &mut Vec::new()))
+    }
+
+    /// Assigns a global, declaring it for later scripts when it doesn't exist.
+    pub fn set_global(&mut self, name: &str, value: &HostValue) -> Result<(), Error> {
+        let global = match self.global_local(name) {
+            Some(global) => global,
+            None => {
+                let register = self.loader.first_free_register();
+
+                if register == MAX_REGISTERS {
+                    return Err(report_error!("cannot declare `{}`, too many globals", name));
+                }
+
+                let name = INTERNER.lock().unwrap().get_or_intern(name);
+
+                self.loader.declare(name, register);
+                self.visible.push((name, register));
+                self.frame_size = self.frame_size.max(register as usize + 1);
+                self.state.reserve_registers(self.frame_size);
+
+                self.loader.global(register).unwrap()
+            }
+        };
+
+        let value = self.import_value(value);
+        let register = &mut self.state.registers()[global.register as usize];
+
+        if global.is_cell {
+            let cell = *register;
+            *self.state.gc_mut().get_mut_cell(cell) = value;
+        } else {
+            *register = value;
+        }
+
+        Ok(())
+    }
+
+    pub fn call(
+        &mut self,
+        function: &FunctionHandle,
+        arguments: &[HostValue],
+    ) -> Result<HostValue, Error> {
+        let arguments: Vec<Value> = arguments
+            .iter()
+            .map(|argument| self.import_value(argument))
+            .collect();
+
+        let value = self
+            .state
+            .call(self.frame_size, function.value, &arguments)?;
+
+        Ok(self.export_value(value, &mut Vec::new()))
+    }
+
+    fn global_local(&self, name: &str) -> Option<Local> {
+        let name = INTERNER.lock().unwrap().get(name)?;
+
+        let &(_, register) = self
+            .visible
+            .iter()
+            .rev()
+            .find(|&&(found, _)| found == name)?;
+
+        self.loader.global(register)
+    }
+
+    /// Vecs and dicts that contain themselves are cut where they refer back
+    /// to a value being converted, which becomes `Nil`.
+    fn export_value(&mut self, value: Value, path: &mut Vec<Value>) -> HostValue {
+        if value.is_number() {
+            return HostValue::Number(value.as_number());
+        }
+
+        if value.is_nil() {
+            return HostValue::Nil;
+        }
+
+        if value.is_bool() {
+            return HostValue::Bool(value.as_bool());
+        }
+
+        if value.is_string() {
+            return HostValue::String(self.state.gc().get_str(value).to_owned());
+        }
+
+        if value.is_closure() || value.is_native() {
+            let pin = self.state.pin(value);
+
+            return HostValue::Function(FunctionHandle { value, _pin: pin });
+        }
+
+        if path.contains(&value) {
+            return HostValue::Nil;
+        }
+
+        path.push(value);
+
+        let host = if value.is_vec() {
+            let elements = self.state.gc().get_vec(value).clone();
+
+            HostValue::Vec(
+                elements
+                    .into_iter()
+                    .map(|element| self.export_value(element, path))
+                    .collect(),
+            )
+        } else {
+            let entries: Vec<(Value, Value)> = self
+                .state
+                .gc()
+                .get_dict(value)
+                .iter()
+                .map(|(&key, &value)| (key, value))
+                .collect();
+
+            HostValue::Dict(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| {
+                        (self.export_value(key, path), self.export_value(value, path))
+                    })
+                    .collect(),
+            )
+        };
+
+        path.pop();
+
+        host
+    }
+
+    fn import_value(&mut self, value: &HostValue) -> Value {
+        match value {
+            HostValue::Nil => Value::nil(),
+            HostValue::Bool(value) => Value::boolean(*value),
+            HostValue::Number(value) => Value::number(*value),
+            HostValue::String(value) => self.state.gc_mut().allocate_string(value.clone()),
+            HostValue::Vec(elements) => {
+                let elements: Vec<Value> = elements
+                    .iter()
+                    .map(|element| self.import_value(element))
+                    .collect();
+
+                let vec = self.state.gc_mut().allocate_vec();
+                self.state.gc_mut().get_mut_vec(vec).extend(elements);
+
+                vec
+            }
+            HostValue::Dict(entries) => {
+                let entries: Vec<(Value, Value)> = entries
+                    .iter()
+                    .map(|(key, value)| {
+                        let key = self.import_value(key);
+
+                        (self.state.dict_key(key), self.import_value(value))
+                    })
+                    .collect();
+
+                let dict = self.state.gc_mut().allocate_dict();
+                self.state.gc_mut().get_mut_dict(dict).extend(entries);
+
+                dict
+            }
+            HostValue::Function(function) => function.value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str) -> HostValue {
+        eval_in(&mut Vm::new(), source)
+    }
+
+    fn eval_in(vm: &mut Vm, source: &str) -> HostValue {
+        vm.eval(source)
+            .unwrap_or_else(|errors| panic!("`{}` failed: {:?}", source, errors[0].message))
+    }
+
+    #[test]
+    fn nested_call_keeps_earlier_arguments() {
+        let source = "
+            fn id(x) { return x; }
+            fn add3(a, b, c) { return a + b + c; }
+            add3(id(1), add3(id(2), id(3), id(4)), id(5));
+        ";
+
+        assert_eq!(eval(source), HostValue::Number(15.0));
+    }
+
+    #[test]
+    fn call_in_last_argument_keeps_the_first() {
+        let source = "
+            native fn push(v, x);
+            keep := [];
+            fn mk(n) { return n; }
+            push(keep, mk(1));
+            keep;
+        ";
+
+        assert_eq!(eval(source), HostValue::Vec(vec![HostValue::Number(1.0)]));
+    }
+
+    #[test]
+    fn argument_is_read_before_later_arguments_assign_it() {
+        let source = "
+            fn sub(a, b) { return a - b; }
+            x := 1;
+            sub(x, x = 5);
+        ";
+
+        assert_eq!(eval(source), HostValue::Number(-4.0));
+    }
+
+    #[test]
+    fn negative_zero_is_its_own_constant() {
+        let source = "
+            zero := 0.0;
+            1 / -0.0;
+        ";
+
+        assert_eq!(eval(source), HostValue::Number(f64::NEG_INFINITY));
+    }
+
+    fn numbers(values: &[f64]) -> HostValue {
+        HostValue::Vec(
+            values
+                .iter()
+                .map(|&value| HostValue::Number(value))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn for_loop_counts_down_by_a_negative_step() {
+        let source = "
+            native fn push(v, x);
+            seen := [];
+            for i := 5 to 1 by -2 { push(seen, i); }
+            seen;
+        ";
+
+        assert_eq!(eval(source), numbers(&[5.0, 3.0, 1.0]));
+    }
+
+    #[test]
+    fn downto_loop_counts_down_by_its_step() {
+        let source = "
+            native fn push(v, x);
+            seen := [];
+            for i := 5 downto 1 by 2 { push(seen, i); }
+            seen;
+        ";
+
+        assert_eq!(eval(source), numbers(&[5.0, 3.0, 1.0]));
+    }
+
+    #[test]
+    fn for_loop_takes_fractional_steps() {
+        let source = "
+            native fn push(v, x);
+            seen := [];
+            for i := 0 to 1 by 0.25 { push(seen, i); }
+            for i := 1 downto 0 by 0.5 { push(seen, i); }
+            seen;
+        ";
+
+        assert_eq!(
+            eval(source),
+            numbers(&[0.0, 0.25, 0.5, 0.75, 1.0, 1.0, 0.5, 0.0])
+        );
+    }
+
+    #[test]
+    fn downto_loop_rejects_a_negative_step_at_runtime() {
+        let source = "
+            s := -1;
+            for i := 5 downto 1 by s { }
+        ";
+
+        let errors = Vm::new().eval(source).unwrap_err();
+
+        assert!(
+            errors[0]
+                .message
+                .contains("step of a `downto` loop must be positive")
+        );
+    }
+
+    #[test]
+    fn garbage_of_a_loop_is_collected() {
+        let mut vm = Vm::new();
+
+        vm.eval(
+            "
+            for i := 1 to 100000 {
+                item := {\"values\": [i, i + 1]};
+            }
+            ",
+        )
+        .unwrap();
+
+        // Every iteration leaves a dict and a vec behind.
+        assert!(vm.state.gc().live_objects() < 10000);
+    }
+
+    #[test]
+    fn closure_assigns_the_variable_it_captures() {
+        let source = "
+            count := 0;
+            fn inc() { count += 1; }
+            inc();
+            inc();
+            count;
+        ";
+
+        assert_eq!(eval(source), HostValue::Number(2.0));
+    }
+
+    #[test]
+    fn closures_share_a_captured_variable() {
+        let source = "
+            fn counter() {
+                count := 0;
+                fn inc() { count += 1; }
+                fn get() { return count; }
+                inc();
+                count += 10;
+                inc();
+                return get;
+            }
+            get := counter();
+            get();
+        ";
+
+        assert_eq!(eval(source), HostValue::Number(12.0));
+    }
+
+    #[test]
+    fn loop_closures_keep_their_own_iteration() {
+        let source = "
+            native fn push(v, x);
+            closures := [];
+            for i := 1 to 3 {
+                fn get() { return i; }
+                push(closures, get);
+            }
+            closures[0]() + closures[2]();
+        ";
+
+        assert_eq!(eval(source), HostValue::Number(4.0));
+    }
+
+    #[test]
+    fn functions_are_pinned_while_a_handle_exists() {
+        let mut vm = Vm::new();
+        let garbage = "for i := 1 to 10000 { item := [i]; }";
+
+        let HostValue::Function(make) = eval_in(
+            &mut vm,
+            "fn make() { fn made() { return 1; } return made; } make;",
+        ) else {
+            panic!("`make` must be returned as a function");
+        };
+
+        let handles: Vec<HostValue> = (0..1000).map(|_| vm.call(&make, &[]).unwrap()).collect();
+        eval_in(&mut vm, garbage);
+
+        assert!(vm.state.gc().live_objects() >= 1000);
+
+        let HostValue::Function(ref made) = handles[0] else {
+            panic!("`made` must be returned as a function");
+        };
+
+        assert_eq!(vm.call(made, &[]).unwrap(), HostValue::Number(1.0));
+
+        drop(handles);
+        eval_in(&mut vm, garbage);
+
+        assert!(vm.state.gc().live_objects() < 1000);
+    }
+
+    #[test]
+    fn errors_are_reported_in_the_named_source() {
+        let mut vm = Vm::new();
+        vm.set_source_name("<repl>");
+
+        let errors = vm.eval("x := 1;\n1 + nil;").unwrap_err();
+        let span = errors[0].span.clone().unwrap();
+        let file = vm.sources().file(span.start).unwrap();
+
+        assert_eq!(file.name, "<repl>");
+        assert_eq!(file.location(span.start), (2, 3));
+    }
+}
@@ -0,0 +1,67 @@
+use std::io::{self, BufRead, Write};
+
+use logos::Logos;
+
+use crate::{diagnostics::error::Error, program::Vm, syntax::token::Token};
+
+/// Reads lines from stdin and evaluates them in a single [`Vm`], so top level
+/// declarations stay alive for the rest of the session.
+pub fn run_repl(max_depth: usize) {
+    let mut vm = Vm::new();
+    vm.set_max_depth(max_depth);
+    vm.set_source_name("<repl>");
+
+    let mut input = String::new();
+    let stdin = io::stdin();
+
+    loop {
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        print!("{}", prompt);
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(error) => {
+                eprintln!("Error: Could not read the input: {}", error);
+                break;
+            }
+        }
+
+        input.push_str(&line);
+
+        if input.trim().is_empty() {
+            input.clear();
+            continue;
+        }
+
+        if is_incomplete(&input) {
+            continue;
+        }
+
+        match vm.eval_debug(&input) {
+            Ok(Some(value)) => println!("{}", value),
+            Ok(None) => {}
+            Err(errors) => Error::report_all(&errors, vm.sources()),
+        }
+
+        input.clear();
+    }
+}
+
+/// Input is incomplete while it has unclosed braces, parentheses or brackets.
+fn is_incomplete(input: &str) -> bool {
+    let mut depth = 0;
+
+    for token in Token::lexer(input).flatten() {
+        match token {
+            Token::LeftBrace | Token::LeftParen | Token::LeftBracket => depth += 1,
+            Token::RightBrace | Token::RightParen | Token::RightBracket => depth -= 1,
+            _ => {}
+        }
+    }
+
+    depth > 0
+}
@@ -0,0 +1,99 @@
+use super::value::Value;
+
+/// Registers in a newly allocated segment, unless a frame needs more.
+const SEGMENT_SIZE: usize = 16 * 1024;
+
+/// Registers of every active frame. The stack grows by chaining segments
+/// instead of reallocating, so the registers of a running frame never move.
+pub(crate) struct CallStack {
+    segments: Vec<Segment>,
+    /// Segment that holds the innermost frame.
+    current: usize,
+}
+
+struct Segment {
+    registers: Box<[Value]>,
+    /// End of the registers in use when a call continued in the next segment.
+    top: usize,
+}
+
+impl Segment {
+    fn new(len: usize) -> Self {
+        Self {
+            registers: vec![Value::default(); len.max(SEGMENT_SIZE)].into_boxed_slice(),
+            top: 0,
+        }
+    }
+}
+
+impl Default for CallStack {
+    fn default() -> Self {
+        Self {
+            segments: vec![Segment::new(SEGMENT_SIZE)],
+            current: 0,
+        }
+    }
+}
+
+impl CallStack {
+    /// Registers of the first segment, where the outermost frame starts.
+    pub fn base(&mut self) -> &mut [Value] {
+        &mut self.segments[0].registers
+    }
+
+    /// Makes the first segment at least `len` registers long. It is only
+    /// resized while no frame is running, keeping its values.
+    pub fn reserve_base(&mut self, len: usize) {
+        debug_assert_eq!(self.current, 0);
+
+        let base = &mut self.segments[0].registers;
+
+        if base.len() < len {
+            let mut registers = vec![Value::default(); len.next_power_of_two()];
+            registers[..base.len()].copy_from_slice(base);
+
+            *base = registers.into_boxed_slice();
+        }
+    }
+
+    /// Continues the stack in the next segment, which holds at least
+    /// `required` registers. `top` is the end of the registers still in use
+    /// in the current segment.
+    pub fn push_segment(&mut self, top: *const Value, required: usize) -> &mut [Value] {
+        let segment = &mut self.segments[self.current];
+        segment.top = unsafe { top.offset_from(segment.registers.as_ptr()) } as usize;
+
+        self.current += 1;
+
+        match self.segments.get_mut(self.current) {
+            Some(segment) if segment.registers.len() >= required => {}
+            Some(segment) => *segment = Segment::new(required),
+            None => self.segments.push(Segment::new(required)),
+        }
+
+        &mut self.segments[self.current].registers
+    }
+
+    pub fn pop_segment(&mut self) {
+        self.current -= 1;
+    }
+
+    /// Frees the segments left over from deep recursion once every frame
+    /// has returned.
+    pub fn shrink(&mut self) {
+        self.segments.truncate(self.current + 1);
+    }
+
+    /// Values that may be in use, from the bottom of the stack up to `top`
+    /// in the current segment.
+    pub fn roots(&self, top: *const Value) -> impl Iterator<Item = Value> {
+        let current = &self.segments[self.current];
+        let len = unsafe { top.offset_from(current.registers.as_ptr()) } as usize;
+
+        self.segments[..self.current]
+            .iter()
+            .flat_map(|segment| &segment.registers[..segment.top])
+            .chain(&current.registers[..len])
+            .copied()
+    }
+}
@@ -0,0 +1,49 @@
+use crate::{
+    diagnostics::error::Error,
+    runtime::{value::Value, vm::VmState},
+    util::string_interner::StringIndex,
+};
+
+/// Called by the VM before every instruction while it's attached with
+/// [`VmState::attach_debugger`]. Returning an error stops the program with it.
+pub trait DebugHook {
+    fn before_instruction(&mut self, state: &VmState) -> Result<(), Error>;
+}
+
+/// An active call, as seen through [`VmState::frames`].
+#[derive(Debug)]
+pub struct Frame {
+    /// Index of the running function in [`VmState::functions`].
+    pub function: usize,
+    /// Index of the instruction about to run.
+    pub pc: usize,
+    /// First register of the frame, valid while the call is active.
+    pub(crate) registers: *const Value,
+}
+
+impl Frame {
+    /// Variables in scope at the instruction about to run, in the order they
+    /// were declared. Only the innermost of variables with the same name is
+    /// kept.
+    pub fn locals(&self, state: &VmState) -> Vec<(StringIndex, Value)> {
+        let function = &state.functions()[self.function];
+        let mut locals: Vec<(StringIndex, Value)> = Vec::new();
+
+        for local in &function.locals {
+            if !local.range.contains(&self.pc) {
+                continue;
+            }
+
+            let mut value = unsafe { *self.registers.add(local.register as usize) };
+
+            if local.is_cell {
+                value = state.gc().get_cell(value);
+            }
+
+            locals.retain(|&(name, _)| name != local.name);
+            locals.push((local.name, value));
+        }
+
+        locals
+    }
+}
@@ -1,19 +1,30 @@
-use std::fmt;
+use std::{cell::RefCell, fmt};
 
-use crate::{
-    program::INTERNER,
-    runtime::{gc::Gc, value::Value},
-    util::string_interner::StringIndex,
-};
+use crate::runtime::{gc::Gc, value::Value};
 
 pub struct DebugValue<'a> {
     value: Value,
     gc: &'a Gc,
+    /// Vecs and dicts being printed around this value, a container found
+    /// again in it is a cycle and is printed as `[...]` or `{...}`.
+    path: Option<&'a RefCell<Vec<Value>>>,
 }
 
 impl<'a> DebugValue<'a> {
     pub fn new(value: Value, gc: &'a Gc) -> Self {
-        Self { value, gc }
+        Self {
+            value,
+            gc,
+            path: None,
+        }
+    }
+
+    fn nested(&self, value: Value, path: &'a RefCell<Vec<Value>>) -> Self {
+        Self {
+            value,
+            gc: self.gc,
+            path: Some(path),
+        }
     }
 }
 
@@ -22,32 +33,62 @@ impl<'a> fmt::Debug for DebugValue<'a> {
         if self.value.is_number() {
             return write!(f, "{}", self.value.as_number());
         }
+        if self.value.is_nil() {
+            return write!(f, "nil");
+        }
+        if self.value.is_bool() {
+            return write!(f, "{}", self.value.as_bool());
+        }
         if self.value.is_closure() {
             return write!(f, "Closure({:p})", self.gc.get_closure(self.value));
         }
+        if self.value.is_native() {
+            return write!(f, "NativeFunction({})", self.value.as_index());
+        }
         if self.value.is_string() {
-            let index = StringIndex(self.value.as_index() as u32);
+            return write!(f, "{}", self.gc.get_str(self.value));
+        }
+
+        let Some(path) = self.path else {
+            let path = RefCell::new(Vec::new());
 
-            return write!(f, "{}", INTERNER.lock().unwrap().resolve(index));
+            return DebugValue {
+                value: self.value,
+                gc: self.gc,
+                path: Some(&path),
+            }
+            .fmt(f);
+        };
+
+        if path.borrow().contains(&self.value) {
+            return if self.value.is_vec() {
+                write!(f, "[...]")
+            } else {
+                write!(f, "{{...}}")
+            };
         }
-        if self.value.is_vec() {
+
+        path.borrow_mut().push(self.value);
+
+        let result = if self.value.is_vec() {
             let mut list = f.debug_list();
             for &value in self.gc.get_vec(self.value) {
-                list.entry(&DebugValue::new(value, self.gc));
+                list.entry(&self.nested(value, path));
             }
-            return list.finish();
-        }
-        if self.value.is_dict() {
+            list.finish()
+        } else if self.value.is_dict() {
             let mut map = f.debug_map();
 
             for (&key, &val) in self.gc.get_dict(self.value) {
-                map.entry(
-                    &DebugValue::new(key, self.gc),
-                    &DebugValue::new(val, self.gc),
-                );
+                map.entry(&self.nested(key, path), &self.nested(val, path));
             }
-            return map.finish();
-        }
-        unsafe { std::hint::unreachable_unchecked() }
+            map.finish()
+        } else {
+            unsafe { std::hint::unreachable_unchecked() }
+        };
+
+        path.borrow_mut().pop();
+
+        result
     }
 }
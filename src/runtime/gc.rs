@@ -2,31 +2,56 @@ use std::hint::unreachable_unchecked;
 
 use foldhash::HashMap;
 
-use crate::bytecode::instruction::Instruction;
+use crate::{
+    bytecode::instruction::Instruction, program::INTERNER, util::string_interner::StringIndex,
+};
 
 use super::value::Value;
 
+const INITIAL_THRESHOLD: usize = 1024;
+
 pub struct Closure {
     pub instructions: *const Instruction,
     pub arity: u8,
-    pub size: u8,
+    pub size: u16,
     pub captured: Vec<Value>,
 }
 
 enum Object {
+    String(String),
     Vec(Vec<Value>),
     Dict(HashMap<Value, Value>),
     Closure(Closure),
+    Cell(Value),
+    Free,
 }
 
-#[derive(Default)]
 pub struct Gc {
     objects: Vec<Object>,
+    marks: Vec<bool>,
     free_list: Vec<usize>,
+    gray: Vec<usize>,
+    allocations: usize,
+    threshold: usize,
+}
+
+impl Default for Gc {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            marks: Vec::new(),
+            free_list: Vec::new(),
+            gray: Vec::new(),
+            allocations: 0,
+            threshold: INITIAL_THRESHOLD,
+        }
+    }
 }
 
 impl Gc {
     fn alloc(&mut self, object: Object) -> usize {
+        self.allocations += 1;
+
         if let Some(index) = self.free_list.pop() {
             self.objects[index] = object;
 
@@ -34,10 +59,108 @@ impl Gc {
         } else {
             let index = self.objects.len();
             self.objects.push(object);
+            self.marks.push(false);
             index
         }
     }
 
+    pub fn should_collect(&self) -> bool {
+        self.allocations >= self.threshold
+    }
+
+    pub fn live_objects(&self) -> usize {
+        self.objects.len() - self.free_list.len()
+    }
+
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        for root in roots {
+            self.mark_value(root);
+        }
+
+        while let Some(index) = self.gray.pop() {
+            self.trace(index);
+        }
+
+        self.sweep();
+
+        self.allocations = 0;
+        self.threshold = INITIAL_THRESHOLD.max(self.live_objects() * 2);
+    }
+
+    fn mark_value(&mut self, value: Value) {
+        if !(value.is_closure()
+            || value.is_dict()
+            || value.is_vec()
+            || value.is_cell()
+            || value.is_heap_string())
+        {
+            return;
+        }
+
+        let index = value.as_index();
+
+        // Roots are scanned conservatively, so stale register values may point to
+        // slots that were already swept and must be ignored.
+        if index >= self.objects.len() || self.marks[index] {
+            return;
+        }
+
+        if let Object::Free = self.objects[index] {
+            return;
+        }
+
+        self.marks[index] = true;
+        self.gray.push(index);
+    }
+
+    fn trace(&mut self, index: usize) {
+        let object = std::mem::replace(&mut self.objects[index], Object::Free);
+
+        match &object {
+            Object::Vec(values) => {
+                for value in values.iter().copied() {
+                    self.mark_value(value);
+                }
+            }
+            Object::Dict(dict) => {
+                for (key, value) in dict.iter() {
+                    self.mark_value(*key);
+                    self.mark_value(*value);
+                }
+            }
+            Object::Closure(closure) => {
+                for value in closure.captured.iter().copied() {
+                    self.mark_value(value);
+                }
+            }
+            Object::Cell(value) => self.mark_value(*value),
+            Object::String(_) | Object::Free => {}
+        }
+
+        self.objects[index] = object;
+    }
+
+    fn sweep(&mut self) {
+        for (index, object) in self.objects.iter_mut().enumerate() {
+            if std::mem::take(&mut self.marks[index]) {
+                continue;
+            }
+
+            if let Object::Free = object {
+                continue;
+            }
+
+            *object = Object::Free;
+            self.free_list.push(index);
+        }
+    }
+
+    pub fn allocate_string(&mut self, string: String) -> Value {
+        let index = self.alloc(Object::String(string));
+
+        Value::heap_string(index)
+    }
+
     pub fn allocate_dict(&mut self) -> Value {
         let object = Object::Dict(HashMap::default());
         let index = self.alloc(object);
@@ -58,6 +181,12 @@ impl Gc {
         Value::closure(index)
     }
 
+    pub fn allocate_cell(&mut self, value: Value) -> Value {
+        let index = self.alloc(Object::Cell(value));
+
+        Value::cell(index)
+    }
+
     pub fn get_mut_closure(&mut self, value: Value) -> &mut Closure {
         let index = value.as_index();
 
@@ -85,6 +214,31 @@ impl Gc {
         }
     }
 
+    pub fn get_mut_cell(&mut self, value: Value) -> &mut Value {
+        let index = value.as_index();
+
+        match &mut self.objects[index] {
+            Object::Cell(v) => v,
+            _ => unsafe { unreachable_unchecked() },
+        }
+    }
+
+    pub fn get_str(&self, value: Value) -> &str {
+        if !value.is_heap_string() {
+            let index = StringIndex(value.as_index() as u32);
+
+            let string: *const str = INTERNER.lock().unwrap().resolve(index);
+
+            // SAFETY: the global interner never removes or moves its strings.
+            return unsafe { &*string };
+        }
+
+        match &self.objects[value.as_index()] {
+            Object::String(s) => s,
+            _ => unsafe { unreachable_unchecked() },
+        }
+    }
+
     pub fn get_vec(&self, value: Value) -> &Vec<Value> {
         let index = value.as_index();
 
@@ -103,6 +257,15 @@ impl Gc {
         }
     }
 
+    pub fn get_cell(&self, value: Value) -> Value {
+        let index = value.as_index();
+
+        match &self.objects[index] {
+            Object::Cell(v) => *v,
+            _ => unsafe { unreachable_unchecked() },
+        }
+    }
+
     pub fn get_closure(&self, value: Value) -> &Closure {
         let index = value.as_index();
 
@@ -112,3 +275,37 @@ impl Gc {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreachable_objects_are_freed() {
+        let mut gc = Gc::default();
+        let kept = gc.allocate_vec();
+        let element = gc.allocate_dict();
+        gc.get_mut_vec(kept).push(element);
+
+        let cell = gc.allocate_cell(Value::nil());
+        *gc.get_mut_cell(cell) = cell;
+        gc.allocate_string(String::from("garbage"));
+
+        gc.collect([kept, Value::number(1.0)]);
+
+        assert_eq!(gc.live_objects(), 2);
+        assert!(gc.get_dict(element).is_empty());
+    }
+
+    #[test]
+    fn freed_slots_are_reused() {
+        let mut gc = Gc::default();
+        let garbage = gc.allocate_vec();
+
+        gc.collect([]);
+        let reused = gc.allocate_dict();
+
+        assert_eq!(reused.as_index(), garbage.as_index());
+        assert_eq!(gc.live_objects(), 1);
+    }
+}
@@ -1,3 +1,7 @@
+pub mod call_stack;
+
+pub mod debug_hook;
+
 pub mod debug_value;
 
 pub mod gc;
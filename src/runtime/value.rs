@@ -1,11 +1,21 @@
 use crate::util::string_interner::StringIndex;
 
+const SIGN: u64 = 0x8000_0000_0000_0000;
 const QNAN: u64 = 0x7FFC_0000_0000_0000;
 const PTR_MASK: u64 = 0x0000_FFFF_FFFF_FFFF;
+const INDEX_MASK: u64 = 0x0000_7FFF_FFFF_FFFF;
+// Strings share one tag; this payload bit marks GC-owned strings, the rest are interned.
+const HEAP_STRING: u64 = 0x0000_8000_0000_0000;
 const TAG_CLOSURE: u64 = QNAN | 0x0003_0000_0000_0000;
 const TAG_STRING: u64 = QNAN | 0x0004_0000_0000_0000;
 const TAG_DICT: u64 = QNAN | 0x0005_0000_0000_0000;
 const TAG_VEC: u64 = QNAN | 0x0006_0000_0000_0000;
+const TAG_NIL: u64 = SIGN | QNAN;
+const TAG_BOOL: u64 = SIGN | QNAN | 0x0001_0000_0000_0000;
+const TAG_NATIVE: u64 = SIGN | QNAN | 0x0002_0000_0000_0000;
+// Cells hold captured variables that are assigned after capture; they never leave
+// the registers of the function that owns them.
+const TAG_CELL: u64 = SIGN | QNAN | 0x0003_0000_0000_0000;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 #[repr(transparent)]
@@ -13,7 +23,7 @@ pub struct Value(u64);
 
 impl Default for Value {
     fn default() -> Self {
-        Value::number(0.0)
+        Value::nil()
     }
 }
 
@@ -26,6 +36,14 @@ impl Value {
         (self.0 & !PTR_MASK) == tag
     }
 
+    pub fn is_nil(self) -> bool {
+        self.0 == TAG_NIL
+    }
+
+    pub fn is_bool(self) -> bool {
+        self.is_tag(TAG_BOOL)
+    }
+
     pub fn is_closure(self) -> bool {
         self.is_tag(TAG_CLOSURE)
     }
@@ -34,6 +52,10 @@ impl Value {
         self.is_tag(TAG_STRING)
     }
 
+    pub fn is_heap_string(self) -> bool {
+        self.is_string() && (self.0 & HEAP_STRING) != 0
+    }
+
     pub fn is_vec(self) -> bool {
         self.is_tag(TAG_VEC)
     }
@@ -42,10 +64,40 @@ impl Value {
         self.is_tag(TAG_DICT)
     }
 
+    pub fn is_native(self) -> bool {
+        self.is_tag(TAG_NATIVE)
+    }
+
+    pub fn is_cell(self) -> bool {
+        self.is_tag(TAG_CELL)
+    }
+
     pub fn tag(self) -> u64 {
         self.0 & !PTR_MASK
     }
 
+    pub fn nil() -> Self {
+        Self(TAG_NIL)
+    }
+
+    pub fn boolean(value: bool) -> Self {
+        Self(TAG_BOOL | value as u64)
+    }
+
+    pub fn as_bool(self) -> bool {
+        (self.0 & 1) != 0
+    }
+
+    /// Numbers compare by value, everything else by identity; heap strings need the
+    /// `Gc` to compare contents.
+    pub fn equals(self, other: Value) -> bool {
+        if self.is_number() && other.is_number() {
+            self.as_number() == other.as_number()
+        } else {
+            self == other
+        }
+    }
+
     pub fn number(value: f64) -> Self {
         Self(value.to_bits())
     }
@@ -58,6 +110,10 @@ impl Value {
         Self(TAG_STRING | (index.0 as u64))
     }
 
+    pub fn heap_string(index: usize) -> Self {
+        Self(TAG_STRING | HEAP_STRING | (index as u64))
+    }
+
     pub fn closure(index: usize) -> Self {
         Self(TAG_CLOSURE | (index as u64))
     }
@@ -70,7 +126,15 @@ impl Value {
         Self(TAG_VEC | (index as u64))
     }
 
+    pub fn native(index: usize) -> Self {
+        Self(TAG_NATIVE | (index as u64))
+    }
+
+    pub fn cell(index: usize) -> Self {
+        Self(TAG_CELL | (index as u64))
+    }
+
     pub fn as_index(self) -> usize {
-        (self.0 & PTR_MASK) as usize
+        (self.0 & INDEX_MASK) as usize
     }
 }
@@ -1,13 +1,21 @@
+use std::cmp::Ordering;
 use std::hint::unreachable_unchecked;
+use std::rc::{Rc, Weak};
 
+use foldhash::HashMap;
+
+use super::call_stack::CallStack;
+use super::debug_hook::{DebugHook, Frame};
 use super::gc::Gc;
 use crate::bytecode::Function;
-use crate::diagnostics::error::Error;
+use crate::diagnostics::error::{Error, TraceFrame};
+use crate::program::INTERNER;
 
 use crate::report_error;
 
-use crate::runtime::debug_value::DebugValue;
 use crate::runtime::gc::Closure;
+use crate::std::native_functions::{NativeFunction, NativeRegistry, vec_index};
+use crate::util::string_interner::StringIndex;
 use crate::{bytecode::instruction::Instruction, runtime::value::Value};
 
 type Handler = unsafe extern "rust-preserve-none" fn(
@@ -15,71 +23,100 @@ type Handler = unsafe extern "rust-preserve-none" fn(
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>>;
 
-static HANDLERS: [Handler; 53] = [
-    opcode_add_rr,
-    opcode_add_rk,
-    opcode_subtract_rr,
-    opcode_subtract_rk,
-    opcode_subtract_kr,
-    opcode_multiply_rr,
-    opcode_multiply_rk,
-    opcode_divide_rr,
-    opcode_divide_rk,
-    opcode_divide_kr,
-    opcode_modulo_rr,
-    opcode_modulo_rk,
-    opcode_modulo_kr,
-    opcode_equal_rr,
-    opcode_equal_rk,
-    opcode_not_equal_rr,
-    opcode_not_equal_rk,
-    opcode_less_rr,
-    opcode_less_rk,
-    opcode_less_equal_rr,
-    opcode_less_equal_rk,
-    opcode_greater_rr,
-    opcode_greater_rk,
-    opcode_greater_equal_rr,
-    opcode_greater_equal_rk,
-    opcode_not,
-    opcode_negate,
-    opcode_move,
-    opcode_move_arg,
-    opcode_load_k,
-    opcode_create_dict,
-    opcode_set_field,
-    opcode_get_field,
-    opcode_create_closure,
-    opcode_capture_value,
-    opcode_call,
-    opcode_return,
-    opcode_jump,
-    opcode_jump_if_false,
-    opcode_jump_if_true,
-    opcode_jump_if_less_rr,
-    opcode_jump_if_less_rk,
-    opcode_jump_if_less_equal_rr,
-    opcode_jump_if_less_equal_rk,
-    opcode_jump_if_greater_rr,
-    opcode_jump_if_greater_rk,
-    opcode_jump_if_greater_equal_rr,
-    opcode_jump_if_greater_equal_rk,
-    opcode_jump_if_equal_rr,
-    opcode_jump_if_equal_rk,
-    opcode_jump_if_not_equal_rr,
-    opcode_jump_if_not_equal_rk,
-    opcode_nop,
-];
+/// Handlers of every instruction, in the order of their discriminants. The
+/// `DEBUG` handlers call the attached [`DebugHook`] before every instruction,
+/// the others are compiled as if debuggers didn't exist.
+const fn handlers<const DEBUG: bool>() -> [Handler; 64] {
+    [
+        opcode_add_rr::<DEBUG>,
+        opcode_add_rk::<DEBUG>,
+        opcode_subtract_rr::<DEBUG>,
+        opcode_subtract_rk::<DEBUG>,
+        opcode_subtract_kr::<DEBUG>,
+        opcode_multiply_rr::<DEBUG>,
+        opcode_multiply_rk::<DEBUG>,
+        opcode_divide_rr::<DEBUG>,
+        opcode_divide_rk::<DEBUG>,
+        opcode_divide_kr::<DEBUG>,
+        opcode_modulo_rr::<DEBUG>,
+        opcode_modulo_rk::<DEBUG>,
+        opcode_modulo_kr::<DEBUG>,
+        opcode_equal_rr::<DEBUG>,
+        opcode_equal_rk::<DEBUG>,
+        opcode_not_equal_rr::<DEBUG>,
+        opcode_not_equal_rk::<DEBUG>,
+        opcode_less_rr::<DEBUG>,
+        opcode_less_rk::<DEBUG>,
+        opcode_less_equal_rr::<DEBUG>,
+        opcode_less_equal_rk::<DEBUG>,
+        opcode_greater_rr::<DEBUG>,
+        opcode_greater_rk::<DEBUG>,
+        opcode_greater_equal_rr::<DEBUG>,
+        opcode_greater_equal_rk::<DEBUG>,
+        opcode_not::<DEBUG>,
+        opcode_negate::<DEBUG>,
+        opcode_move::<DEBUG>,
+        opcode_move_arg::<DEBUG>,
+        opcode_load_k::<DEBUG>,
+        opcode_load_k_wide::<DEBUG>,
+        opcode_create_dict::<DEBUG>,
+        opcode_set_field::<DEBUG>,
+        opcode_get_field::<DEBUG>,
+        opcode_get_index::<DEBUG>,
+        opcode_set_index::<DEBUG>,
+        opcode_create_vec::<DEBUG>,
+        opcode_vec_push::<DEBUG>,
+        opcode_create_closure::<DEBUG>,
+        opcode_capture_value::<DEBUG>,
+        opcode_create_cell::<DEBUG>,
+        opcode_get_cell::<DEBUG>,
+        opcode_set_cell::<DEBUG>,
+        opcode_call::<DEBUG>,
+        opcode_tail_call::<DEBUG>,
+        opcode_return::<DEBUG>,
+        opcode_jump::<DEBUG>,
+        opcode_jump_if_false::<DEBUG>,
+        opcode_jump_if_true::<DEBUG>,
+        opcode_jump_if_less_rr::<DEBUG>,
+        opcode_jump_if_less_rk::<DEBUG>,
+        opcode_jump_if_less_equal_rr::<DEBUG>,
+        opcode_jump_if_less_equal_rk::<DEBUG>,
+        opcode_jump_if_greater_rr::<DEBUG>,
+        opcode_jump_if_greater_rk::<DEBUG>,
+        opcode_jump_if_greater_equal_rr::<DEBUG>,
+        opcode_jump_if_greater_equal_rk::<DEBUG>,
+        opcode_jump_if_equal_rr::<DEBUG>,
+        opcode_jump_if_equal_rk::<DEBUG>,
+        opcode_jump_if_not_equal_rr::<DEBUG>,
+        opcode_jump_if_not_equal_rk::<DEBUG>,
+        opcode_for_prep::<DEBUG>,
+        opcode_for_loop::<DEBUG>,
+        opcode_nop::<DEBUG>,
+    ]
+}
+
+static HANDLERS: [Handler; 64] = handlers::<false>();
+static DEBUG_HANDLERS: [Handler; 64] = handlers::<true>();
+
+#[inline(always)]
+fn table<const DEBUG: bool>() -> &'static [Handler; 64] {
+    if DEBUG { &DEBUG_HANDLERS } else { &HANDLERS }
+}
 
 macro_rules! dispatch_next {
     ($ip:expr, $registers:expr, $constants:expr, $state:expr, $frame_size:expr) => {
         unsafe {
             let ip: *const Instruction = $ip.add(1);
+
+            if DEBUG {
+                $state.debug_hook(ip, &$registers)?;
+            }
+
             let index = (*ip).discriminant();
-            let handler = *HANDLERS.get_unchecked(index);
+            let handler = *table::<DEBUG>().get_unchecked(index);
 
             become handler(ip, $registers, $constants, $state, $frame_size);
         }
@@ -90,8 +127,13 @@ macro_rules! dispatch_offset {
     ($ip:expr, $registers:expr, $constants:expr, $state:expr, $frame_size:expr, $offset:expr) => {
         unsafe {
             let ip: *const Instruction = $ip.offset($offset as isize);
+
+            if DEBUG {
+                $state.debug_hook(ip, &$registers)?;
+            }
+
             let index = (*ip).discriminant();
-            let handler = *HANDLERS.get_unchecked(index);
+            let handler = *table::<DEBUG>().get_unchecked(index);
 
             become handler(ip, $registers, $constants, $state, $frame_size);
         }
@@ -99,60 +141,550 @@ macro_rules! dispatch_offset {
 }
 
 macro_rules! type_check {
-    ($cond:expr, $($arg:tt)*) => {{
+    ($state:expr, $ip:expr, $cond:expr, $($arg:tt)*) => {{
         if std::hint::unlikely(!$cond) {
-            return Err(Box::new(report_error!($($arg)*)));
+            return Err($state.runtime_error($ip, report_error!($($arg)*)));
         }
     }};
 }
 
-pub fn run_vm(functions: Vec<Function>, constants: Vec<Value>) -> Result<Value, Error> {
-    let Function {
-        ref instructions,
-        registers_count,
-        ..
-    } = functions[0];
-
-    let ip = instructions.as_ptr();
-    let index = unsafe { (*ip).discriminant() };
-    let frame_size = registers_count;
-
-    let mut registers = [Value::default(); 4096];
-    let registers = Registers(&mut registers);
-    let constants = Constants(constants.as_ptr());
-
-    let mut state = VmState::new(functions);
+/// Nested calls allowed before a stack overflow is reported. Every Kaori
+/// call also takes a native stack frame, and this keeps the interpreter well
+/// within the stack of the main thread.
+pub const DEFAULT_MAX_DEPTH: usize = 10_000;
 
-    let value = unsafe {
-        HANDLERS[index](ip, registers, constants, &mut state, frame_size).map_err(|e| *e)?
-    };
-
-    //println!("{:?}", DebugValue::new(value, &state.gc));
-    Ok(value)
-}
+/// Native stack kept free below the deepest call, for the natives and the
+/// error reporting that run on top of it.
+const NATIVE_STACK_RESERVE: usize = 128 * 1024;
 
 pub struct VmState {
     functions: Vec<Function>,
+    constants: Vec<Value>,
+    natives: Vec<NativeFunction>,
+    stack: CallStack,
+    /// Calls made from the outermost frame that haven't returned yet.
+    depth: usize,
+    max_depth: usize,
+    /// Lowest native stack address a call may start at, so that threads too
+    /// small for `max_depth` calls overflow with an error instead of a crash.
+    stack_limit: usize,
+    /// Values handed out to the host, kept alive while their pin is.
+    pinned: HashMap<Value, Weak<()>>,
     gc: Gc,
+    debugger: Option<Box<dyn DebugHook>>,
+    /// Active calls, outermost first, kept only while a debugger is attached.
+    frames: Vec<Frame>,
 }
 
 impl VmState {
-    pub fn new(functions: Vec<Function>) -> Self {
+    pub fn new(natives: &NativeRegistry) -> Self {
         Self {
-            functions,
+            functions: Vec::new(),
+            constants: Vec::new(),
+            natives: natives.functions().to_vec(),
+            stack: CallStack::default(),
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            stack_limit: 0,
+            pinned: HashMap::default(),
             gc: Gc::default(),
+            debugger: None,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
+    /// Name of the closure or native function `callee`, `None` when it was
+    /// declared without one.
+    pub fn function_name(&self, callee: Value) -> Option<StringIndex> {
+        if callee.is_native() {
+            return Some(self.natives[callee.as_index()].name);
+        }
+
+        let instructions = self.gc.get_closure(callee).instructions;
+
+        self.functions
+            .iter()
+            .find(|function| function.instructions.as_ptr() == instructions)
+            .and_then(|function| function.name)
+    }
+
+    /// Appends newly compiled functions and replaces the constant pool, which
+    /// must extend the previous one so that loaded code keeps its indexes.
+    pub fn load(&mut self, functions: Vec<Function>, constants: Vec<Value>) {
+        debug_assert!(constants.starts_with(&self.constants));
+
+        self.functions.extend(functions);
+        self.constants = constants;
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Registers at the bottom of the stack, where the outermost frame of
+    /// [`VmState::run`] keeps its values between runs.
+    pub fn registers(&mut self) -> &mut [Value] {
+        self.stack.base()
+    }
+
+    /// Grows the bottom of the stack to hold at least `count` registers.
+    pub fn reserve_registers(&mut self, count: usize) {
+        self.stack.reserve_base(count);
+    }
+
+    /// Calls `debugger` before every instruction of the functions run from
+    /// now on. Without one the VM runs handlers that never check for it.
+    pub fn attach_debugger(&mut self, debugger: Box<dyn DebugHook>) {
+        self.debugger = Some(debugger);
+    }
+
+    /// Active calls, outermost first, while the attached debugger is called.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Keeps `value` alive until the returned pin and all its clones are
+    /// dropped. Pinning a value again shares the existing pin.
+    pub fn pin(&mut self, value: Value) -> Rc<()> {
+        if let Some(pin) = self.pinned.get(&value).and_then(Weak::upgrade) {
+            return pin;
+        }
+
+        let pin = Rc::new(());
+        self.pinned.insert(value, Rc::downgrade(&pin));
+
+        pin
+    }
+
+    /// Runs a loaded function as the outermost frame, with its registers at the
+    /// bottom of the stack.
+    pub fn run(&mut self, function: usize) -> Result<Value, Error> {
+        let Function {
+            ref instructions,
+            registers_count,
+            ..
+        } = self.functions[function];
+
+        // The frame's registers, and the arguments of the calls it makes.
+        self.stack
+            .reserve_base(registers_count as usize + u8::MAX as usize);
+        self.stack_limit = native_stack_limit();
+
+        let ip = instructions.as_ptr();
+
+        let registers = self.base_registers(0);
+        let constants = Constants(self.constants.as_ptr());
+
+        // The registers of the outermost frame keep the globals of earlier runs.
+        let size = registers_count;
+
+        let result = if self.debugger.is_some() {
+            unsafe { enter::<true>(ip, registers, constants, self, size, size as usize) }
+        } else {
+            unsafe { enter::<false>(ip, registers, constants, self, size, size as usize) }
+        };
+
+        self.stack.shrink();
+
+        result.map_err(|error| *error)
+    }
+
+    /// Calls a closure or native function from the host. The callee's frame
+    /// starts at `base`, above the registers of the outermost frame.
+    ///
+    /// Only for calls made while no frame is running, reserving the first
+    /// segment can move the registers of running frames.
+    pub(crate) fn call(
+        &mut self,
+        base: usize,
+        callee: Value,
+        arguments: &[Value],
+    ) -> Result<Value, Error> {
+        if arguments.len() > u8::MAX as usize {
+            return Err(report_error!("a call can't have more than 255 arguments"));
+        }
+
+        if callee.is_native() {
+            let NativeFunction {
+                name,
+                arity,
+                function,
+            } = self.natives[callee.as_index()];
+
+            if arguments.len() != arity as usize {
+                let name = INTERNER.lock().unwrap().resolve(name).to_owned();
+
+                return Err(report_error!(
+                    "native function `{}` expects {} arguments, but received {}",
+                    name,
+                    arity,
+                    arguments.len()
+                ));
+            }
+
+            return function(arguments, self);
+        }
+
+        if !callee.is_closure() {
+            return Err(report_error!("cannot call, value is not a function"));
+        }
+
+        let Closure {
+            instructions,
+            arity,
+            size,
+            ref captured,
+        } = *self.gc.get_closure(callee);
+
+        if arguments.len() != arity as usize {
+            return Err(report_error!(
+                "the number of arguments must match the number of parameters in a function call"
+            ));
+        }
+
+        self.stack
+            .reserve_base(base + size as usize + u8::MAX as usize);
+        self.stack_limit = native_stack_limit();
+
+        let registers = &mut self.stack.base()[base..];
+
+        registers[..arguments.len()].copy_from_slice(arguments);
+
+        for (i, value) in captured.iter().copied().enumerate() {
+            registers[arity as usize + i] = value;
+        }
+
+        let initialized = arity as usize + captured.len();
+        let registers = self.base_registers(base);
+        let constants = Constants(self.constants.as_ptr());
+
+        let result = if self.debugger.is_some() {
+            unsafe { enter::<true>(instructions, registers, constants, self, size, initialized) }
+        } else {
+            unsafe { enter::<false>(instructions, registers, constants, self, size, initialized) }
+        };
+
+        self.stack.shrink();
+
+        result.map_err(|error| *error)
+    }
+
+    /// Registers of a frame starting at `base` in the first segment. They are
+    /// detached from `self` because handlers receive both.
+    fn base_registers<'a>(&mut self, base: usize) -> Registers<'a> {
+        let registers = &mut self.stack.base()[base..];
+
+        Registers(unsafe {
+            std::slice::from_raw_parts_mut(registers.as_mut_ptr(), registers.len())
+        })
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn stack_overflow(&self, ip: *const Instruction) -> Box<Error> {
+        let error = if self.depth == self.max_depth {
+            report_error!("stack overflow, more than {} nested calls", self.max_depth)
+        } else {
+            report_error!(
+                "stack overflow, the native stack ran out after {} nested calls",
+                self.depth
+            )
+        };
+
+        self.runtime_error(ip, error)
+    }
+
+    /// Whether another call would go past the maximum depth or the native
+    /// stack of the thread.
+    #[inline(always)]
+    fn call_overflows(&self) -> bool {
+        self.depth == self.max_depth || stack_address() < self.stack_limit
+    }
+
+    pub fn gc(&self) -> &Gc {
+        &self.gc
+    }
+
+    pub fn gc_mut(&mut self) -> &mut Gc {
+        &mut self.gc
+    }
+
+    pub fn values_equal(&self, a: Value, b: Value) -> bool {
+        if (a.is_heap_string() || b.is_heap_string()) && a.is_string() && b.is_string() {
+            self.gc.get_str(a) == self.gc.get_str(b)
+        } else {
+            a.equals(b)
+        }
+    }
+
+    /// Heap strings are interned before being used as dict keys so that keys
+    /// hash and compare by their contents.
+    pub fn dict_key(&self, key: Value) -> Value {
+        if key.is_heap_string() {
+            let contents = self.gc.get_str(key);
+            let index = INTERNER.lock().unwrap().get_or_intern(contents);
+
+            Value::string(index)
+        } else {
+            key
+        }
+    }
+
+    /// Index of the function executing `ip`, and of the instruction in it.
+    fn position(&self, ip: *const Instruction) -> (usize, usize) {
+        let index = self
+            .functions
+            .iter()
+            .position(|function| function.instructions.as_ptr_range().contains(&ip))
+            .expect("instruction pointer must belong to a function");
+
+        let offset = unsafe { ip.offset_from(self.functions[index].instructions.as_ptr()) };
+
+        (index, offset as usize)
+    }
+
+    /// Finds the function executing `ip` and the source span of that instruction.
+    fn locate(&self, ip: *const Instruction) -> TraceFrame {
+        let (index, offset) = self.position(ip);
+        let function = &self.functions[index];
+
+        let name = match function.name {
+            Some(name) => INTERNER.lock().unwrap().resolve(name).to_owned(),
+            None if function.is_script => String::from("<script>"),
+            None => String::from("<anonymous>"),
+        };
+
+        TraceFrame {
+            function: name,
+            span: function.spans[offset].clone(),
+        }
+    }
+
+    /// Attaches the span of the failing instruction and the innermost frame
+    /// to an error raised by a handler.
+    #[cold]
+    #[inline(never)]
+    fn runtime_error(&self, ip: *const Instruction, mut error: Error) -> Box<Error> {
+        let frame = self.locate(ip);
+
+        if error.span.is_none() {
+            error.span = frame.span.clone();
+        }
+
+        error.trace.push(frame);
+
+        Box::new(error)
+    }
+
+    /// Records the frame about to run `ip` and calls the attached debugger.
+    #[cold]
+    #[inline(never)]
+    fn debug_hook(
+        &mut self,
+        ip: *const Instruction,
+        registers: &Registers,
+    ) -> Result<(), Box<Error>> {
+        let Some(mut debugger) = self.debugger.take() else {
+            return Ok(());
+        };
+
+        let (function, pc) = self.position(ip);
+
+        // Frames deeper than the running one have returned.
+        self.frames.truncate(self.depth);
+        self.frames.push(Frame {
+            function,
+            pc,
+            registers: registers.0.as_ptr(),
+        });
+
+        let result = debugger.before_instruction(self);
+        self.debugger = Some(debugger);
+
+        result.map_err(|error| self.runtime_error(ip, error))
+    }
+
+    /// Records the caller's frame while an error unwinds through a call.
+    #[cold]
+    #[inline(never)]
+    fn unwind_call(&self, ip: *const Instruction, mut error: Box<Error>) -> Box<Error> {
+        error.trace.push(self.locate(ip));
+
+        error
+    }
+
+    fn collect_garbage(&mut self, registers: &Registers, frame_size: u16) {
+        if !self.gc.should_collect() {
+            return;
         }
+
+        // Arguments of a call under construction live right above the frame,
+        // so the scanned window extends by the maximum arity.
+        let window = (frame_size as usize + u8::MAX as usize).min(registers.0.len());
+
+        let top = unsafe { registers.0.as_ptr().add(window) };
+
+        self.pinned.retain(|_, pin| pin.strong_count() > 0);
+
+        let roots = self
+            .stack
+            .roots(top)
+            .chain(self.constants.iter().copied())
+            .chain(self.pinned.keys().copied());
+
+        self.gc.collect(roots);
+    }
+}
+
+/// Approximate address of the top of the native stack.
+#[inline(always)]
+fn stack_address() -> usize {
+    let marker = 0u8;
+
+    std::hint::black_box(&marker) as *const u8 as usize
+}
+
+/// Native stack address calls must stay above on the current thread, `0`
+/// when its size is unknown and only the maximum depth applies.
+fn native_stack_limit() -> usize {
+    match stacker::remaining_stack() {
+        Some(remaining) => (stack_address() - remaining).saturating_add(NATIVE_STACK_RESERVE),
+        None => 0,
+    }
+}
+
+/// Runs a function from its first instruction. Under a debugger the registers
+/// past the `initialized` ones start as nil, so variables shown before they
+/// are assigned don't hold values left over from earlier calls.
+#[inline(always)]
+unsafe fn enter<const DEBUG: bool>(
+    instructions: *const Instruction,
+    mut registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    size: u16,
+    initialized: usize,
+) -> Result<Value, Box<Error>> {
+    if DEBUG {
+        registers.clear(initialized, size);
+        state.debug_hook(instructions, &registers)?;
+    }
+
+    let index = unsafe { (*instructions).discriminant() };
+
+    unsafe { table::<DEBUG>()[index](instructions, registers, constants, state, size) }
+}
+
+/// Calls a closure whose frame doesn't fit in the rest of the current segment
+/// from the start of the next one, where its arguments are copied to.
+#[cold]
+#[inline(never)]
+fn call_in_segment<const DEBUG: bool>(
+    state: &mut VmState,
+    registers: &Registers,
+    constants: Constants,
+    frame_size: u16,
+    closure: Value,
+) -> Result<Value, Box<Error>> {
+    let Closure {
+        instructions,
+        arity,
+        size,
+        ref captured,
+    } = *state.gc.get_closure(closure);
+
+    let start = frame_size as usize;
+    let arguments = &registers.0[start..start + arity as usize];
+    let required = size as usize + u8::MAX as usize;
+
+    let segment = state
+        .stack
+        .push_segment(arguments.as_ptr_range().end, required);
+
+    segment[..arguments.len()].copy_from_slice(arguments);
+
+    for (i, value) in captured.iter().copied().enumerate() {
+        segment[arity as usize + i] = value;
     }
+
+    let callee =
+        Registers(unsafe { std::slice::from_raw_parts_mut(segment.as_mut_ptr(), segment.len()) });
+
+    let initialized = arity as usize + captured.len();
+    let result =
+        unsafe { enter::<DEBUG>(instructions, callee, constants, state, size, initialized) };
+
+    state.stack.pop_segment();
+
+    result
+}
+
+#[cold]
+#[inline(never)]
+fn concat_strings(
+    state: &mut VmState,
+    ip: *const Instruction,
+    registers: &Registers,
+    frame_size: u16,
+    src1: Value,
+    src2: Value,
+) -> Result<Value, Box<Error>> {
+    type_check!(
+        state,
+        ip,
+        src1.is_string() && src2.is_string(),
+        "cannot add, both operands must be numbers or strings",
+    );
+
+    let mut string = String::from(state.gc.get_str(src1));
+    string.push_str(state.gc.get_str(src2));
+
+    state.collect_garbage(registers, frame_size);
+
+    Ok(state.gc.allocate_string(string))
+}
+
+#[cold]
+#[inline(never)]
+fn compare_strings(
+    state: &VmState,
+    ip: *const Instruction,
+    src1: Value,
+    src2: Value,
+) -> Result<Ordering, Box<Error>> {
+    type_check!(
+        state,
+        ip,
+        src1.is_string() && src2.is_string(),
+        "cannot compare, both operands must be numbers or strings",
+    );
+
+    Ok(state.gc.get_str(src1).cmp(state.gc.get_str(src2)))
 }
 
 struct Registers<'a>(pub &'a mut [Value]);
 
 impl<'a> Registers<'a> {
-    fn set_value(&mut self, dest: u8, value: Value) {
+    fn set_value(&mut self, dest: u16, value: Value) {
         unsafe { *self.0.get_unchecked_mut(dest as usize) = value }
     }
 
-    unsafe fn get_value(&self, src: u8) -> Value {
+    /// Sets the registers from `start` up to `end` to nil.
+    fn clear(&mut self, start: usize, end: u16) {
+        if let Some(registers) = self.0.get_mut(start..end as usize) {
+            registers.fill(Value::nil());
+        }
+    }
+
+    unsafe fn get_value(&self, src: u16) -> Value {
         unsafe { *self.0.get_unchecked(src as usize) }
     }
 }
@@ -164,15 +696,19 @@ impl Constants {
     unsafe fn get_value(&self, src: u16) -> Value {
         unsafe { *self.0.add(src as usize) }
     }
+
+    unsafe fn get_wide_value(&self, src: u32) -> Value {
+        unsafe { *self.0.add(src as usize) }
+    }
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_add_rr(
+unsafe extern "rust-preserve-none" fn opcode_add_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::Add { dest, src1, src2 } = *ip else {
@@ -185,23 +721,24 @@ unsafe extern "rust-preserve-none" fn opcode_add_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    type_check!(
-        src1.is_number() && src2.is_number(),
-        "cannot add, both operands must be numbers",
-    );
+    let value = if std::hint::likely(src1.is_number() && src2.is_number()) {
+        Value::number(src1.as_number() + src2.as_number())
+    } else {
+        concat_strings(state, ip, &registers, frame_size, src1, src2)?
+    };
 
-    registers.set_value(dest, Value::number(src1.as_number() + src2.as_number()));
+    registers.set_value(dest, value);
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_add_rk(
+unsafe extern "rust-preserve-none" fn opcode_add_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::AddK { dest, src1, src2 } = *ip else {
@@ -215,8 +752,10 @@ unsafe extern "rust-preserve-none" fn opcode_add_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
-        "cannot add, both operands must be numbers",
+        "cannot add, both operands must be numbers or strings",
     );
 
     registers.set_value(dest, Value::number(src1.as_number() + src2.as_number()));
@@ -225,12 +764,12 @@ unsafe extern "rust-preserve-none" fn opcode_add_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_subtract_rr(
+unsafe extern "rust-preserve-none" fn opcode_subtract_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::Subtract { dest, src1, src2 } = *ip else {
@@ -244,6 +783,8 @@ unsafe extern "rust-preserve-none" fn opcode_subtract_rr(
     let src2 = unsafe { registers.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number() && src2.is_number(),
         "cannot subtract, both operands must be numbers",
     );
@@ -254,12 +795,12 @@ unsafe extern "rust-preserve-none" fn opcode_subtract_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_subtract_rk(
+unsafe extern "rust-preserve-none" fn opcode_subtract_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::SubtractRK { dest, src1, src2 } = *ip else {
@@ -273,6 +814,8 @@ unsafe extern "rust-preserve-none" fn opcode_subtract_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
         "cannot subtract, both operands must be numbers",
     );
@@ -283,12 +826,12 @@ unsafe extern "rust-preserve-none" fn opcode_subtract_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_subtract_kr(
+unsafe extern "rust-preserve-none" fn opcode_subtract_kr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::SubtractKR { dest, src1, src2 } = *ip else {
@@ -302,6 +845,8 @@ unsafe extern "rust-preserve-none" fn opcode_subtract_kr(
     let src2 = unsafe { registers.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src2.is_number(),
         "cannot subtract, both operands must be numbers",
     );
@@ -312,12 +857,12 @@ unsafe extern "rust-preserve-none" fn opcode_subtract_kr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_multiply_rr(
+unsafe extern "rust-preserve-none" fn opcode_multiply_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::Multiply { dest, src1, src2 } = *ip else {
@@ -331,6 +876,8 @@ unsafe extern "rust-preserve-none" fn opcode_multiply_rr(
     let src2 = unsafe { registers.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number() && src2.is_number(),
         "cannot multiply, both operands must be numbers",
     );
@@ -341,12 +888,12 @@ unsafe extern "rust-preserve-none" fn opcode_multiply_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_multiply_rk(
+unsafe extern "rust-preserve-none" fn opcode_multiply_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::MultiplyK { dest, src1, src2 } = *ip else {
@@ -360,6 +907,8 @@ unsafe extern "rust-preserve-none" fn opcode_multiply_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
         "cannot multiply, both operands must be numbers",
     );
@@ -370,12 +919,12 @@ unsafe extern "rust-preserve-none" fn opcode_multiply_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_divide_rr(
+unsafe extern "rust-preserve-none" fn opcode_divide_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::Divide { dest, src1, src2 } = *ip else {
@@ -389,6 +938,8 @@ unsafe extern "rust-preserve-none" fn opcode_divide_rr(
     let src2 = unsafe { registers.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number() && src2.is_number(),
         "cannot divide, both operands must be numbers",
     );
@@ -399,12 +950,12 @@ unsafe extern "rust-preserve-none" fn opcode_divide_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_divide_rk(
+unsafe extern "rust-preserve-none" fn opcode_divide_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::DivideRK { dest, src1, src2 } = *ip else {
@@ -418,6 +969,8 @@ unsafe extern "rust-preserve-none" fn opcode_divide_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
         "cannot divide, both operands must be numbers",
     );
@@ -428,12 +981,12 @@ unsafe extern "rust-preserve-none" fn opcode_divide_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_divide_kr(
+unsafe extern "rust-preserve-none" fn opcode_divide_kr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::DivideKR { dest, src1, src2 } = *ip else {
@@ -447,6 +1000,8 @@ unsafe extern "rust-preserve-none" fn opcode_divide_kr(
     let src2 = unsafe { registers.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src2.is_number(),
         "cannot divide, both operands must be numbers",
     );
@@ -457,12 +1012,12 @@ unsafe extern "rust-preserve-none" fn opcode_divide_kr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_modulo_rr(
+unsafe extern "rust-preserve-none" fn opcode_modulo_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::Modulo { dest, src1, src2 } = *ip else {
@@ -476,6 +1031,8 @@ unsafe extern "rust-preserve-none" fn opcode_modulo_rr(
     let src2 = unsafe { registers.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number() && src2.is_number(),
         "cannot compute modulo, both operands must be numbers",
     );
@@ -486,12 +1043,12 @@ unsafe extern "rust-preserve-none" fn opcode_modulo_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_modulo_rk(
+unsafe extern "rust-preserve-none" fn opcode_modulo_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::ModuloRK { dest, src1, src2 } = *ip else {
@@ -505,6 +1062,8 @@ unsafe extern "rust-preserve-none" fn opcode_modulo_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
         "cannot compute modulo, both operands must be numbers",
     );
@@ -515,12 +1074,12 @@ unsafe extern "rust-preserve-none" fn opcode_modulo_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_modulo_kr(
+unsafe extern "rust-preserve-none" fn opcode_modulo_kr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::ModuloKR { dest, src1, src2 } = *ip else {
@@ -534,6 +1093,8 @@ unsafe extern "rust-preserve-none" fn opcode_modulo_kr(
     let src2 = unsafe { registers.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src2.is_number(),
         "cannot compute modulo, both operands must be numbers",
     );
@@ -544,12 +1105,12 @@ unsafe extern "rust-preserve-none" fn opcode_modulo_kr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_equal_rr(
+unsafe extern "rust-preserve-none" fn opcode_equal_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::Equal { dest, src1, src2 } = *ip else {
@@ -562,18 +1123,18 @@ unsafe extern "rust-preserve-none" fn opcode_equal_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    registers.set_value(dest, Value::number((src1 == src2) as u8 as f64));
+    registers.set_value(dest, Value::boolean(state.values_equal(src1, src2)));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_equal_rk(
+unsafe extern "rust-preserve-none" fn opcode_equal_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::EqualK { dest, src1, src2 } = *ip else {
@@ -586,18 +1147,18 @@ unsafe extern "rust-preserve-none" fn opcode_equal_rk(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { constants.get_value(src2) };
 
-    registers.set_value(dest, Value::number((src1 == src2) as u8 as f64));
+    registers.set_value(dest, Value::boolean(state.values_equal(src1, src2)));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_not_equal_rr(
+unsafe extern "rust-preserve-none" fn opcode_not_equal_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::NotEqual { dest, src1, src2 } = *ip else {
@@ -610,18 +1171,18 @@ unsafe extern "rust-preserve-none" fn opcode_not_equal_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    registers.set_value(dest, Value::number((src1 != src2) as u8 as f64));
+    registers.set_value(dest, Value::boolean(!state.values_equal(src1, src2)));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_not_equal_rk(
+unsafe extern "rust-preserve-none" fn opcode_not_equal_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::NotEqualK { dest, src1, src2 } = *ip else {
@@ -634,18 +1195,18 @@ unsafe extern "rust-preserve-none" fn opcode_not_equal_rk(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { constants.get_value(src2) };
 
-    registers.set_value(dest, Value::number((src1 != src2) as u8 as f64));
+    registers.set_value(dest, Value::boolean(!state.values_equal(src1, src2)));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_less_rr(
+unsafe extern "rust-preserve-none" fn opcode_less_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::Less { dest, src1, src2 } = *ip else {
@@ -658,26 +1219,24 @@ unsafe extern "rust-preserve-none" fn opcode_less_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    type_check!(
-        src1.is_number() && src2.is_number(),
-        "cannot compare, both operands must be numbers",
-    );
+    let result = if std::hint::likely(src1.is_number() && src2.is_number()) {
+        src1.as_number() < src2.as_number()
+    } else {
+        compare_strings(state, ip, src1, src2)?.is_lt()
+    };
 
-    registers.set_value(
-        dest,
-        Value::number((src1.as_number() < src2.as_number()) as u8 as f64),
-    );
+    registers.set_value(dest, Value::boolean(result));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_less_rk(
+unsafe extern "rust-preserve-none" fn opcode_less_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::LessK { dest, src1, src2 } = *ip else {
@@ -691,25 +1250,24 @@ unsafe extern "rust-preserve-none" fn opcode_less_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
-        "cannot compare, both operands must be numbers",
+        "cannot compare, both operands must be numbers or strings",
     );
 
-    registers.set_value(
-        dest,
-        Value::number((src1.as_number() < src2.as_number()) as u8 as f64),
-    );
+    registers.set_value(dest, Value::boolean(src1.as_number() < src2.as_number()));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_less_equal_rr(
+unsafe extern "rust-preserve-none" fn opcode_less_equal_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::LessEqual { dest, src1, src2 } = *ip else {
@@ -722,26 +1280,24 @@ unsafe extern "rust-preserve-none" fn opcode_less_equal_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    type_check!(
-        src1.is_number() && src2.is_number(),
-        "cannot compare, both operands must be numbers",
-    );
+    let result = if std::hint::likely(src1.is_number() && src2.is_number()) {
+        src1.as_number() <= src2.as_number()
+    } else {
+        compare_strings(state, ip, src1, src2)?.is_le()
+    };
 
-    registers.set_value(
-        dest,
-        Value::number((src1.as_number() <= src2.as_number()) as u8 as f64),
-    );
+    registers.set_value(dest, Value::boolean(result));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_less_equal_rk(
+unsafe extern "rust-preserve-none" fn opcode_less_equal_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::LessEqualK { dest, src1, src2 } = *ip else {
@@ -755,25 +1311,24 @@ unsafe extern "rust-preserve-none" fn opcode_less_equal_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
-        "cannot compare, both operands must be numbers",
+        "cannot compare, both operands must be numbers or strings",
     );
 
-    registers.set_value(
-        dest,
-        Value::number((src1.as_number() <= src2.as_number()) as u8 as f64),
-    );
+    registers.set_value(dest, Value::boolean(src1.as_number() <= src2.as_number()));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_greater_rr(
+unsafe extern "rust-preserve-none" fn opcode_greater_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::Greater { dest, src1, src2 } = *ip else {
@@ -786,26 +1341,24 @@ unsafe extern "rust-preserve-none" fn opcode_greater_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    type_check!(
-        src1.is_number() && src2.is_number(),
-        "cannot compare, both operands must be numbers",
-    );
+    let result = if std::hint::likely(src1.is_number() && src2.is_number()) {
+        src1.as_number() > src2.as_number()
+    } else {
+        compare_strings(state, ip, src1, src2)?.is_gt()
+    };
 
-    registers.set_value(
-        dest,
-        Value::number((src1.as_number() > src2.as_number()) as u8 as f64),
-    );
+    registers.set_value(dest, Value::boolean(result));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_greater_rk(
+unsafe extern "rust-preserve-none" fn opcode_greater_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::GreaterK { dest, src1, src2 } = *ip else {
@@ -819,25 +1372,24 @@ unsafe extern "rust-preserve-none" fn opcode_greater_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
-        "cannot compare, both operands must be numbers",
+        "cannot compare, both operands must be numbers or strings",
     );
 
-    registers.set_value(
-        dest,
-        Value::number((src1.as_number() > src2.as_number()) as u8 as f64),
-    );
+    registers.set_value(dest, Value::boolean(src1.as_number() > src2.as_number()));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_greater_equal_rr(
+unsafe extern "rust-preserve-none" fn opcode_greater_equal_rr<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::GreaterEqual { dest, src1, src2 } = *ip else {
@@ -850,26 +1402,24 @@ unsafe extern "rust-preserve-none" fn opcode_greater_equal_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    type_check!(
-        src1.is_number() && src2.is_number(),
-        "cannot compare, both operands must be numbers",
-    );
+    let result = if std::hint::likely(src1.is_number() && src2.is_number()) {
+        src1.as_number() >= src2.as_number()
+    } else {
+        compare_strings(state, ip, src1, src2)?.is_ge()
+    };
 
-    registers.set_value(
-        dest,
-        Value::number((src1.as_number() >= src2.as_number()) as u8 as f64),
-    );
+    registers.set_value(dest, Value::boolean(result));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_greater_equal_rk(
+unsafe extern "rust-preserve-none" fn opcode_greater_equal_rk<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src1, src2) = unsafe {
         let Instruction::GreaterEqualK { dest, src1, src2 } = *ip else {
@@ -883,25 +1433,24 @@ unsafe extern "rust-preserve-none" fn opcode_greater_equal_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
-        "cannot compare, both operands must be numbers",
+        "cannot compare, both operands must be numbers or strings",
     );
 
-    registers.set_value(
-        dest,
-        Value::number((src1.as_number() >= src2.as_number()) as u8 as f64),
-    );
+    registers.set_value(dest, Value::boolean(src1.as_number() >= src2.as_number()));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_not(
+unsafe extern "rust-preserve-none" fn opcode_not<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src) = unsafe {
         let Instruction::Not { dest, src } = *ip else {
@@ -914,22 +1463,24 @@ unsafe extern "rust-preserve-none" fn opcode_not(
     let src = unsafe { registers.get_value(src) };
 
     type_check!(
-        src.is_number(),
+        state,
+        ip,
+        src.is_bool(),
         "cannot apply not, operand must be a boolean",
     );
 
-    registers.set_value(dest, Value::number((src.as_number() == 0.0) as u8 as f64));
+    registers.set_value(dest, Value::boolean(!src.as_bool()));
 
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_negate(
+unsafe extern "rust-preserve-none" fn opcode_negate<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src) = unsafe {
         let Instruction::Negate { dest, src } = *ip else {
@@ -941,7 +1492,12 @@ unsafe extern "rust-preserve-none" fn opcode_negate(
 
     let src = unsafe { registers.get_value(src) };
 
-    type_check!(src.is_number(), "cannot negate, operand must be a number",);
+    type_check!(
+        state,
+        ip,
+        src.is_number(),
+        "cannot negate, operand must be a number",
+    );
 
     registers.set_value(dest, Value::number(-src.as_number()));
 
@@ -949,12 +1505,12 @@ unsafe extern "rust-preserve-none" fn opcode_negate(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_move(
+unsafe extern "rust-preserve-none" fn opcode_move<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src) = unsafe {
         let Instruction::Move { dest, src } = *ip else {
@@ -972,12 +1528,12 @@ unsafe extern "rust-preserve-none" fn opcode_move(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_move_arg(
+unsafe extern "rust-preserve-none" fn opcode_move_arg<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src) = unsafe {
         let Instruction::MoveArg { dest, src } = *ip else {
@@ -995,12 +1551,12 @@ unsafe extern "rust-preserve-none" fn opcode_move_arg(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_load_k(
+unsafe extern "rust-preserve-none" fn opcode_load_k<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src) = unsafe {
         let Instruction::LoadK { dest, src } = *ip else {
@@ -1018,12 +1574,35 @@ unsafe extern "rust-preserve-none" fn opcode_load_k(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_create_dict(
+unsafe extern "rust-preserve-none" fn opcode_load_k_wide<const DEBUG: bool>(
+    ip: *const Instruction,
+    mut registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (dest, src) = unsafe {
+        let Instruction::LoadKWide { dest, src } = *ip else {
+            unreachable_unchecked()
+        };
+
+        (dest, src)
+    };
+
+    let constant = unsafe { constants.get_wide_value(src) };
+
+    registers.set_value(dest, constant);
+
+    dispatch_next!(ip, registers, constants, state, frame_size)
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_create_dict<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let dest = unsafe {
         let Instruction::CreateDict { dest } = *ip else {
@@ -1033,6 +1612,8 @@ unsafe extern "rust-preserve-none" fn opcode_create_dict(
         dest
     };
 
+    state.collect_garbage(&registers, frame_size);
+
     let value = state.gc.allocate_dict();
 
     registers.set_value(dest, value);
@@ -1041,12 +1622,12 @@ unsafe extern "rust-preserve-none" fn opcode_create_dict(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_set_field(
+unsafe extern "rust-preserve-none" fn opcode_set_field<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (object, key, value) = unsafe {
         let Instruction::SetField { object, key, value } = *ip else {
@@ -1060,7 +1641,14 @@ unsafe extern "rust-preserve-none" fn opcode_set_field(
     let key = unsafe { registers.get_value(key) };
     let value = unsafe { registers.get_value(value) };
 
-    type_check!(object.is_dict(), "cannot set field, value is not a dict",);
+    type_check!(
+        state,
+        ip,
+        object.is_dict(),
+        "cannot set field, value is not a dict",
+    );
+
+    let key = state.dict_key(key);
 
     state.gc.get_mut_dict(object).insert(key, value);
 
@@ -1068,12 +1656,12 @@ unsafe extern "rust-preserve-none" fn opcode_set_field(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_get_field(
+unsafe extern "rust-preserve-none" fn opcode_get_field<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, object, key) = unsafe {
         let Instruction::GetField { dest, object, key } = *ip else {
@@ -1086,14 +1674,21 @@ unsafe extern "rust-preserve-none" fn opcode_get_field(
     let object = unsafe { registers.get_value(object) };
     let key = unsafe { registers.get_value(key) };
 
-    type_check!(object.is_dict(), "cannot get field, value is not a dict",);
-
+    type_check!(
+        state,
+        ip,
+        object.is_dict(),
+        "cannot get field, value is not a dict",
+    );
+
+    let key = state.dict_key(key);
+
     let value = state
         .gc
         .get_dict(object)
         .get(&key)
         .copied()
-        .unwrap_or_default();
+        .unwrap_or(Value::nil());
 
     registers.set_value(dest, value);
 
@@ -1101,12 +1696,167 @@ unsafe extern "rust-preserve-none" fn opcode_get_field(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_create_closure(
+unsafe extern "rust-preserve-none" fn opcode_get_index<const DEBUG: bool>(
+    ip: *const Instruction,
+    mut registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (dest, object, index) = unsafe {
+        let Instruction::GetIndex {
+            dest,
+            object,
+            index,
+        } = *ip
+        else {
+            unreachable_unchecked()
+        };
+
+        (dest, object, index)
+    };
+
+    let object = unsafe { registers.get_value(object) };
+    let index = unsafe { registers.get_value(index) };
+
+    let value = if object.is_vec() {
+        let elements = state.gc.get_vec(object);
+        let index = match vec_index(index, elements.len()) {
+            Ok(index) => index,
+            Err(error) => return Err(state.runtime_error(ip, error)),
+        };
+
+        elements[index]
+    } else {
+        type_check!(
+            state,
+            ip,
+            object.is_dict(),
+            "cannot index, value must be a vec or dict",
+        );
+
+        let key = state.dict_key(index);
+
+        state
+            .gc
+            .get_dict(object)
+            .get(&key)
+            .copied()
+            .unwrap_or(Value::nil())
+    };
+
+    registers.set_value(dest, value);
+
+    dispatch_next!(ip, registers, constants, state, frame_size)
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_set_index<const DEBUG: bool>(
+    ip: *const Instruction,
+    registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (object, index, value) = unsafe {
+        let Instruction::SetIndex {
+            object,
+            index,
+            value,
+        } = *ip
+        else {
+            unreachable_unchecked()
+        };
+
+        (object, index, value)
+    };
+
+    let object = unsafe { registers.get_value(object) };
+    let index = unsafe { registers.get_value(index) };
+    let value = unsafe { registers.get_value(value) };
+
+    if object.is_vec() {
+        let elements = state.gc.get_mut_vec(object);
+        let index = match vec_index(index, elements.len()) {
+            Ok(index) => index,
+            Err(error) => return Err(state.runtime_error(ip, error)),
+        };
+
+        elements[index] = value;
+    } else {
+        type_check!(
+            state,
+            ip,
+            object.is_dict(),
+            "cannot index, value must be a vec or dict",
+        );
+
+        let key = state.dict_key(index);
+
+        state.gc.get_mut_dict(object).insert(key, value);
+    }
+
+    dispatch_next!(ip, registers, constants, state, frame_size)
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_create_vec<const DEBUG: bool>(
+    ip: *const Instruction,
+    mut registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let dest = unsafe {
+        let Instruction::CreateVec { dest } = *ip else {
+            unreachable_unchecked()
+        };
+
+        dest
+    };
+
+    state.collect_garbage(&registers, frame_size);
+
+    let value = state.gc.allocate_vec();
+
+    registers.set_value(dest, value);
+
+    dispatch_next!(ip, registers, constants, state, frame_size)
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_vec_push<const DEBUG: bool>(
+    ip: *const Instruction,
+    registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (vec, src) = unsafe {
+        let Instruction::VecPush { vec, src } = *ip else {
+            unreachable_unchecked()
+        };
+
+        (vec, src)
+    };
+
+    let vec = unsafe { registers.get_value(vec) };
+    let value = unsafe { registers.get_value(src) };
+
+    type_check!(state, ip, vec.is_vec(), "cannot push, value is not a vec",);
+
+    state.gc.get_mut_vec(vec).push(value);
+
+    dispatch_next!(ip, registers, constants, state, frame_size)
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_create_closure<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src) = unsafe {
         let Instruction::CreateClosure { dest, src } = *ip else {
@@ -1120,6 +1870,7 @@ unsafe extern "rust-preserve-none" fn opcode_create_closure(
         ref instructions,
         registers_count,
         arity,
+        ..
     } = state.functions[src as usize];
 
     let closure = Closure {
@@ -1129,6 +1880,8 @@ unsafe extern "rust-preserve-none" fn opcode_create_closure(
         captured: Vec::new(),
     };
 
+    state.collect_garbage(&registers, frame_size);
+
     let closure = state.gc.allocate_closure(closure);
 
     registers.set_value(dest, closure);
@@ -1137,12 +1890,12 @@ unsafe extern "rust-preserve-none" fn opcode_create_closure(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_capture_value(
+unsafe extern "rust-preserve-none" fn opcode_capture_value<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src) = unsafe {
         let Instruction::CaptureValue { dest, src } = *ip else {
@@ -1163,12 +1916,85 @@ unsafe extern "rust-preserve-none" fn opcode_capture_value(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_call(
+unsafe extern "rust-preserve-none" fn opcode_create_cell<const DEBUG: bool>(
     ip: *const Instruction,
     mut registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (dest, src) = unsafe {
+        let Instruction::CreateCell { dest, src } = *ip else {
+            unreachable_unchecked()
+        };
+
+        (dest, src)
+    };
+
+    state.collect_garbage(&registers, frame_size);
+
+    let value = unsafe { registers.get_value(src) };
+    let cell = state.gc.allocate_cell(value);
+
+    registers.set_value(dest, cell);
+
+    dispatch_next!(ip, registers, constants, state, frame_size)
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_get_cell<const DEBUG: bool>(
+    ip: *const Instruction,
+    mut registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (dest, cell) = unsafe {
+        let Instruction::GetCell { dest, cell } = *ip else {
+            unreachable_unchecked()
+        };
+
+        (dest, cell)
+    };
+
+    let cell = unsafe { registers.get_value(cell) };
+
+    registers.set_value(dest, state.gc.get_cell(cell));
+
+    dispatch_next!(ip, registers, constants, state, frame_size)
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_set_cell<const DEBUG: bool>(
+    ip: *const Instruction,
+    registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (cell, src) = unsafe {
+        let Instruction::SetCell { cell, src } = *ip else {
+            unreachable_unchecked()
+        };
+
+        (cell, src)
+    };
+
+    let value = unsafe { registers.get_value(src) };
+    let cell = unsafe { registers.get_value(cell) };
+
+    *state.gc.get_mut_cell(cell) = value;
+
+    dispatch_next!(ip, registers, constants, state, frame_size)
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_call<const DEBUG: bool>(
+    ip: *const Instruction,
+    mut registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (dest, src, call_arity) = unsafe {
         let Instruction::Call { dest, src, arity } = *ip else {
@@ -1180,7 +2006,45 @@ unsafe extern "rust-preserve-none" fn opcode_call(
 
     let src = unsafe { registers.get_value(src) };
 
-    type_check!(src.is_closure(), "cannot call, value is not a function",);
+    if src.is_native() {
+        let NativeFunction {
+            name,
+            arity,
+            function,
+        } = state.natives[src.as_index()];
+
+        if call_arity != arity {
+            let name = INTERNER.lock().unwrap().resolve(name).to_owned();
+
+            return Err(state.runtime_error(
+                ip,
+                report_error!(
+                    "native function `{}` expects {} arguments, but received {}",
+                    name,
+                    arity,
+                    call_arity
+                ),
+            ));
+        }
+
+        let start = frame_size as usize;
+        let arguments = &registers.0[start..start + arity as usize];
+        let return_value = match function(arguments, state) {
+            Ok(value) => value,
+            Err(error) => return Err(state.runtime_error(ip, error)),
+        };
+
+        registers.set_value(dest, return_value);
+
+        dispatch_next!(ip, registers, constants, state, frame_size)
+    }
+
+    type_check!(
+        state,
+        ip,
+        src.is_closure(),
+        "cannot call, value is not a function",
+    );
 
     let return_value = {
         let Closure {
@@ -1191,26 +2055,43 @@ unsafe extern "rust-preserve-none" fn opcode_call(
         } = *state.gc.get_closure(src);
 
         if call_arity != closure_arity {
-            return Err(Box::new(report_error!(
-                "the number of arguments must match the number of parameters in a function call"
-            )));
+            return Err(state.runtime_error(
+                ip,
+                report_error!(
+                    "the number of arguments must match the number of parameters in a function call"
+                ),
+            ));
         }
 
-        const MIN_REGISTERS: isize = u8::MAX as isize;
+        if state.call_overflows() {
+            return Err(state.stack_overflow(ip));
+        }
 
-        if (registers.0.len() as isize - frame_size as isize) < MIN_REGISTERS {
-            return Err(Box::new(report_error!("the call stack ran out of memory")));
-        };
+        // The callee's registers, and the arguments of the calls it makes.
+        let required = size as usize + u8::MAX as usize;
 
-        let mut registers = Registers(&mut registers.0[frame_size as usize..]);
+        state.depth += 1;
 
-        for (i, value) in captured.iter().copied().enumerate() {
-            registers.set_value(closure_arity + i as u8, value);
-        }
+        let result = if registers.0.len() - (frame_size as usize) < required {
+            call_in_segment::<DEBUG>(state, &registers, constants, frame_size, src)
+        } else {
+            let mut callee = Registers(&mut registers.0[frame_size as usize..]);
 
-        let index = unsafe { (*instructions).discriminant() };
+            for (i, value) in captured.iter().copied().enumerate() {
+                callee.set_value(closure_arity as u16 + i as u16, value);
+            }
 
-        unsafe { HANDLERS[index](instructions, registers, constants, state, size)? }
+            let initialized = closure_arity as usize + captured.len();
+
+            unsafe { enter::<DEBUG>(instructions, callee, constants, state, size, initialized) }
+        };
+
+        state.depth -= 1;
+
+        match result {
+            Ok(value) => value,
+            Err(error) => return Err(state.unwind_call(ip, error)),
+        }
     };
 
     registers.set_value(dest, return_value);
@@ -1219,12 +2100,121 @@ unsafe extern "rust-preserve-none" fn opcode_call(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_return(
+unsafe extern "rust-preserve-none" fn opcode_tail_call<const DEBUG: bool>(
+    ip: *const Instruction,
+    mut registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (src, call_arity) = unsafe {
+        let Instruction::TailCall { src, arity } = *ip else {
+            unreachable_unchecked()
+        };
+
+        (src, arity)
+    };
+
+    let src = unsafe { registers.get_value(src) };
+
+    if src.is_native() {
+        let NativeFunction {
+            name,
+            arity,
+            function,
+        } = state.natives[src.as_index()];
+
+        if call_arity != arity {
+            let name = INTERNER.lock().unwrap().resolve(name).to_owned();
+
+            return Err(state.runtime_error(
+                ip,
+                report_error!(
+                    "native function `{}` expects {} arguments, but received {}",
+                    name,
+                    arity,
+                    call_arity
+                ),
+            ));
+        }
+
+        let start = frame_size as usize;
+        let arguments = &registers.0[start..start + arity as usize];
+
+        return function(arguments, state).map_err(|error| state.runtime_error(ip, error));
+    }
+
+    type_check!(
+        state,
+        ip,
+        src.is_closure(),
+        "cannot call, value is not a function",
+    );
+
+    let Closure {
+        instructions,
+        arity: closure_arity,
+        size,
+        ref captured,
+    } = *state.gc.get_closure(src);
+
+    if call_arity != closure_arity {
+        return Err(state.runtime_error(
+            ip,
+            report_error!(
+                "the number of arguments must match the number of parameters in a function call"
+            ),
+        ));
+    }
+
+    // The callee's registers, and the arguments of the calls it makes.
+    let required = size as usize + u8::MAX as usize;
+
+    // A frame that can't grow in place is replaced by a regular call from the
+    // next segment.
+    if registers.0.len() < required {
+        if state.call_overflows() {
+            return Err(state.stack_overflow(ip));
+        }
+
+        state.depth += 1;
+
+        let result = call_in_segment::<DEBUG>(state, &registers, constants, frame_size, src);
+
+        state.depth -= 1;
+
+        return result.map_err(|error| state.unwind_call(ip, error));
+    }
+
+    let start = frame_size as usize;
+    registers
+        .0
+        .copy_within(start..start + closure_arity as usize, 0);
+
+    for (i, value) in captured.iter().copied().enumerate() {
+        registers.set_value(closure_arity as u16 + i as u16, value);
+    }
+
+    if DEBUG {
+        registers.clear(closure_arity as usize + captured.len(), size);
+        state.debug_hook(instructions, &registers)?;
+    }
+
+    unsafe {
+        let index = (*instructions).discriminant();
+        let handler = *table::<DEBUG>().get_unchecked(index);
+
+        become handler(instructions, registers, constants, state, size);
+    }
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_return<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     _constants: Constants,
     _vm: &mut VmState,
-    _frame_size: u8,
+    _frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let src = unsafe {
         let Instruction::Return { src } = *ip else {
@@ -1240,12 +2230,12 @@ unsafe extern "rust-preserve-none" fn opcode_return(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump(
+unsafe extern "rust-preserve-none" fn opcode_jump<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let offset = unsafe {
         let Instruction::Jump { offset } = *ip else {
@@ -1259,12 +2249,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_false(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_false<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src, offset) = unsafe {
         let Instruction::JumpIfFalse { src, offset } = *ip else {
@@ -1277,24 +2267,26 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_false(
     let src = unsafe { registers.get_value(src) };
 
     type_check!(
-        src.is_number(),
+        state,
+        ip,
+        src.is_bool(),
         "cannot use this as a condition, value must be a boolean",
     );
 
-    if src.as_number() == 0.0 {
-        dispatch_offset!(ip, registers, constants, state, frame_size, offset)
-    } else {
+    if src.as_bool() {
         dispatch_next!(ip, registers, constants, state, frame_size)
+    } else {
+        dispatch_offset!(ip, registers, constants, state, frame_size, offset)
     }
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_true(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_true<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src, offset) = unsafe {
         let Instruction::JumpIfTrue { src, offset } = *ip else {
@@ -1307,24 +2299,26 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_true(
     let src = unsafe { registers.get_value(src) };
 
     type_check!(
-        src.is_number(),
+        state,
+        ip,
+        src.is_bool(),
         "cannot use this as a condition, value must be a boolean",
     );
 
-    if src.as_number() == 0.0 {
-        dispatch_next!(ip, registers, constants, state, frame_size)
-    } else {
+    if src.as_bool() {
         dispatch_offset!(ip, registers, constants, state, frame_size, offset)
+    } else {
+        dispatch_next!(ip, registers, constants, state, frame_size)
     }
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_less_rr(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_less_rr<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfLess { src1, src2, offset } = *ip else {
@@ -1337,12 +2331,13 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_less_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    type_check!(
-        src1.is_number() && src2.is_number(),
-        "cannot compare, both operands must be numbers",
-    );
+    let result = if std::hint::likely(src1.is_number() && src2.is_number()) {
+        src1.as_number() < src2.as_number()
+    } else {
+        compare_strings(state, ip, src1, src2)?.is_lt()
+    };
 
-    if src1.as_number() < src2.as_number() {
+    if result {
         dispatch_offset!(ip, registers, constants, state, frame_size, offset)
     } else {
         dispatch_next!(ip, registers, constants, state, frame_size)
@@ -1350,12 +2345,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_less_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_less_rk(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_less_rk<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfLessK { src1, src2, offset } = *ip else {
@@ -1369,8 +2364,10 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_less_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
-        "cannot compare, both operands must be numbers",
+        "cannot compare, both operands must be numbers or strings",
     );
 
     if src1.as_number() < src2.as_number() {
@@ -1381,12 +2378,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_less_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_less_equal_rr(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_less_equal_rr<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfLessEqual { src1, src2, offset } = *ip else {
@@ -1399,12 +2396,13 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_less_equal_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    type_check!(
-        src1.is_number() && src2.is_number(),
-        "cannot compare, both operands must be numbers",
-    );
+    let result = if std::hint::likely(src1.is_number() && src2.is_number()) {
+        src1.as_number() <= src2.as_number()
+    } else {
+        compare_strings(state, ip, src1, src2)?.is_le()
+    };
 
-    if src1.as_number() <= src2.as_number() {
+    if result {
         dispatch_offset!(ip, registers, constants, state, frame_size, offset)
     } else {
         dispatch_next!(ip, registers, constants, state, frame_size)
@@ -1412,12 +2410,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_less_equal_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_less_equal_rk(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_less_equal_rk<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfLessEqualK { src1, src2, offset } = *ip else {
@@ -1431,8 +2429,10 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_less_equal_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
-        "cannot compare, both operands must be numbers",
+        "cannot compare, both operands must be numbers or strings",
     );
 
     if src1.as_number() <= src2.as_number() {
@@ -1443,12 +2443,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_less_equal_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_rr(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_rr<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfGreater { src1, src2, offset } = *ip else {
@@ -1461,12 +2461,13 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    type_check!(
-        src1.is_number() && src2.is_number(),
-        "cannot compare, both operands must be numbers",
-    );
+    let result = if std::hint::likely(src1.is_number() && src2.is_number()) {
+        src1.as_number() > src2.as_number()
+    } else {
+        compare_strings(state, ip, src1, src2)?.is_gt()
+    };
 
-    if src1.as_number() > src2.as_number() {
+    if result {
         dispatch_offset!(ip, registers, constants, state, frame_size, offset)
     } else {
         dispatch_next!(ip, registers, constants, state, frame_size)
@@ -1474,12 +2475,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_rk(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_rk<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfGreaterK { src1, src2, offset } = *ip else {
@@ -1493,8 +2494,10 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
-        "cannot compare, both operands must be numbers",
+        "cannot compare, both operands must be numbers or strings",
     );
 
     if src1.as_number() > src2.as_number() {
@@ -1505,12 +2508,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_equal_rr(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_equal_rr<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfGreaterEqual { src1, src2, offset } = *ip else {
@@ -1523,12 +2526,13 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_equal_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    type_check!(
-        src1.is_number() && src2.is_number(),
-        "cannot compare, both operands must be numbers",
-    );
+    let result = if std::hint::likely(src1.is_number() && src2.is_number()) {
+        src1.as_number() >= src2.as_number()
+    } else {
+        compare_strings(state, ip, src1, src2)?.is_ge()
+    };
 
-    if src1.as_number() >= src2.as_number() {
+    if result {
         dispatch_offset!(ip, registers, constants, state, frame_size, offset)
     } else {
         dispatch_next!(ip, registers, constants, state, frame_size)
@@ -1536,12 +2540,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_equal_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_equal_rk(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_equal_rk<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfGreaterEqualK { src1, src2, offset } = *ip else {
@@ -1555,8 +2559,10 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_equal_rk(
     let src2 = unsafe { constants.get_value(src2) };
 
     type_check!(
+        state,
+        ip,
         src1.is_number(),
-        "cannot compare, both operands must be numbers",
+        "cannot compare, both operands must be numbers or strings",
     );
 
     if src1.as_number() >= src2.as_number() {
@@ -1567,12 +2573,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_greater_equal_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_equal_rr(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_equal_rr<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfEqual { src1, src2, offset } = *ip else {
@@ -1585,7 +2591,7 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_equal_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    if src1 == src2 {
+    if state.values_equal(src1, src2) {
         dispatch_offset!(ip, registers, constants, state, frame_size, offset)
     } else {
         dispatch_next!(ip, registers, constants, state, frame_size)
@@ -1593,12 +2599,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_equal_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_equal_rk(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_equal_rk<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfEqualK { src1, src2, offset } = *ip else {
@@ -1611,7 +2617,7 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_equal_rk(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { constants.get_value(src2) };
 
-    if src1.as_number() == src2.as_number() {
+    if state.values_equal(src1, src2) {
         dispatch_offset!(ip, registers, constants, state, frame_size, offset)
     } else {
         dispatch_next!(ip, registers, constants, state, frame_size)
@@ -1619,12 +2625,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_equal_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_not_equal_rr(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_not_equal_rr<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfNotEqual { src1, src2, offset } = *ip else {
@@ -1637,7 +2643,7 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_not_equal_rr(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { registers.get_value(src2) };
 
-    if src1 != src2 {
+    if !state.values_equal(src1, src2) {
         dispatch_offset!(ip, registers, constants, state, frame_size, offset)
     } else {
         dispatch_next!(ip, registers, constants, state, frame_size)
@@ -1645,12 +2651,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_not_equal_rr(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_jump_if_not_equal_rk(
+unsafe extern "rust-preserve-none" fn opcode_jump_if_not_equal_rk<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     let (src1, src2, offset) = unsafe {
         let Instruction::JumpIfNotEqualK { src1, src2, offset } = *ip else {
@@ -1663,7 +2669,110 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_not_equal_rk(
     let src1 = unsafe { registers.get_value(src1) };
     let src2 = unsafe { constants.get_value(src2) };
 
-    if src1.as_number() != src2.as_number() {
+    if !state.values_equal(src1, src2) {
+        dispatch_offset!(ip, registers, constants, state, frame_size, offset)
+    } else {
+        dispatch_next!(ip, registers, constants, state, frame_size)
+    }
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_for_prep<const DEBUG: bool>(
+    ip: *const Instruction,
+    mut registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (descending, base, offset) = unsafe {
+        let Instruction::ForPrep {
+            descending,
+            base,
+            offset,
+        } = *ip
+        else {
+            unreachable_unchecked()
+        };
+
+        (descending, base, offset)
+    };
+
+    let counter = unsafe { registers.get_value(base) };
+    let limit = unsafe { registers.get_value(base + 1) };
+    let step = unsafe { registers.get_value(base + 2) };
+
+    type_check!(
+        state,
+        ip,
+        counter.is_number() && limit.is_number() && step.is_number(),
+        "cannot run for loop, start, end and step must be numbers",
+    );
+
+    let (counter, limit, step) = (counter.as_number(), limit.as_number(), step.as_number());
+
+    type_check!(
+        state,
+        ip,
+        step != 0.0,
+        "cannot run for loop, step must not be zero",
+    );
+
+    // `downto` counts down by its step, a negative one would count up past
+    // the limit and never run the body.
+    let step = if descending {
+        type_check!(
+            state,
+            ip,
+            step.is_sign_positive(),
+            "cannot run for loop, step of a `downto` loop must be positive",
+        );
+
+        registers.set_value(base + 2, Value::number(-step));
+
+        -step
+    } else {
+        step
+    };
+
+    if (step > 0.0 && counter > limit) || (step < 0.0 && counter < limit) {
+        dispatch_offset!(ip, registers, constants, state, frame_size, offset)
+    } else {
+        registers.set_value(base + 3, Value::number(counter));
+
+        dispatch_next!(ip, registers, constants, state, frame_size)
+    }
+}
+
+#[inline(never)]
+unsafe extern "rust-preserve-none" fn opcode_for_loop<const DEBUG: bool>(
+    ip: *const Instruction,
+    mut registers: Registers,
+    constants: Constants,
+    state: &mut VmState,
+    frame_size: u16,
+) -> Result<Value, Box<Error>> {
+    let (base, offset) = unsafe {
+        let Instruction::ForLoop { base, offset } = *ip else {
+            unreachable_unchecked()
+        };
+
+        (base, offset)
+    };
+
+    let (counter, limit, step) = unsafe {
+        (
+            registers.get_value(base).as_number(),
+            registers.get_value(base + 1).as_number(),
+            registers.get_value(base + 2).as_number(),
+        )
+    };
+
+    let counter = counter + step;
+    registers.set_value(base, Value::number(counter));
+
+    if (step > 0.0 && counter <= limit) || (step < 0.0 && counter >= limit) {
+        registers.set_value(base + 3, Value::number(counter));
+
         dispatch_offset!(ip, registers, constants, state, frame_size, offset)
     } else {
         dispatch_next!(ip, registers, constants, state, frame_size)
@@ -1671,12 +2780,12 @@ unsafe extern "rust-preserve-none" fn opcode_jump_if_not_equal_rk(
 }
 
 #[inline(never)]
-unsafe extern "rust-preserve-none" fn opcode_nop(
+unsafe extern "rust-preserve-none" fn opcode_nop<const DEBUG: bool>(
     ip: *const Instruction,
     registers: Registers,
     constants: Constants,
     state: &mut VmState,
-    frame_size: u8,
+    frame_size: u16,
 ) -> Result<Value, Box<Error>> {
     dispatch_next!(ip, registers, constants, state, frame_size)
 }
@@ -1,9 +1,164 @@
-/*
-fn print(registers: *mut Value) -> Value {
-    unsafe {
+use foldhash::HashMap;
 
+use crate::{
+    diagnostics::error::Error,
+    program::INTERNER,
+    report_error,
+    runtime::{debug_value::DebugValue, value::Value, vm::VmState},
+    util::string_interner::StringIndex,
+};
 
-    let value = register
+pub type NativeFn = fn(arguments: &[Value], state: &mut VmState) -> Result<Value, Error>;
 
+#[derive(Clone, Copy)]
+pub struct NativeFunction {
+    pub name: StringIndex,
+    pub arity: u8,
+    pub function: NativeFn,
+}
+
+#[derive(Clone)]
+pub struct NativeRegistry {
+    functions: Vec<NativeFunction>,
+    names: HashMap<StringIndex, usize>,
+}
+
+impl NativeRegistry {
+    pub fn empty() -> Self {
+        Self {
+            functions: Vec::new(),
+            names: HashMap::default(),
+        }
+    }
+
+    pub fn register(&mut self, name: &str, arity: u8, function: NativeFn) {
+        let name = INTERNER.lock().unwrap().get_or_intern(name);
+        let native = NativeFunction {
+            name,
+            arity,
+            function,
+        };
+
+        if let Some(&index) = self.names.get(&name) {
+            self.functions[index] = native;
+        } else {
+            self.names.insert(name, self.functions.len());
+            self.functions.push(native);
+        }
+    }
+
+    pub fn lookup(&self, name: StringIndex) -> Option<(usize, &NativeFunction)> {
+        let index = *self.names.get(&name)?;
+
+        Some((index, &self.functions[index]))
+    }
+
+    pub fn functions(&self) -> &[NativeFunction] {
+        &self.functions
+    }
+}
+
+impl Default for NativeRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+
+        registry.register("print", 1, print);
+        registry.register("str", 1, str);
+        registry.register("len", 1, len);
+        registry.register("push", 2, push);
+        registry.register("pop", 1, pop);
+        registry.register("get", 2, get);
+
+        registry
+    }
+}
+
+fn print(arguments: &[Value], state: &mut VmState) -> Result<Value, Error> {
+    println!("{:?}", DebugValue::new(arguments[0], state.gc()));
+
+    Ok(Value::nil())
+}
+
+fn str(arguments: &[Value], state: &mut VmState) -> Result<Value, Error> {
+    let value = arguments[0];
+
+    if value.is_string() {
+        return Ok(value);
+    }
+
+    let string = format!("{:?}", DebugValue::new(value, state.gc()));
+
+    Ok(state.gc_mut().allocate_string(string))
+}
+
+fn len(arguments: &[Value], state: &mut VmState) -> Result<Value, Error> {
+    let value = arguments[0];
+    let gc = state.gc();
+
+    let length = if value.is_vec() {
+        gc.get_vec(value).len()
+    } else if value.is_string() {
+        gc.get_str(value).chars().count()
+    } else if value.is_dict() {
+        gc.get_dict(value).len()
+    } else {
+        return Err(report_error!(
+            "cannot get length, value must be a vec, string or dict"
+        ));
+    };
+
+    Ok(Value::number(length as f64))
+}
+
+fn push(arguments: &[Value], state: &mut VmState) -> Result<Value, Error> {
+    let (vec, value) = (arguments[0], arguments[1]);
+
+    if !vec.is_vec() {
+        return Err(report_error!("cannot push, value is not a vec"));
+    }
+
+    state.gc_mut().get_mut_vec(vec).push(value);
+
+    Ok(Value::nil())
+}
+
+fn pop(arguments: &[Value], state: &mut VmState) -> Result<Value, Error> {
+    let vec = arguments[0];
+
+    if !vec.is_vec() {
+        return Err(report_error!("cannot pop, value is not a vec"));
+    }
+
+    Ok(state.gc_mut().get_mut_vec(vec).pop().unwrap_or_default())
+}
+
+fn get(arguments: &[Value], state: &mut VmState) -> Result<Value, Error> {
+    let (vec, index) = (arguments[0], arguments[1]);
+
+    if !vec.is_vec() {
+        return Err(report_error!("cannot get element, value is not a vec"));
     }
-} */
+
+    let elements = state.gc().get_vec(vec);
+    let index = vec_index(index, elements.len())?;
+
+    Ok(elements[index])
+}
+
+pub fn vec_index(index: Value, len: usize) -> Result<usize, Error> {
+    if !index.is_number() || index.as_number().fract() != 0.0 {
+        return Err(report_error!("vec index must be an integer number"));
+    }
+
+    let number = index.as_number();
+
+    if number < 0.0 || number >= len as f64 {
+        return Err(report_error!(
+            "index {} is out of bounds for a vec of length {}",
+            number,
+            len
+        ));
+    }
+
+    Ok(number as usize)
+}
@@ -13,6 +13,46 @@ pub struct ExprId(u32);
 pub struct Ast {
     expressions: Vec<Expr>,
     spans: HashMap<ExprId, Range<usize>>,
+    /// Comments around statements and list items, only kept when the parser is asked to.
+    trivia: HashMap<ExprId, Trivia>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommentKind {
+    Line,
+    Doc,
+    Block,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    pub kind: CommentKind,
+    pub span: Range<usize>,
+    /// The comment as written, delimiters included.
+    pub text: String,
+    /// Whether the comment starts a line, rather than following code on it.
+    pub newline_before: bool,
+    /// Whether an empty line separates the comment from what comes before it.
+    pub blank_line_before: bool,
+}
+
+/// Comments attached to a statement or to an item of a list.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Trivia {
+    /// Comments on the lines above the statement or item.
+    pub leading: Vec<Comment>,
+    /// Comments after the statement on its last line. An item also gets the
+    /// comments inside it.
+    pub trailing: Vec<Comment>,
+    /// Comments after the last statement of a block, or after the last item
+    /// of the arguments, parameters or elements of an expression, before
+    /// its end.
+    pub inner: Vec<Comment>,
+    /// Whether an empty line separates the statement from the code or
+    /// comment above it.
+    pub blank_line_before: bool,
+    /// Whether the statement is the last of its block and leaves out its `;`.
+    pub omits_semicolon: bool,
 }
 
 pub enum Expr {
@@ -46,6 +86,8 @@ pub enum Expr {
     Identifier(StringIndex),
     StringLiteral(StringIndex),
     NumberLiteral(f64),
+    BooleanLiteral(bool),
+    NilLiteral,
     FunctionCall {
         callee: ExprId,
         arguments: Box<[ExprId]>,
@@ -54,9 +96,16 @@ pub enum Expr {
         object: ExprId,
         property: ExprId,
     },
+    Index {
+        object: ExprId,
+        index: ExprId,
+    },
     DictLiteral {
         fields: Box<[(ExprId, Option<ExprId>)]>,
     },
+    VecLiteral {
+        elements: Box<[ExprId]>,
+    },
     NativeFunction {
         name: ExprId,
         parameters: Box<[ExprId]>,
@@ -77,10 +126,17 @@ pub enum Expr {
         block: ExprId,
     },
     ForLoop {
+        variable: ExprId,
         start: ExprId,
         end: ExprId,
+        step: Option<ExprId>,
+        descending: bool,
         block: ExprId,
     },
+    /// Brings the exported names of the module at `path` into scope.
+    Import(StringIndex),
+    /// A top level declaration visible to the modules that import its file.
+    Export(ExprId),
     Return(Option<ExprId>),
     Break,
     Continue,
@@ -105,6 +161,11 @@ impl Ast {
         ExprId(last as u32)
     }
 
+    /// Every expression in the tree, in the order they were parsed.
+    pub fn expressions(&self) -> impl Iterator<Item = ExprId> + use<> {
+        (0..self.expressions.len() as u32).map(ExprId)
+    }
+
     pub fn get(&self, id: ExprId) -> &Expr {
         &self.expressions[id.0 as usize]
     }
@@ -113,6 +174,43 @@ impl Ast {
         self.spans.get(&id)
     }
 
+    pub fn trivia(&self, id: ExprId) -> Option<&Trivia> {
+        self.trivia.get(&id)
+    }
+
+    pub fn trivia_mut(&mut self, id: ExprId) -> &mut Trivia {
+        self.trivia.entry(id).or_default()
+    }
+
+    /// Text of the `///` comments right above `id`, without the slashes.
+    pub fn doc_comment(&self, id: ExprId) -> Option<String> {
+        let leading = &self.trivia(id)?.leading;
+
+        // Only the doc comments after the last other comment document `id`.
+        let start = leading
+            .iter()
+            .rposition(|comment| comment.kind != CommentKind::Doc)
+            .map_or(0, |index| index + 1);
+
+        let lines: Vec<&str> = leading[start..]
+            .iter()
+            .map(|comment| {
+                let line = &comment.text[3..];
+                line.strip_prefix(' ').unwrap_or(line)
+            })
+            .collect();
+
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    /// The declaration exported by `id`, or `id` itself when it's not an export.
+    pub fn declaration(&self, id: ExprId) -> ExprId {
+        match *self.get(id) {
+            Expr::Export(declaration) => declaration,
+            _ => id,
+        }
+    }
+
     pub fn binary(
         &mut self,
         operator: BinaryOp,
@@ -179,18 +277,40 @@ impl Ast {
         self.insert(Expr::NumberLiteral(value), Some(span))
     }
 
-    pub fn function_call(&mut self, callee: ExprId, arguments: Vec<ExprId>) -> ExprId {
+    pub fn boolean_literal(&mut self, value: bool, span: Range<usize>) -> ExprId {
+        self.insert(Expr::BooleanLiteral(value), Some(span))
+    }
+
+    pub fn nil_literal(&mut self, span: Range<usize>) -> ExprId {
+        self.insert(Expr::NilLiteral, Some(span))
+    }
+
+    pub fn function_call(
+        &mut self,
+        callee: ExprId,
+        arguments: Vec<ExprId>,
+        span: Range<usize>,
+    ) -> ExprId {
         self.insert(
             Expr::FunctionCall {
                 callee,
                 arguments: arguments.into(),
             },
-            None,
+            Some(span),
         )
     }
 
-    pub fn member_access(&mut self, object: ExprId, property: ExprId) -> ExprId {
-        self.insert(Expr::MemberAccess { object, property }, None)
+    pub fn member_access(
+        &mut self,
+        object: ExprId,
+        property: ExprId,
+        span: Range<usize>,
+    ) -> ExprId {
+        self.insert(Expr::MemberAccess { object, property }, Some(span))
+    }
+
+    pub fn index(&mut self, object: ExprId, index: ExprId, span: Range<usize>) -> ExprId {
+        self.insert(Expr::Index { object, index }, Some(span))
     }
 
     pub fn dict_literal(&mut self, fields: Vec<(ExprId, Option<ExprId>)>) -> ExprId {
@@ -202,6 +322,15 @@ impl Ast {
         )
     }
 
+    pub fn vec_literal(&mut self, elements: Vec<ExprId>) -> ExprId {
+        self.insert(
+            Expr::VecLiteral {
+                elements: elements.into(),
+            },
+            None,
+        )
+    }
+
     pub fn native_function(&mut self, name: ExprId, parameters: Vec<ExprId>) -> ExprId {
         self.insert(
             Expr::NativeFunction {
@@ -228,8 +357,8 @@ impl Ast {
         )
     }
 
-    pub fn block(&mut self, expressions: Vec<ExprId>) -> ExprId {
-        self.insert(Expr::Block(expressions.into()), None)
+    pub fn block(&mut self, expressions: Vec<ExprId>, span: Range<usize>) -> ExprId {
+        self.insert(Expr::Block(expressions.into()), Some(span))
     }
 
     pub fn if_(
@@ -252,8 +381,34 @@ impl Ast {
         self.insert(Expr::WhileLoop { condition, block }, None)
     }
 
-    pub fn for_loop(&mut self, start: ExprId, end: ExprId, block: ExprId) -> ExprId {
-        self.insert(Expr::ForLoop { start, end, block }, None)
+    pub fn for_loop(
+        &mut self,
+        variable: ExprId,
+        start: ExprId,
+        end: ExprId,
+        step: Option<ExprId>,
+        descending: bool,
+        block: ExprId,
+    ) -> ExprId {
+        self.insert(
+            Expr::ForLoop {
+                variable,
+                start,
+                end,
+                step,
+                descending,
+                block,
+            },
+            None,
+        )
+    }
+
+    pub fn import(&mut self, path: StringIndex, span: Range<usize>) -> ExprId {
+        self.insert(Expr::Import(path), Some(span))
+    }
+
+    pub fn export(&mut self, declaration: ExprId, span: Range<usize>) -> ExprId {
+        self.insert(Expr::Export(declaration), Some(span))
     }
 
     pub fn return_(&mut self, expression: Option<ExprId>, span: Range<usize>) -> ExprId {
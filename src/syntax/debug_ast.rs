@@ -0,0 +1,175 @@
+use std::fmt;
+
+use crate::{
+    program::INTERNER,
+    syntax::ast::{Ast, Comment, Expr, ExprId},
+    util::string_interner::StringIndex,
+};
+
+/// Renders the whole tree, one node per line, with children indented under
+/// their parent and source spans where they are known.
+pub struct DebugAst<'a> {
+    ast: &'a Ast,
+}
+
+impl<'a> DebugAst<'a> {
+    pub fn new(ast: &'a Ast) -> Self {
+        Self { ast }
+    }
+
+    fn write_node(&self, f: &mut fmt::Formatter<'_>, id: ExprId, depth: usize) -> fmt::Result {
+        let resolve = |index: StringIndex| INTERNER.lock().unwrap().resolve(index).to_owned();
+
+        let (label, children): (String, Vec<ExprId>) = match *self.ast.get(id) {
+            Expr::Binary {
+                operator,
+                left,
+                right,
+            } => (format!("Binary {:?}", operator), vec![left, right]),
+            Expr::LogicalAnd { left, right } => ("LogicalAnd".into(), vec![left, right]),
+            Expr::LogicalOr { left, right } => ("LogicalOr".into(), vec![left, right]),
+            Expr::LogicalNot(expression) => ("LogicalNot".into(), vec![expression]),
+            Expr::Unary { operator, right } => (format!("Unary {:?}", operator), vec![right]),
+            Expr::Assign {
+                operator,
+                left,
+                right,
+            } => (format!("Assign {:?}", operator), vec![left, right]),
+            Expr::DeclareAssign { left, right } => ("DeclareAssign".into(), vec![left, right]),
+            Expr::Identifier(name) => (format!("Identifier {}", resolve(name)), vec![]),
+            Expr::StringLiteral(value) => (format!("StringLiteral {:?}", resolve(value)), vec![]),
+            Expr::NumberLiteral(value) => (format!("NumberLiteral {}", value), vec![]),
+            Expr::BooleanLiteral(value) => (format!("BooleanLiteral {}", value), vec![]),
+            Expr::NilLiteral => ("NilLiteral".into(), vec![]),
+            Expr::FunctionCall {
+                callee,
+                ref arguments,
+            } => {
+                let mut children = vec![callee];
+                children.extend(arguments.iter().copied());
+
+                ("FunctionCall".into(), children)
+            }
+            Expr::MemberAccess { object, property } => {
+                ("MemberAccess".into(), vec![object, property])
+            }
+            Expr::Index { object, index } => ("Index".into(), vec![object, index]),
+            Expr::DictLiteral { ref fields } => {
+                let children = fields
+                    .iter()
+                    .flat_map(|&(key, value)| std::iter::once(key).chain(value))
+                    .collect();
+
+                ("DictLiteral".into(), children)
+            }
+            Expr::VecLiteral { ref elements } => ("VecLiteral".into(), elements.to_vec()),
+            Expr::NativeFunction {
+                name,
+                ref parameters,
+            } => {
+                let mut children = vec![name];
+                children.extend(parameters.iter().copied());
+
+                ("NativeFunction".into(), children)
+            }
+            Expr::Function {
+                name,
+                ref parameters,
+                block,
+            } => {
+                let mut children: Vec<ExprId> = name.into_iter().collect();
+                children.extend(parameters.iter().copied());
+                children.push(block);
+
+                ("Function".into(), children)
+            }
+            Expr::Block(ref expressions) => ("Block".into(), expressions.to_vec()),
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let mut children = vec![condition, then_branch];
+                children.extend(else_branch);
+
+                ("If".into(), children)
+            }
+            Expr::WhileLoop { condition, block } => ("WhileLoop".into(), vec![condition, block]),
+            Expr::ForLoop {
+                variable,
+                start,
+                end,
+                step,
+                descending,
+                block,
+            } => {
+                let direction = if descending { "downto" } else { "to" };
+
+                let mut children = vec![variable, start, end];
+                children.extend(step);
+                children.push(block);
+
+                (format!("ForLoop {}", direction), children)
+            }
+            Expr::Import(path) => (format!("Import {:?}", resolve(path)), vec![]),
+            Expr::Export(declaration) => ("Export".into(), vec![declaration]),
+            Expr::Return(expression) => ("Return".into(), expression.into_iter().collect()),
+            Expr::Break => ("Break".into(), vec![]),
+            Expr::Continue => ("Continue".into(), vec![]),
+        };
+
+        let trivia = self.ast.trivia(id);
+
+        for comment in trivia.iter().flat_map(|trivia| &trivia.leading) {
+            self.write_comment(f, "Leading", comment, depth)?;
+        }
+
+        write!(f, "{:indent$}{}", "", label, indent = depth * 2)?;
+
+        if let Some(span) = self.ast.span(id) {
+            write!(f, " @{}..{}", span.start, span.end)?;
+        }
+
+        writeln!(f)?;
+
+        for child in children {
+            self.write_node(f, child, depth + 1)?;
+        }
+
+        for comment in trivia.iter().flat_map(|trivia| &trivia.inner) {
+            self.write_comment(f, "Inner", comment, depth + 1)?;
+        }
+
+        for comment in trivia.iter().flat_map(|trivia| &trivia.trailing) {
+            self.write_comment(f, "Trailing", comment, depth)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_comment(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        position: &str,
+        comment: &Comment,
+        depth: usize,
+    ) -> fmt::Result {
+        writeln!(
+            f,
+            "{:indent$}{} {:?} {:?} @{}..{}",
+            "",
+            position,
+            comment.kind,
+            comment.text,
+            comment.span.start,
+            comment.span.end,
+            indent = depth * 2
+        )
+    }
+}
+
+impl<'a> fmt::Display for DebugAst<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_node(f, self.ast.entry(), 0)
+    }
+}
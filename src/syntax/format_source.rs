@@ -0,0 +1,749 @@
+use logos::Logos;
+
+use crate::{
+    diagnostics::error::Error,
+    program::INTERNER,
+    syntax::{
+        ast::{Ast, Comment, Expr, ExprId},
+        ops::{AssignOp, BinaryOp, UnaryOp},
+        parser::Parser,
+        token::Token,
+    },
+    util::string_interner::StringIndex,
+};
+
+/// Lines longer than this break their argument lists, vecs and dicts into
+/// one item per line.
+const MAX_WIDTH: usize = 100;
+
+const INDENT: &str = "    ";
+
+/// Formats a whole source file. Comments are kept, and the output formats
+/// to itself.
+pub fn format_source(source: &str) -> Result<String, Vec<Error>> {
+    let tokens = Token::lexer(source).spanned();
+    let (ast, errors) = Parser::new(tokens).keep_comments().parse();
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut printer = Printer {
+        ast: &ast,
+        source,
+        output: String::new(),
+        indent: 0,
+        flat: false,
+    };
+
+    let entry = ast.entry();
+
+    let Expr::Block(ref statements) = *ast.get(entry) else {
+        unreachable!("the program must be parsed as a block");
+    };
+
+    printer.statements(entry, statements);
+
+    let mut output = printer.output.trim_start_matches('\n').to_owned();
+
+    if !output.is_empty() {
+        output.push('\n');
+    }
+
+    Ok(output)
+}
+
+/// Element of a comma separated list.
+#[derive(Clone, Copy)]
+enum Item {
+    Expression(ExprId),
+    Field(ExprId, Option<ExprId>),
+}
+
+impl Item {
+    /// The expression comments around the item are attached to.
+    fn id(self) -> ExprId {
+        match self {
+            Item::Expression(id) | Item::Field(id, _) => id,
+        }
+    }
+}
+
+struct Printer<'a> {
+    ast: &'a Ast,
+    source: &'a str,
+    output: String,
+    indent: usize,
+    /// Set while trying a list on one line, where nested lists don't break.
+    flat: bool,
+}
+
+impl Printer<'_> {
+    fn write(&mut self, text: &str) {
+        self.output.push_str(text);
+    }
+
+    fn newline(&mut self) {
+        self.output.push('\n');
+
+        for _ in 0..self.indent {
+            self.output.push_str(INDENT);
+        }
+    }
+
+    /// Starts the line of a statement or comment, after an empty line when
+    /// the source had one there and it's not the first line of its block.
+    fn start_line(&mut self, first: bool, blank_line: bool) {
+        if blank_line && !first {
+            self.output.push('\n');
+        }
+
+        self.newline();
+    }
+
+    fn resolve(&self, index: StringIndex) -> String {
+        INTERNER.lock().unwrap().resolve(index).to_owned()
+    }
+
+    /// Writes every statement of a block on its own line, each starting with
+    /// a newline, along with the comments around them.
+    fn statements(&mut self, block: ExprId, statements: &[ExprId]) {
+        let mut first = true;
+
+        for &statement in statements {
+            let trivia = self.ast.trivia(statement);
+
+            for comment in trivia.iter().flat_map(|trivia| &trivia.leading) {
+                self.comment_line(comment, first);
+                first = false;
+            }
+
+            let blank_line = trivia.is_some_and(|trivia| trivia.blank_line_before);
+            self.start_line(first, blank_line);
+            first = false;
+
+            self.statement(statement);
+
+            for comment in trivia.iter().flat_map(|trivia| &trivia.trailing) {
+                self.write(" ");
+                self.write(comment.text.trim_end());
+            }
+        }
+
+        let trivia = self.ast.trivia(block);
+
+        for comment in trivia.iter().flat_map(|trivia| &trivia.inner) {
+            self.comment_line(comment, first);
+            first = false;
+        }
+    }
+
+    fn comment_line(&mut self, comment: &Comment, first: bool) {
+        self.start_line(first, comment.blank_line_before);
+        self.write(comment.text.trim_end());
+    }
+
+    fn statement(&mut self, id: ExprId) {
+        match *self.ast.get(id) {
+            Expr::Function { .. }
+            | Expr::WhileLoop { .. }
+            | Expr::ForLoop { .. }
+            | Expr::If { .. } => {
+                self.expression(id, 0, true);
+            }
+            Expr::Export(declaration) => {
+                self.write("export ");
+                self.statement(declaration);
+            }
+            Expr::Import(path) => {
+                let path = self.resolve(path);
+
+                self.write(&format!("import \"{}\";", path));
+            }
+            Expr::Return(expression) => {
+                self.write("return");
+
+                if let Some(expression) = expression {
+                    self.write(" ");
+                    self.expression(expression, 0, true);
+                }
+
+                self.write(";");
+            }
+            Expr::Break => self.write("break;"),
+            Expr::Continue => self.write("continue;"),
+            _ => {
+                let omits_semicolon = self
+                    .ast
+                    .trivia(id)
+                    .is_some_and(|trivia| trivia.omits_semicolon);
+
+                // A statement that starts with `fn` or `if` would be parsed
+                // as a declaration or an `if` statement and end early.
+                if self.starts_with_keyword(id) {
+                    self.write("(");
+                    self.expression(id, 0, true);
+                    self.write(")");
+                } else {
+                    self.expression(id, 0, true);
+                }
+
+                if !omits_semicolon {
+                    self.write(";");
+                }
+            }
+        }
+    }
+
+    /// Whether the leftmost operand of `id`, printed without parentheses, is
+    /// a function or an `if`.
+    fn starts_with_keyword(&self, id: ExprId) -> bool {
+        match *self.ast.get(id) {
+            Expr::Function { .. } | Expr::If { .. } => true,
+            Expr::Binary { left, .. }
+            | Expr::LogicalAnd { left, .. }
+            | Expr::LogicalOr { left, .. }
+            | Expr::Assign { left, .. }
+            | Expr::DeclareAssign { left, .. } => self.starts_with_keyword(left),
+            _ => false,
+        }
+    }
+
+    /// Writes an expression, in parentheses when it binds looser than
+    /// `min_precedence`. A `not` takes everything after it, so it's only left
+    /// bare at the `tail` of the enclosing expression.
+    fn expression(&mut self, id: ExprId, min_precedence: u8, tail: bool) {
+        let expression = self.ast.get(id);
+
+        let parenthesize = match expression {
+            Expr::LogicalNot(..) => !tail,
+            _ => precedence(expression) < min_precedence,
+        };
+
+        if parenthesize {
+            self.write("(");
+            self.expression(id, 0, true);
+            self.write(")");
+
+            return;
+        }
+
+        match *expression {
+            Expr::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let precedence = precedence(expression);
+
+                self.expression(left, precedence, false);
+                self.write(&format!(" {} ", binary_operator(operator)));
+                self.expression(right, precedence + 1, tail);
+            }
+            Expr::LogicalAnd { left, right } => {
+                self.expression(left, 2, false);
+                self.write(" and ");
+                self.expression(right, 3, tail);
+            }
+            Expr::LogicalOr { left, right } => {
+                self.expression(left, 1, false);
+                self.write(" or ");
+                self.expression(right, 2, tail);
+            }
+            Expr::LogicalNot(expression) => {
+                self.write("not ");
+                self.expression(expression, 1, true);
+            }
+            Expr::Unary {
+                operator: UnaryOp::Negate,
+                right,
+            } => {
+                self.write("-");
+                self.expression(right, 7, tail);
+            }
+            Expr::Assign {
+                operator,
+                left,
+                right,
+            } => {
+                self.expression(left, 1, false);
+                self.write(&format!(" {} ", assign_operator(operator)));
+                self.expression(right, 1, tail);
+            }
+            Expr::DeclareAssign { left, right } => {
+                self.expression(left, 1, false);
+                self.write(" := ");
+                self.expression(right, 1, tail);
+            }
+            Expr::Identifier(name) => {
+                let name = self.resolve(name);
+                self.write(&name);
+            }
+            Expr::StringLiteral(value) => {
+                let value = self.resolve(value);
+                self.write(&format!("\"{}\"", value));
+            }
+            Expr::NumberLiteral(value) => {
+                // The literal is written as it was, `1.0` stays `1.0`.
+                let text = match self.ast.span(id) {
+                    Some(span) => self.source[span.clone()].to_owned(),
+                    None => value.to_string(),
+                };
+
+                self.write(&text);
+            }
+            Expr::BooleanLiteral(value) => self.write(if value { "true" } else { "false" }),
+            Expr::NilLiteral => self.write("nil"),
+            Expr::FunctionCall {
+                callee,
+                ref arguments,
+            } => {
+                let items: Vec<Item> = arguments.iter().copied().map(Item::Expression).collect();
+
+                self.postfix_operand(callee);
+                self.list(id, "(", &items, ")");
+            }
+            Expr::MemberAccess { object, property } => {
+                self.postfix_operand(object);
+                self.write(".");
+
+                let Expr::StringLiteral(property) = *self.ast.get(property) else {
+                    unreachable!("a property is parsed as a string literal");
+                };
+
+                let property = self.resolve(property);
+                self.write(&property);
+            }
+            Expr::Index { object, index } => {
+                self.postfix_operand(object);
+                self.write("[");
+                self.expression(index, 0, true);
+                self.write("]");
+            }
+            Expr::DictLiteral { ref fields } => {
+                let items: Vec<Item> = fields
+                    .iter()
+                    .map(|&(key, value)| Item::Field(key, value))
+                    .collect();
+
+                self.list(id, "{", &items, "}");
+            }
+            Expr::VecLiteral { ref elements } => {
+                let items: Vec<Item> = elements.iter().copied().map(Item::Expression).collect();
+
+                self.list(id, "[", &items, "]");
+            }
+            Expr::NativeFunction {
+                name,
+                ref parameters,
+            } => {
+                self.write("native fn ");
+                self.expression(name, 0, true);
+                self.parameters(id, parameters);
+            }
+            Expr::Function {
+                name,
+                ref parameters,
+                block,
+            } => {
+                self.write("fn");
+
+                if let Some(name) = name {
+                    self.write(" ");
+                    self.expression(name, 0, true);
+                }
+
+                self.parameters(id, parameters);
+                self.write(" ");
+                self.block(block);
+            }
+            Expr::Block(..) => self.block(id),
+            Expr::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.write("if ");
+                self.expression(condition, 0, true);
+                self.write(" ");
+                self.block(then_branch);
+
+                if let Some(else_branch) = else_branch {
+                    self.write(" else ");
+
+                    // `else if` chains stay flat instead of nesting.
+                    match self.ast.get(else_branch) {
+                        Expr::If { .. } => self.expression(else_branch, 0, true),
+                        _ => self.block(else_branch),
+                    }
+                }
+            }
+            Expr::WhileLoop { condition, block } => {
+                self.write("while ");
+                self.expression(condition, 0, true);
+                self.write(" ");
+                self.block(block);
+            }
+            Expr::ForLoop {
+                variable,
+                start,
+                end,
+                step,
+                descending,
+                block,
+            } => {
+                self.write("for ");
+                self.expression(variable, 0, true);
+                self.write(" := ");
+                self.expression(start, 0, true);
+                self.write(if descending { " downto " } else { " to " });
+                self.expression(end, 0, true);
+
+                if let Some(step) = step {
+                    self.write(" by ");
+                    self.expression(step, 0, true);
+                }
+
+                self.write(" ");
+                self.block(block);
+            }
+            Expr::Import(..)
+            | Expr::Export(..)
+            | Expr::Return(..)
+            | Expr::Break
+            | Expr::Continue => {
+                self.statement(id);
+            }
+        }
+    }
+
+    /// Writes the callee of a call or the object of a member access or index,
+    /// in parentheses unless the parser reads a postfix operator after it.
+    fn postfix_operand(&mut self, id: ExprId) {
+        let postfix = matches!(
+            self.ast.get(id),
+            Expr::Identifier(..)
+                | Expr::StringLiteral(..)
+                | Expr::DictLiteral { .. }
+                | Expr::VecLiteral { .. }
+                | Expr::FunctionCall { .. }
+                | Expr::MemberAccess { .. }
+                | Expr::Index { .. }
+        );
+
+        if postfix {
+            self.expression(id, 0, true);
+        } else {
+            self.write("(");
+            self.expression(id, 0, true);
+            self.write(")");
+        }
+    }
+
+    /// Writes the parameters of the function `id` on one line, unless there
+    /// are comments between them.
+    fn parameters(&mut self, id: ExprId, parameters: &[ExprId]) {
+        let items: Vec<Item> = parameters.iter().copied().map(Item::Expression).collect();
+
+        if self.has_comments(id, &items) {
+            self.broken_list(id, "(", &items, ")");
+            return;
+        }
+
+        self.write("(");
+
+        for (index, &parameter) in parameters.iter().enumerate() {
+            if index > 0 {
+                self.write(", ");
+            }
+
+            self.expression(parameter, 0, true);
+        }
+
+        self.write(")");
+    }
+
+    fn block(&mut self, id: ExprId) {
+        let Expr::Block(ref statements) = *self.ast.get(id) else {
+            unreachable!("expected a block");
+        };
+
+        let empty = statements.is_empty()
+            && self
+                .ast
+                .trivia(id)
+                .is_none_or(|trivia| trivia.inner.is_empty());
+
+        if empty {
+            self.write("{}");
+            return;
+        }
+
+        // Lists in the block lay out on their own lines.
+        let flat = std::mem::replace(&mut self.flat, false);
+
+        self.write("{");
+        self.indent += 1;
+        self.statements(id, statements);
+        self.indent -= 1;
+        self.newline();
+        self.write("}");
+
+        self.flat = flat;
+    }
+
+    /// Writes the list of `id` on one line when it fits along with the lists
+    /// in it, otherwise one item per line with a trailing comma. Lists with
+    /// comments always break, to keep the comments next to their items.
+    fn list(&mut self, id: ExprId, open: &str, items: &[Item], close: &str) {
+        if self.has_comments(id, items) {
+            self.broken_list(id, open, items, close);
+            return;
+        }
+
+        let start = self.output.len();
+        let flat = std::mem::replace(&mut self.flat, true);
+
+        self.write(open);
+
+        for (index, &item) in items.iter().enumerate() {
+            if index > 0 {
+                self.write(", ");
+            }
+
+            self.item(item);
+        }
+
+        self.write(close);
+
+        self.flat = flat;
+
+        if flat || items.is_empty() || self.fits(start) {
+            return;
+        }
+
+        self.output.truncate(start);
+        self.broken_list(id, open, items, close);
+    }
+
+    /// Writes the list of `id` with one item per line, each after the comments
+    /// above it and followed by the ones on its line.
+    fn broken_list(&mut self, id: ExprId, open: &str, items: &[Item], close: &str) {
+        // Lists in the items only stay on one line when they fit on their own.
+        let flat = std::mem::replace(&mut self.flat, false);
+
+        self.write(open);
+        self.indent += 1;
+
+        let mut first = true;
+
+        for &item in items {
+            let trivia = self.ast.trivia(item.id());
+
+            for comment in trivia.iter().flat_map(|trivia| &trivia.leading) {
+                self.comment_line(comment, first);
+                first = false;
+            }
+
+            self.newline();
+            first = false;
+
+            self.item(item);
+            self.write(",");
+
+            for comment in trivia.iter().flat_map(|trivia| &trivia.trailing) {
+                self.write(" ");
+                self.write(comment.text.trim_end());
+            }
+        }
+
+        let trivia = self.ast.trivia(id);
+
+        for comment in trivia.iter().flat_map(|trivia| &trivia.inner) {
+            self.comment_line(comment, first);
+            first = false;
+        }
+
+        self.indent -= 1;
+        self.newline();
+        self.write(close);
+
+        self.flat = flat;
+    }
+
+    /// Whether comments were kept before the end of the list of `id` or
+    /// around its items.
+    fn has_comments(&self, id: ExprId, items: &[Item]) -> bool {
+        let inner = self
+            .ast
+            .trivia(id)
+            .is_some_and(|trivia| !trivia.inner.is_empty());
+
+        inner
+            || items.iter().any(|item| {
+                self.ast
+                    .trivia(item.id())
+                    .is_some_and(|trivia| !trivia.leading.is_empty() || !trivia.trailing.is_empty())
+            })
+    }
+
+    fn item(&mut self, item: Item) {
+        match item {
+            Item::Expression(expression) => self.expression(expression, 0, true),
+            Item::Field(key, value) => {
+                self.expression(key, 0, true);
+
+                if let Some(value) = value {
+                    self.write(": ");
+                    self.expression(value, 0, true);
+                }
+            }
+        }
+    }
+
+    /// Whether the first and last lines written since `start` are short
+    /// enough. The lines between belong to the blocks of functions in the
+    /// list, which lay out on their own.
+    fn fits(&self, start: usize) -> bool {
+        let line_start = self.output[..start]
+            .rfind('\n')
+            .map_or(0, |index| index + 1);
+        let first_end = self.output[start..]
+            .find('\n')
+            .map_or(self.output.len(), |index| start + index);
+        let last_start = self.output[start..]
+            .rfind('\n')
+            .map_or(line_start, |index| start + index + 1);
+
+        let width = |line: &str| line.chars().count();
+
+        width(&self.output[line_start..first_end]) <= MAX_WIDTH
+            && width(&self.output[last_start..]) <= MAX_WIDTH
+    }
+}
+
+/// How tightly an expression binds, operands of binary operators bind at
+/// least as tightly as the operator.
+fn precedence(expression: &Expr) -> u8 {
+    match expression {
+        Expr::Assign { .. } | Expr::DeclareAssign { .. } => 0,
+        Expr::LogicalOr { .. } => 1,
+        Expr::LogicalAnd { .. } => 2,
+        Expr::Binary { operator, .. } => match operator {
+            BinaryOp::Equal | BinaryOp::NotEqual => 3,
+            BinaryOp::Greater | BinaryOp::GreaterEqual | BinaryOp::Less | BinaryOp::LessEqual => 4,
+            BinaryOp::Add | BinaryOp::Subtract => 5,
+            BinaryOp::Multiply | BinaryOp::Divide | BinaryOp::Modulo => 6,
+        },
+        Expr::Unary { .. } | Expr::LogicalNot(..) => 7,
+        _ => 8,
+    }
+}
+
+fn binary_operator(operator: BinaryOp) -> &'static str {
+    match operator {
+        BinaryOp::Add => "+",
+        BinaryOp::Subtract => "-",
+        BinaryOp::Multiply => "*",
+        BinaryOp::Divide => "/",
+        BinaryOp::Modulo => "%",
+        BinaryOp::Equal => "==",
+        BinaryOp::NotEqual => "!=",
+        BinaryOp::Greater => ">",
+        BinaryOp::GreaterEqual => ">=",
+        BinaryOp::Less => "<",
+        BinaryOp::LessEqual => "<=",
+    }
+}
+
+fn assign_operator(operator: AssignOp) -> &'static str {
+    match operator {
+        AssignOp::Assign => "=",
+        AssignOp::AddAssign => "+=",
+        AssignOp::SubtractAssign => "-=",
+        AssignOp::MultiplyAssign => "*=",
+        AssignOp::DivideAssign => "/=",
+        AssignOp::ModuloAssign => "%=",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSY: &str = r#"// leading comment
+native fn print(value);
+fn add(a,b){return a+b;} // trailing
+x:=add(1, // first
+  2);
+if x>1{print("big");}else if x<0 {print("neg");} else{print("small");}
+d := {"a":1,"b":[1,2,3]};
+for i:=1 to 3 by 1{
+    /* block */
+    print(i);
+}
+while false { }
+"#;
+
+    const FORMATTED: &str = r#"// leading comment
+native fn print(value);
+fn add(a, b) {
+    return a + b;
+} // trailing
+x := add(
+    1, // first
+    2,
+);
+if x > 1 {
+    print("big");
+} else if x < 0 {
+    print("neg");
+} else {
+    print("small");
+}
+d := {"a": 1, "b": [1, 2, 3]};
+for i := 1 to 3 by 1 {
+    /* block */
+    print(i);
+}
+while false {}
+"#;
+
+    fn format(source: &str) -> String {
+        format_source(source).unwrap_or_else(|_| panic!("`{}` must parse", source))
+    }
+
+    #[test]
+    fn formats_and_keeps_comments() {
+        assert_eq!(format(MESSY), FORMATTED);
+    }
+
+    #[test]
+    fn formatted_source_formats_to_itself() {
+        assert_eq!(format(FORMATTED), FORMATTED);
+    }
+
+    #[test]
+    fn examples_format_to_themselves() {
+        for example in ["mandelbrot", "objects", "recursive_fib"] {
+            let path = format!(
+                "{}/examples/kaori/{}.kr",
+                env!("CARGO_MANIFEST_DIR"),
+                example
+            );
+            let once = format(&std::fs::read_to_string(path).unwrap());
+
+            assert_eq!(
+                format(&once),
+                once,
+                "{} changes when formatted again",
+                example
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_source_is_not_formatted() {
+        assert!(format_source("x := ;").is_err());
+    }
+}
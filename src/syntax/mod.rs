@@ -1,4 +1,6 @@
 pub mod ast;
+pub mod debug_ast;
+pub mod format_source;
 pub mod ops;
 pub mod parser;
 pub mod token;
@@ -27,3 +27,17 @@ pub enum AssignOp {
     DivideAssign,
     ModuloAssign,
 }
+
+impl AssignOp {
+    /// The binary operator applied by a compound assignment, `None` for plain `=`.
+    pub fn binary_operator(self) -> Option<BinaryOp> {
+        match self {
+            Self::Assign => None,
+            Self::AddAssign => Some(BinaryOp::Add),
+            Self::SubtractAssign => Some(BinaryOp::Subtract),
+            Self::MultiplyAssign => Some(BinaryOp::Multiply),
+            Self::DivideAssign => Some(BinaryOp::Divide),
+            Self::ModuloAssign => Some(BinaryOp::Modulo),
+        }
+    }
+}
@@ -7,42 +7,193 @@ use crate::{
     program::INTERNER,
     report_error,
     syntax::{
-        ast::{Ast, ExprId},
+        ast::{Ast, Comment, CommentKind, Expr, ExprId},
         ops::{AssignOp, BinaryOp, UnaryOp},
-        token::Token,
+        token::{LexError, Token},
     },
+    util::string_interner::{StringIndex, StringInterner},
 };
 
 pub struct Parser<'a> {
     tokens: SpannedIter<'a, Token>,
     peeked: Option<(Token, Range<usize>)>,
+    /// Added to every span, the position of the source in its [`SourceMap`](crate::diagnostics::source_map::SourceMap).
+    offset: usize,
+    /// Whether comments are kept as trivia in the tree.
+    keep_comments: bool,
+    /// Comments read since the last statement took them.
+    comments: Vec<Comment>,
+    /// End of the last token or comment read, to tell whether a comment
+    /// starts its line.
+    last_end: usize,
+    /// End of the last token consumed, where the statement being parsed
+    /// ends once its last token is consumed.
+    consumed_end: usize,
+    /// Whether an empty line comes before the peeked token.
+    blank_line: bool,
+    /// Where names and strings are interned, the global interner when unset.
+    interner: Option<&'a mut StringInterner>,
     ast: Ast,
+    errors: Vec<Error>,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(tokens: SpannedIter<'a, Token>) -> Self {
+        Self::with_offset(tokens, 0)
+    }
+
+    pub fn with_offset(tokens: SpannedIter<'a, Token>, offset: usize) -> Self {
         Self {
             tokens,
             peeked: None,
+            offset,
+            keep_comments: false,
+            comments: Vec::new(),
+            last_end: 0,
+            consumed_end: 0,
+            blank_line: false,
+            interner: None,
             ast: Ast::default(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Keeps comments as [`Trivia`](crate::syntax::ast::Trivia) of the
+    /// statements around them, for tools that print the source back.
+    pub fn keep_comments(mut self) -> Self {
+        self.keep_comments = true;
+        self
+    }
+
+    /// Interns names and strings in `interner` instead of the global one,
+    /// for trees that aren't compiled and shouldn't outlive it.
+    pub fn interner(mut self, interner: &'a mut StringInterner) -> Self {
+        self.interner = Some(interner);
+        self
+    }
+
+    fn intern(&mut self, text: &str) -> StringIndex {
+        match &mut self.interner {
+            Some(interner) => interner.get_or_intern(text),
+            None => INTERNER.lock().unwrap().get_or_intern(text),
         }
     }
 
-    pub fn parse(mut self) -> Result<Ast, Error> {
+    /// Parses the whole program, recovering from syntax errors so that every
+    /// one of them is reported. Statements that failed to parse are left out
+    /// of the returned tree.
+    pub fn parse(mut self) -> (Ast, Vec<Error>) {
         let mut expressions = Vec::new();
 
-        while !self.at_end()? {
-            let (expression, require_semicolon) = self.parse_expression_statement()?;
-            expressions.push(expression);
+        loop {
+            let at_end = match self.at_end() {
+                Ok(at_end) => at_end,
+                Err(error) => {
+                    self.errors.push(error);
+                    continue;
+                }
+            };
 
-            if require_semicolon {
-                self.consume(Token::Semicolon)?;
+            self.attach_trailing(expressions.last().copied());
+
+            if at_end {
+                break;
+            }
+
+            match self.parse_statement(Token::Eof) {
+                Ok(expression) => expressions.push(expression),
+                Err(error) => {
+                    self.recover(error);
+
+                    // A stray `}` can't close anything at the top level.
+                    if let Ok(Token::RightBrace) = self.peek_token() {
+                        self.peeked = None;
+                    }
+                }
             }
         }
 
-        self.ast.block(expressions);
+        let end = self.offset + self.tokens.source().len();
+        let block = self.ast.block(expressions, self.offset..end);
+        self.attach_inner(block);
+
+        (self.ast, self.errors)
+    }
+
+    /// Gives the comments read after `statement` on its last line to it.
+    fn attach_trailing(&mut self, statement: Option<ExprId>) {
+        let count = self
+            .comments
+            .iter()
+            .take_while(|comment| !comment.newline_before)
+            .count();
+
+        let Some(statement) = statement.filter(|_| count > 0) else {
+            return;
+        };
 
-        Ok(self.ast)
+        let trailing: Vec<Comment> = self.comments.drain(..count).collect();
+        self.ast.trivia_mut(statement).trailing.extend(trailing);
+    }
+
+    fn attach_leading(
+        &mut self,
+        statement: ExprId,
+        leading: Vec<Comment>,
+        blank_line: bool,
+        omits_semicolon: bool,
+    ) {
+        if !self.keep_comments || (leading.is_empty() && !blank_line && !omits_semicolon) {
+            return;
+        }
+
+        let trivia = self.ast.trivia_mut(statement);
+        trivia.leading = leading;
+        trivia.blank_line_before = blank_line;
+        trivia.omits_semicolon = omits_semicolon;
+    }
+
+    /// Gives the comments left before the end of a block to the block.
+    fn attach_inner(&mut self, block: ExprId) {
+        if !self.comments.is_empty() {
+            self.ast.trivia_mut(block).inner = std::mem::take(&mut self.comments);
+        }
+    }
+
+    fn recover(&mut self, error: Error) {
+        self.errors.push(error);
+        self.synchronize();
+    }
+
+    /// Skips tokens until a point where a new statement can start: right after
+    /// a `;`, before a `}` or before a statement keyword.
+    fn synchronize(&mut self) {
+        loop {
+            let token = match self.peek_token() {
+                Ok(token) => token,
+                Err(_) => continue,
+            };
+
+            match token {
+                Token::Eof
+                | Token::RightBrace
+                | Token::Function
+                | Token::Native
+                | Token::Import
+                | Token::Export
+                | Token::While
+                | Token::For
+                | Token::If
+                | Token::Return
+                | Token::Break
+                | Token::Continue => return,
+                Token::Semicolon => {
+                    self.peeked = None;
+                    return;
+                }
+                _ => self.peeked = None,
+            }
+        }
     }
 
     fn at_end(&mut self) -> Result<bool, Error> {
@@ -91,49 +242,182 @@ impl<'a> Parser<'a> {
     }
 
     fn next(&mut self) -> Result<(Token, Range<usize>), Error> {
-        if let Some((token, span)) = self.tokens.next() {
-            match token {
+        // An invalid token is skipped, the next peek reads past it.
+        if let Some((_, span)) = self.peeked.take() {
+            self.consumed_end = span.end;
+        }
+
+        while let Some((token, span)) = self.tokens.next() {
+            let newlines = self.tokens.source()[self.last_end..span.start]
+                .matches('\n')
+                .count();
+            self.last_end = span.end;
+
+            let span = span.start + self.offset..span.end + self.offset;
+
+            return match token {
+                Ok(token) if token.is_comment() => {
+                    if self.keep_comments {
+                        self.keep_comment(token, span, newlines);
+                    }
+
+                    continue;
+                }
                 Ok(Token::UnterminatedStringLiteral) => {
                     Err(report_error!(span, "unterminated string literal"))
                 }
                 Ok(token) => {
                     self.peeked = Some((token, span.start..span.end));
+                    self.blank_line = newlines > 1;
 
                     Ok((token, span))
                 }
-                Err(_) => Err(report_error!(span, "this is not a valid token")),
-            }
-        } else {
-            self.peeked = Some((Token::Eof, 0..0));
-
-            Ok((Token::Eof, 0..0))
+                Err(LexError::UnterminatedComment) => {
+                    Err(report_error!(span, "unterminated block comment"))
+                }
+                Err(LexError::InvalidToken) => {
+                    Err(report_error!(span, "this is not a valid token"))
+                }
+            };
         }
+
+        let end = self.offset + self.tokens.source().len();
+        let span = end..end;
+        self.peeked = Some((Token::Eof, span.clone()));
+
+        Ok((Token::Eof, span))
     }
 
+    /// Keeps a comment read after `newlines` line breaks.
+    fn keep_comment(&mut self, token: Token, span: Range<usize>, newlines: usize) {
+        let kind = match token {
+            Token::LineComment => CommentKind::Line,
+            Token::DocComment => CommentKind::Doc,
+            _ => CommentKind::Block,
+        };
+
+        self.comments.push(Comment {
+            kind,
+            text: self.tokens.slice().to_owned(),
+            span,
+            newline_before: newlines > 0,
+            blank_line_before: newlines > 1,
+        });
+    }
+
+    /// Parses items separated by commas up to `terminator`. Comments above an
+    /// item go to it, as do the ones inside it and after it on its line. The
+    /// comments left before `terminator` are returned for the whole list.
     fn parse_comma_separator<T>(
         &mut self,
         parse_item: fn(&mut Self) -> Result<T, Error>,
+        item_id: fn(&T) -> ExprId,
         terminator: Token,
-    ) -> Result<Vec<T>, Error> {
+    ) -> Result<(Vec<T>, Vec<Comment>), Error> {
         let mut items = Vec::new();
 
         while !self.at_end()? && self.peek_token()? != terminator {
+            let leading = std::mem::take(&mut self.comments);
+
             let item = parse_item(self)?;
+            let id = item_id(&item);
             items.push(item);
 
-            if self.peek_token()? == terminator {
+            if !leading.is_empty() {
+                self.ast.trivia_mut(id).leading = leading;
+            }
+
+            let last = self.peek_token()? == terminator;
+
+            if !last {
+                self.consume(Token::Comma)?;
+            }
+
+            self.attach_item_trailing(id, last);
+
+            if last {
                 break;
             }
+        }
 
-            self.consume(Token::Comma)?;
+        Ok((items, std::mem::take(&mut self.comments)))
+    }
+
+    /// Gives the comments read inside a list `item`, and after it on the line
+    /// it ends, to it. Comments after the comma of an item that isn't the
+    /// `last` are left to the next item when it starts on their line.
+    fn attach_item_trailing(&mut self, item: ExprId, last: bool) {
+        let next = self
+            .peeked
+            .as_ref()
+            .map_or(usize::MAX, |(_, span)| span.start);
+        let source = self.tokens.source();
+
+        let ends_line = |comment: &Comment| {
+            let start = comment.span.end - self.offset;
+            let end = (next - self.offset).min(source.len());
+
+            source[start..end].contains('\n')
+        };
+
+        let count = self
+            .comments
+            .iter()
+            .take_while(|comment| {
+                comment.span.start < self.consumed_end
+                    || (!comment.newline_before && (last || ends_line(comment)))
+            })
+            .count();
+
+        if count > 0 {
+            let trailing: Vec<Comment> = self.comments.drain(..count).collect();
+            self.ast.trivia_mut(item).trailing.extend(trailing);
+        }
+    }
+
+    /// Gives the comments left before the end of a list to the expression
+    /// holding it.
+    fn attach_list_inner(&mut self, list: ExprId, comments: Vec<Comment>) {
+        if !comments.is_empty() {
+            self.ast.trivia_mut(list).inner = comments;
+        }
+    }
+
+    /// Parses a statement and its `;`, which the last statement before `end`
+    /// may leave out to produce the value of the enclosing block.
+    fn parse_statement(&mut self, end: Token) -> Result<ExprId, Error> {
+        let mut leading = std::mem::take(&mut self.comments);
+        let blank_line = self.blank_line;
+
+        let (expression, require_semicolon) = self.parse_expression_statement()?;
+
+        let omits_semicolon = require_semicolon && self.peek_token()? == end;
+
+        if require_semicolon && !omits_semicolon {
+            self.consume(Token::Semicolon)?;
         }
 
-        Ok(items)
+        // Comments between the tokens of the statement go above it, the ones
+        // after its end are left for the statement that follows.
+        let inside = self
+            .comments
+            .iter()
+            .take_while(|comment| comment.span.start < self.consumed_end)
+            .count();
+
+        leading.extend(self.comments.drain(..inside));
+        self.attach_leading(expression, leading, blank_line, omits_semicolon);
+
+        Ok(expression)
     }
 
     fn parse_expression_statement(&mut self) -> Result<(ExprId, bool), Error> {
         let token = self.peek_token()?;
 
+        if token == Token::Export {
+            return self.parse_export();
+        }
+
         let require_semicolon = !matches!(
             token,
             Token::Function | Token::While | Token::For | Token::If
@@ -142,6 +426,7 @@ impl<'a> Parser<'a> {
         let expression = match token {
             Token::Function => self.parse_function()?,
             Token::Native => self.parse_native_function()?,
+            Token::Import => self.parse_import()?,
             Token::While => self.parse_while_loop()?,
             Token::For => self.parse_for_loop()?,
             Token::Break => self.parse_break()?,
@@ -187,22 +472,40 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_block(&mut self) -> Result<ExprId, Error> {
+        let start = self.peek_span()?.start;
+
         self.consume(Token::LeftBrace)?;
 
         let mut expressions = Vec::new();
 
-        while !self.at_end()? && self.peek_token()? != Token::RightBrace {
-            let (expression, requires_semicolon) = self.parse_expression_statement()?;
-            expressions.push(expression);
+        loop {
+            let token = match self.peek_token() {
+                Ok(token) => token,
+                Err(error) => {
+                    self.errors.push(error);
+                    continue;
+                }
+            };
+
+            self.attach_trailing(expressions.last().copied());
+
+            if matches!(token, Token::Eof | Token::RightBrace) {
+                break;
+            }
 
-            if self.peek_token()? != Token::RightBrace && requires_semicolon {
-                self.consume(Token::Semicolon)?;
+            match self.parse_statement(Token::RightBrace) {
+                Ok(expression) => expressions.push(expression),
+                Err(error) => self.recover(error),
             }
         }
 
+        let end = self.peek_span()?.end;
+        let block = self.ast.block(expressions, start..end);
+        self.attach_inner(block);
+
         self.consume(Token::RightBrace)?;
 
-        Ok(self.ast.block(expressions))
+        Ok(block)
     }
 
     fn parse_if(&mut self) -> Result<ExprId, Error> {
@@ -238,7 +541,102 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_for_loop(&mut self) -> Result<ExprId, Error> {
-        todo!()
+        self.consume(Token::For)?;
+
+        let variable = self.parse_identifier()?;
+
+        self.consume(Token::DeclareAssign)?;
+
+        let start = self.parse_expression()?;
+
+        let (token, span) = self.peek()?;
+
+        let descending = match token {
+            Token::To => false,
+            Token::DownTo => true,
+            _ => {
+                return Err(report_error!(
+                    span,
+                    "expected `to` or `downto` and found {}",
+                    token
+                ));
+            }
+        };
+
+        self.next()?;
+
+        let end = self.parse_expression()?;
+
+        let step = if self.peek_token()? == Token::By {
+            self.next()?;
+
+            Some(self.parse_expression()?)
+        } else {
+            None
+        };
+
+        let block = self.parse_block()?;
+
+        Ok(self
+            .ast
+            .for_loop(variable, start, end, step, descending, block))
+    }
+
+    fn parse_import(&mut self) -> Result<ExprId, Error> {
+        let span = self.peek_span()?;
+
+        self.consume(Token::Import)?;
+
+        let (token, path_span) = self.peek()?;
+
+        if token != Token::StringLiteral {
+            return Err(report_error!(
+                path_span,
+                "expected the path of a module and found {}",
+                token
+            ));
+        }
+
+        let value = self.tokens.slice();
+        let path = self.intern(&value[1..value.len() - 1]);
+
+        self.next()?;
+
+        Ok(self.ast.import(path, span.start..path_span.end))
+    }
+
+    /// Parses `export` followed by a named function, a native function or a
+    /// variable declaration. Also returns whether a `;` must follow it.
+    fn parse_export(&mut self) -> Result<(ExprId, bool), Error> {
+        let span = self.peek_span()?;
+
+        self.consume(Token::Export)?;
+
+        let (token, declaration_span) = self.peek()?;
+
+        let (declaration, require_semicolon) = match token {
+            Token::Function => (self.parse_function()?, false),
+            Token::Native => (self.parse_native_function()?, true),
+            _ => (self.parse_expression()?, true),
+        };
+
+        let exportable = match *self.ast.get(declaration) {
+            Expr::Function { name, .. } => name.is_some(),
+            Expr::NativeFunction { .. } => true,
+            Expr::DeclareAssign { left, .. } => {
+                matches!(self.ast.get(left), Expr::Identifier(..))
+            }
+            _ => false,
+        };
+
+        if !exportable {
+            return Err(report_error!(
+                declaration_span,
+                "only named functions, native functions and `:=` declarations can be exported"
+            ));
+        }
+
+        Ok((self.ast.export(declaration, span), require_semicolon))
     }
 
     fn parse_native_function(&mut self) -> Result<ExprId, Error> {
@@ -249,11 +647,15 @@ impl<'a> Parser<'a> {
 
         self.consume(Token::LeftParen)?;
 
-        let parameters = self.parse_comma_separator(Self::parse_identifier, Token::RightParen)?;
+        let (parameters, comments) =
+            self.parse_comma_separator(Self::parse_identifier, |&id| id, Token::RightParen)?;
 
         self.consume(Token::RightParen)?;
 
-        Ok(self.ast.native_function(name, parameters))
+        let native_function = self.ast.native_function(name, parameters);
+        self.attach_list_inner(native_function, comments);
+
+        Ok(native_function)
     }
 
     fn parse_function(&mut self) -> Result<ExprId, Error> {
@@ -267,13 +669,17 @@ impl<'a> Parser<'a> {
 
         self.consume(Token::LeftParen)?;
 
-        let parameters = self.parse_comma_separator(Self::parse_identifier, Token::RightParen)?;
+        let (parameters, comments) =
+            self.parse_comma_separator(Self::parse_identifier, |&id| id, Token::RightParen)?;
 
         self.consume(Token::RightParen)?;
 
         let block = self.parse_block()?;
 
-        Ok(self.ast.function(name, parameters, block))
+        let function = self.ast.function(name, parameters, block);
+        self.attach_list_inner(function, comments);
+
+        Ok(function)
     }
 
     fn parse_assign(&mut self) -> Result<ExprId, Error> {
@@ -289,6 +695,13 @@ impl<'a> Parser<'a> {
             Token::DivideAssign => AssignOp::DivideAssign,
             Token::ModuloAssign => AssignOp::ModuloAssign,
             Token::DeclareAssign => {
+                if !matches!(self.ast.get(left), Expr::Identifier(..)) {
+                    return Err(report_error!(
+                        span,
+                        "cannot declare this expression, expected a variable name"
+                    ));
+                }
+
                 self.next()?;
                 let right = self.parse_or()?;
 
@@ -297,6 +710,16 @@ impl<'a> Parser<'a> {
             _ => return Ok(left),
         };
 
+        if !matches!(
+            self.ast.get(left),
+            Expr::Identifier(..) | Expr::MemberAccess { .. } | Expr::Index { .. }
+        ) {
+            return Err(report_error!(
+                span,
+                "cannot assign to this expression, expected a variable, field or index"
+            ));
+        }
+
         self.next()?;
 
         let right = self.parse_or()?;
@@ -470,14 +893,18 @@ impl<'a> Parser<'a> {
         let (token, span) = self.peek()?;
 
         let primary = match token {
-            Token::Function => self.parse_function()?,
+            Token::Function => {
+                let function = self.parse_function()?;
+
+                self.parse_postfix_unary(function)?
+            }
             Token::If => self.parse_if()?,
             Token::LeftParen => {
                 self.consume(Token::LeftParen)?;
                 let expression = self.parse_expression()?;
                 self.consume(Token::RightParen)?;
 
-                expression
+                self.parse_postfix_unary(expression)?
             }
             Token::NumberLiteral => {
                 let value = match self.tokens.slice().parse::<f64>() {
@@ -495,30 +922,43 @@ impl<'a> Parser<'a> {
             Token::True => {
                 self.next()?;
 
-                self.ast.number_literal(1.0, span)
+                self.ast.boolean_literal(true, span)
             }
             Token::False => {
                 self.next()?;
 
-                self.ast.number_literal(0.0, span)
+                self.ast.boolean_literal(false, span)
+            }
+            Token::Nil => {
+                self.next()?;
+
+                self.ast.nil_literal(span)
             }
             Token::StringLiteral => {
                 let value = self.tokens.slice();
                 self.next()?;
 
-                let index = INTERNER
-                    .lock()
-                    .unwrap()
-                    .get_or_intern(&value[1..value.len() - 1]);
+                let index = self.intern(&value[1..value.len() - 1]);
 
-                self.ast.string_literal(index, span)
+                let string = self.ast.string_literal(index, span);
+
+                self.parse_postfix_unary(string)?
             }
             Token::Identifier => {
                 let identifier = self.parse_identifier()?;
 
                 self.parse_postfix_unary(identifier)?
             }
-            Token::LeftBrace => self.parse_dict_literal()?,
+            Token::LeftBrace => {
+                let dict = self.parse_dict_literal()?;
+
+                self.parse_postfix_unary(dict)?
+            }
+            Token::LeftBracket => {
+                let vec = self.parse_vec_literal()?;
+
+                self.parse_postfix_unary(vec)?
+            }
             _ => {
                 let span = self.peek_span()?;
 
@@ -536,7 +976,7 @@ impl<'a> Parser<'a> {
     fn parse_identifier(&mut self) -> Result<ExprId, Error> {
         let name = self.tokens.slice();
         let span = self.peek_span()?;
-        let index = INTERNER.lock().unwrap().get_or_intern(name);
+        let index = self.intern(name);
 
         self.consume(Token::Identifier)?;
 
@@ -559,12 +999,32 @@ impl<'a> Parser<'a> {
     fn parse_dict_literal(&mut self) -> Result<ExprId, Error> {
         self.consume(Token::LeftBrace)?;
 
-        let fields =
-            self.parse_comma_separator(Self::parse_dict_literal_field, Token::RightBrace)?;
+        let (fields, comments) = self.parse_comma_separator(
+            Self::parse_dict_literal_field,
+            |&(key, _)| key,
+            Token::RightBrace,
+        )?;
 
         self.consume(Token::RightBrace)?;
 
-        Ok(self.ast.dict_literal(fields))
+        let dict_literal = self.ast.dict_literal(fields);
+        self.attach_list_inner(dict_literal, comments);
+
+        Ok(dict_literal)
+    }
+
+    fn parse_vec_literal(&mut self) -> Result<ExprId, Error> {
+        self.consume(Token::LeftBracket)?;
+
+        let (elements, comments) =
+            self.parse_comma_separator(Self::parse_expression, |&id| id, Token::RightBracket)?;
+
+        self.consume(Token::RightBracket)?;
+
+        let vec_literal = self.ast.vec_literal(elements);
+        self.attach_list_inner(vec_literal, comments);
+
+        Ok(vec_literal)
     }
 
     fn parse_postfix_unary(&mut self, operand: ExprId) -> Result<ExprId, Error> {
@@ -573,34 +1033,63 @@ impl<'a> Parser<'a> {
         Ok(match token {
             Token::LeftParen => self.parse_function_call(operand)?,
             Token::Dot => self.parse_member_access(operand)?,
+            Token::LeftBracket => self.parse_index(operand)?,
             _ => operand,
         })
     }
 
     fn parse_function_call(&mut self, callee: ExprId) -> Result<ExprId, Error> {
+        let start = match self.ast.span(callee) {
+            Some(span) => span.start,
+            None => self.peek_span()?.start,
+        };
+
         self.consume(Token::LeftParen)?;
 
-        let arguments = self.parse_comma_separator(Self::parse_expression, Token::RightParen)?;
+        let (arguments, comments) =
+            self.parse_comma_separator(Self::parse_expression, |&id| id, Token::RightParen)?;
+
+        let end = self.peek_span()?.end;
 
         self.consume(Token::RightParen)?;
 
-        let function_call = self.ast.function_call(callee, arguments);
+        let function_call = self.ast.function_call(callee, arguments, start..end);
+        self.attach_list_inner(function_call, comments);
 
         self.parse_postfix_unary(function_call)
     }
 
+    fn parse_index(&mut self, object: ExprId) -> Result<ExprId, Error> {
+        let start = self.peek_span()?.start;
+
+        self.consume(Token::LeftBracket)?;
+
+        let index = self.parse_expression()?;
+
+        let end = self.peek_span()?.end;
+
+        self.consume(Token::RightBracket)?;
+
+        let index = self.ast.index(object, index, start..end);
+
+        self.parse_postfix_unary(index)
+    }
+
     fn parse_member_access(&mut self, object: ExprId) -> Result<ExprId, Error> {
+        let start = self.peek_span()?.start;
+
         self.consume(Token::Dot)?;
 
         let span = self.peek_span()?;
+        let end = span.end;
 
-        let index = INTERNER.lock().unwrap().get_or_intern(self.tokens.slice());
+        let index = self.intern(self.tokens.slice());
 
         let property = self.ast.string_literal(index, span);
 
         self.consume(Token::Identifier)?;
 
-        let member_access = self.ast.member_access(object, property);
+        let member_access = self.ast.member_access(object, property, start..end);
 
         self.parse_postfix_unary(member_access)
     }
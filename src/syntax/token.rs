@@ -1,7 +1,17 @@
-use logos::Logos;
+use logos::{Lexer, Logos};
 use std::fmt;
+
+/// Why the lexer failed to read a token.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub enum LexError {
+    #[default]
+    InvalidToken,
+    UnterminatedComment,
+}
+
 #[derive(Logos, Debug, PartialEq, Copy, Clone)]
 #[logos(skip r"[ \t\f\r\n]+")]
+#[logos(error = LexError)]
 pub enum Token {
     #[token(":=")]
     DeclareAssign,
@@ -55,6 +65,10 @@ pub enum Token {
     LeftBrace,
     #[token("}")]
     RightBrace,
+    #[token("[")]
+    LeftBracket,
+    #[token("]")]
+    RightBracket,
     #[token("and")]
     And,
     #[token("or")]
@@ -63,6 +77,10 @@ pub enum Token {
     Not,
     #[token("native")]
     Native,
+    #[token("import")]
+    Import,
+    #[token("export")]
+    Export,
     #[token("fn")]
     Function,
     #[token("for")]
@@ -83,6 +101,8 @@ pub enum Token {
     True,
     #[token("false")]
     False,
+    #[token("nil")]
+    Nil,
     #[token("to")]
     To,
     #[token("downto")]
@@ -99,9 +119,58 @@ pub enum Token {
     UnterminatedStringLiteral,
     #[regex(r"[a-zA-Z_][a-zA-Z0-9_]*")]
     Identifier,
+    /// `//` up to the end of the line, `////` and longer count as well.
+    #[regex(r"//([^/\n][^\n]*)?|////[^\n]*", allow_greedy = true)]
+    LineComment,
+    /// `///` up to the end of the line, documents the declaration below it.
+    #[regex(r"///([^/\n][^\n]*)?", allow_greedy = true)]
+    DocComment,
+    /// `/* */`, which may contain other block comments.
+    #[token("/*", block_comment)]
+    BlockComment,
     Eof,
 }
 
+impl Token {
+    pub fn is_comment(self) -> bool {
+        matches!(
+            self,
+            Self::LineComment | Self::DocComment | Self::BlockComment
+        )
+    }
+}
+
+/// Reads up to the `*/` that closes the comment, skipping nested comments.
+fn block_comment(lexer: &mut Lexer<Token>) -> Result<(), LexError> {
+    let remainder = lexer.remainder();
+    let bytes = remainder.as_bytes();
+    let mut depth = 1;
+    let mut index = 0;
+
+    while index + 1 < bytes.len() {
+        match &bytes[index..index + 2] {
+            b"/*" => {
+                depth += 1;
+                index += 2;
+            }
+            b"*/" => {
+                depth -= 1;
+                index += 2;
+
+                if depth == 0 {
+                    lexer.bump(index);
+                    return Ok(());
+                }
+            }
+            _ => index += 1,
+        }
+    }
+
+    lexer.bump(remainder.len());
+
+    Err(LexError::UnterminatedComment)
+}
+
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
@@ -132,10 +201,14 @@ impl fmt::Display for Token {
             Self::RightParen => "`)`",
             Self::LeftBrace => "`{`",
             Self::RightBrace => "`}`",
+            Self::LeftBracket => "`[`",
+            Self::RightBracket => "`]`",
             Self::And => "`and`",
             Self::Or => "`or`",
             Self::Not => "`not`",
             Self::Native => "`native`",
+            Self::Import => "`import`",
+            Self::Export => "`export`",
             Self::Function => "`fn`",
             Self::For => "`for`",
             Self::While => "`while`",
@@ -146,6 +219,7 @@ impl fmt::Display for Token {
             Self::Return => "`return`",
             Self::True => "`true`",
             Self::False => "`false`",
+            Self::Nil => "`nil`",
             Self::To => "`to`",
             Self::DownTo => "`downto`",
             Self::By => "`by`",
@@ -153,6 +227,9 @@ impl fmt::Display for Token {
             Self::StringLiteral => "<string literal>",
             Self::UnterminatedStringLiteral => "<unterminated string>",
             Self::Identifier => "<identifier>",
+            Self::LineComment => "<comment>",
+            Self::DocComment => "<doc comment>",
+            Self::BlockComment => "<block comment>",
             Self::Eof => "<end of file>",
         };
         write!(f, "{}", s)
@@ -1,12 +1,16 @@
+use std::sync::Arc;
+
 use foldhash::HashMap;
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq, Debug)]
 pub struct StringIndex(pub u32);
 
+/// Strings are shared between the map and the list, and freed with the
+/// interner. Their contents never move once interned.
 #[derive(Default, Debug)]
 pub struct StringInterner {
-    map: HashMap<&'static str, usize>,
-    strings: Vec<&'static str>,
+    map: HashMap<Arc<str>, usize>,
+    strings: Vec<Arc<str>>,
 }
 
 impl StringInterner {
@@ -15,15 +19,19 @@ impl StringInterner {
             return StringIndex(index as u32);
         }
 
-        let s = s.to_owned().leak();
+        let s: Arc<str> = Arc::from(s);
         let index = self.strings.len();
-        self.strings.push(s);
+        self.strings.push(s.clone());
         self.map.insert(s, index);
 
         StringIndex(index as u32)
     }
 
-    pub fn resolve(&self, index: StringIndex) -> &'static str {
-        self.strings[index.0 as usize]
+    pub fn get(&self, s: &str) -> Option<StringIndex> {
+        self.map.get(s).map(|&index| StringIndex(index as u32))
+    }
+
+    pub fn resolve(&self, index: StringIndex) -> &str {
+        &self.strings[index.0 as usize]
     }
 }